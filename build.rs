@@ -1,3 +1,4 @@
+use std::env;
 use std::path::PathBuf;
 
 use embuild::{
@@ -7,6 +8,11 @@ use embuild::{
 };
 
 fn main() -> anyhow::Result<()> {
+    // The host tools do not link against ESP-IDF
+    if env::var("CARGO_CFG_TARGET_OS")? != "espidf" {
+        return Ok(());
+    }
+
     // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
     LinkArgs::output_propagated("ESP_IDF")?;
 
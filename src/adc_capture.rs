@@ -0,0 +1,138 @@
+// Continuous sampling of analog microphones on ADC1 through the I2S peripheral and its DMA (ESP32 only)
+
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use esp_idf_sys::*;
+
+use crate::dsp::RingBuffer;
+
+const I2S_PORT: i2s_port_t = i2s_port_t_I2S_NUM_0;
+
+// samples read from the DMA buffers at once
+const READ_SAMPLES: usize = 256;
+
+pub struct AdcCapture {
+    // ADC1 channel of each microphone
+    channels: Vec<adc1_channel_t>,
+    // per microphone
+    sample_rate: u32,
+    rings: Vec<RingBuffer>,
+    buffer: Vec<u16>,
+    // when the latest sample was read
+    latest: Instant,
+}
+
+impl AdcCapture {
+    /// The I2S peripheral scans `channels` one after the other, each at `sample_rate`,
+    /// and keeps the latest `ring_len` samples of every channel.
+    pub fn new(channels: &[adc1_channel_t], sample_rate: u32, ring_len: usize) -> Result<Self> {
+        if channels.is_empty() || channels.len() > 16 {
+            bail!("Unsupported number of ADC channels: {}", channels.len());
+        }
+
+        #[allow(clippy::needless_update)]
+        let config = i2s_config_t {
+            mode: i2s_mode_t_I2S_MODE_MASTER | i2s_mode_t_I2S_MODE_RX | i2s_mode_t_I2S_MODE_ADC_BUILT_IN,
+            // the scan takes turns between the channels
+            sample_rate: sample_rate * channels.len() as u32,
+            bits_per_sample: i2s_bits_per_sample_t_I2S_BITS_PER_SAMPLE_16BIT,
+            channel_format: i2s_channel_fmt_t_I2S_CHANNEL_FMT_ONLY_LEFT,
+            communication_format: i2s_comm_format_t_I2S_COMM_FORMAT_STAND_I2S,
+            intr_alloc_flags: 0,
+            dma_buf_count: 4,
+            dma_buf_len: READ_SAMPLES as i32,
+            use_apll: false,
+            ..Default::default()
+        };
+
+        esp!(unsafe { i2s_driver_install(I2S_PORT, &config, 0, ptr::null_mut()) })?;
+        esp!(unsafe { i2s_set_adc_mode(adc_unit_t_ADC_UNIT_1, channels[0]) })?;
+
+        // pattern table entries: channel in the upper nibble, then bit width and attenuation
+        let mut pattern: Vec<adc_digi_pattern_table_t> = channels
+            .iter()
+            .map(|channel| adc_digi_pattern_table_t {
+                val: ((*channel as u8) << 4) | ((adc_bits_width_t_ADC_WIDTH_BIT_12 as u8) << 2) | adc_atten_t_ADC_ATTEN_DB_11 as u8,
+            })
+            .collect();
+
+        #[allow(clippy::needless_update)]
+        let digi = adc_digi_config_t {
+            conv_limit_en: false,
+            conv_limit_num: 255,
+            adc1_pattern_len: pattern.len() as u32,
+            adc1_pattern: pattern.as_mut_ptr(),
+            conv_mode: adc_digi_convert_mode_t_ADC_CONV_SINGLE_UNIT_1,
+            format: adc_digi_output_format_t_ADC_DIGI_FORMAT_12BIT,
+            ..Default::default()
+        };
+        esp!(unsafe { adc_digi_controller_configure(&digi) })?;
+
+        esp!(unsafe { i2s_adc_enable(I2S_PORT) })?;
+
+        Ok(Self {
+            channels: channels.to_vec(),
+            sample_rate,
+            rings: channels.iter().map(|_| RingBuffer::new(ring_len)).collect(),
+            buffer: vec![0; READ_SAMPLES],
+            latest: Instant::now(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// The scan samples channel i this much after channel 0 of the same round.
+    pub fn skew(&self, i: usize) -> Duration {
+        Duration::from_nanos(i as u64 * 1_000_000_000 / (self.sample_rate as u64 * self.channels.len() as u64))
+    }
+
+    /// Blocks until the DMA delivered the next samples and sorts them into the per channel rings.
+    pub fn read(&mut self) -> Result<()> {
+        let mut read = 0;
+        esp!(unsafe {
+            i2s_read(
+                I2S_PORT,
+                self.buffer.as_mut_ptr() as *mut _,
+                self.buffer.len() * 2,
+                &mut read,
+                u32::MAX,
+            )
+        })?;
+        self.latest = Instant::now();
+
+        for sample in &self.buffer[..read / 2] {
+            // 12 bits of data, the channel in the upper 4 bits
+            let channel = (sample >> 12) as adc1_channel_t;
+            let value = (sample & 0x0fff) as f32 / 4095.0;
+            if let Some(i) = self.channels.iter().position(|c| *c == channel) {
+                self.rings[i].push(value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The latest `count` samples of each channel, oldest first.
+    pub fn latest(&self, count: usize) -> Vec<Vec<f32>> {
+        self.rings.iter().map(|ring| ring.latest(count)).collect()
+    }
+
+    /// When the newest samples returned by `latest` were taken.
+    pub fn latest_time(&self) -> Instant {
+        self.latest
+    }
+}
+
+impl Drop for AdcCapture {
+    fn drop(&mut self) {
+        unsafe {
+            i2s_adc_disable(I2S_PORT);
+            i2s_driver_uninstall(I2S_PORT);
+        }
+    }
+}
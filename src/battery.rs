@@ -0,0 +1,85 @@
+#![allow(dead_code)]
+
+// The voltage of the battery of the car, from an ADC pin behind a divider
+
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct BatteryConfig {
+    // battery volts per volt at the ADC pin
+    pub divider: f32,
+    // the voltage the engines are tuned at
+    pub nominal: f32,
+    // below this, the battery is low
+    pub low: f32,
+    // and it must stay below for this long, the engines pull the voltage down when they start
+    pub low_for: Duration,
+    // time constant of the low pass filter on the readings
+    pub smoothing: Duration,
+    // the gain on the duties for a sagging voltage goes no higher than this
+    pub max_compensation: f32,
+}
+
+pub struct Battery {
+    config: BatteryConfig,
+    voltage: Option<f32>,
+    measured: Option<Instant>,
+    low_since: Option<Instant>,
+    // once low, always low
+    low: bool,
+}
+
+impl Battery {
+    pub fn new(config: BatteryConfig) -> Self {
+        Self {
+            config,
+            voltage: None,
+            measured: None,
+            low_since: None,
+            low: false,
+        }
+    }
+
+    /// Filtered, in volts; None before the first reading.
+    pub fn voltage(&self) -> Option<f32> {
+        self.voltage
+    }
+
+    /// Takes a reading of the ADC pin in millivolts.
+    pub fn measure(&mut self, pin_millivolts: u16, now: Instant) {
+        let reading = pin_millivolts as f32 / 1000.0 * self.config.divider;
+        let voltage = match (self.voltage, self.measured) {
+            (Some(voltage), Some(measured)) => {
+                let elapsed = now.saturating_duration_since(measured).as_secs_f32();
+                let share = 1.0 - (-elapsed / self.config.smoothing.as_secs_f32()).exp();
+                voltage + share * (reading - voltage)
+            }
+            _ => reading,
+        };
+        self.voltage = Some(voltage);
+        self.measured = Some(now);
+
+        if voltage < self.config.low {
+            let since = *self.low_since.get_or_insert(now);
+            if now.saturating_duration_since(since) >= self.config.low_for {
+                self.low = true;
+            }
+        } else {
+            self.low_since = None;
+        }
+    }
+
+    pub fn low(&self) -> bool {
+        self.low
+    }
+
+    /// The gain on the duties that makes the engines as strong as on a nominal battery.
+    pub fn compensation(&self) -> f32 {
+        match self.voltage {
+            Some(voltage) if voltage > 0.0 => {
+                (self.config.nominal / voltage).min(self.config.max_compensation)
+            }
+            _ => 1.0,
+        }
+    }
+}
@@ -0,0 +1,163 @@
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+/// A stretch of constant tone; a duty of 0 is silence.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Segment {
+    pub frequency_hz: u32,
+    // of the PWM driving the buzzer, 0.5 is the loudest
+    pub duty: f32,
+    pub duration: Duration,
+}
+
+impl Segment {
+    pub const fn tone(frequency_hz: u32, duration: Duration) -> Self {
+        Self {
+            frequency_hz,
+            duty: 0.5,
+            duration,
+        }
+    }
+
+    pub const fn silence(duration: Duration) -> Self {
+        Self {
+            frequency_hz: 0,
+            duty: 0.0,
+            duration,
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.duty <= 0.0 || self.frequency_hz == 0
+    }
+}
+
+pub type Pattern = Vec<Segment>;
+
+pub const BARKER_13: [i8; 13] = [1, 1, 1, 1, 1, -1, -1, 1, 1, -1, 1, -1, 1];
+
+/// The signals the car can emit; the tracker looks for the same one.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Code {
+    // a single tone
+    Square {
+        on: Duration,
+    },
+    // a stepped sweep from one frequency to another
+    Chirp {
+        from_hz: u32,
+        to_hz: u32,
+        duration: Duration,
+        steps: u32,
+    },
+    // Barker 13 sequence, +1 and -1 chips keyed as two frequencies
+    Barker {
+        low_hz: u32,
+        high_hz: u32,
+        chip: Duration,
+    },
+    // a Barker 13 preamble followed by the 8 bits of the id of the car, MSB first
+    CarId {
+        id: u8,
+        low_hz: u32,
+        high_hz: u32,
+        chip: Duration,
+    },
+}
+
+/// The signal of the `coded` feature: a 2 to 4 kHz sweep, which the matched filter
+/// compresses to a peak about half a millisecond wide.
+pub const CODED: Code = Code::Chirp {
+    from_hz: 2000,
+    to_hz: 4000,
+    duration: Duration::from_millis(10),
+    steps: 20,
+};
+
+/// What the car plays when its battery is low: three falling tones, well below the tones the tracker
+/// listens for, shorter than the beep period.
+pub fn low_battery() -> Pattern {
+    [1500, 1200, 900]
+        .iter()
+        .flat_map(|frequency_hz| {
+            vec![
+                Segment::tone(*frequency_hz, Duration::from_millis(80)),
+                Segment::silence(Duration::from_millis(40)),
+            ]
+        })
+        .collect()
+}
+
+fn keyed(chips: &[i8], low_hz: u32, high_hz: u32, chip: Duration) -> Pattern {
+    chips
+        .iter()
+        .map(|value| Segment::tone(if *value > 0 { high_hz } else { low_hz }, chip))
+        .collect()
+}
+
+impl Code {
+    /// Segments of one emission at `frequency_hz` (used by the single tone only).
+    pub fn pattern(&self, frequency_hz: u32) -> Pattern {
+        match *self {
+            Code::Square { on } => vec![Segment::tone(frequency_hz, on)],
+            Code::Chirp {
+                from_hz,
+                to_hz,
+                duration,
+                steps,
+            } => {
+                let steps = steps.max(1);
+                (0..steps)
+                    .map(|k| {
+                        let frequency = from_hz as f32
+                            + (to_hz as f32 - from_hz as f32) * (k as f32 + 0.5) / steps as f32;
+                        Segment::tone(frequency.round() as u32, duration / steps)
+                    })
+                    .collect()
+            }
+            Code::Barker {
+                low_hz,
+                high_hz,
+                chip,
+            } => keyed(&BARKER_13, low_hz, high_hz, chip),
+            Code::CarId {
+                id,
+                low_hz,
+                high_hz,
+                chip,
+            } => {
+                let bits: Vec<i8> = (0..8)
+                    .rev()
+                    .map(|bit| if id >> bit & 1 == 1 { 1 } else { -1 })
+                    .collect();
+                let mut pattern = keyed(&BARKER_13, low_hz, high_hz, chip);
+                pattern.extend(keyed(&bits, low_hz, high_hz, chip));
+                pattern
+            }
+        }
+    }
+}
+
+pub fn duration(pattern: &[Segment]) -> Duration {
+    pattern.iter().map(|segment| segment.duration).sum()
+}
+
+/// The pattern as the buzzer plays it, sampled at `sample_rate` and scaled to -1..1.
+pub fn render(pattern: &[Segment], sample_rate: f32) -> Vec<f32> {
+    let mut samples = Vec::new();
+    let mut phase = 0.0f32;
+    for segment in pattern {
+        let count = (segment.duration.as_secs_f32() * sample_rate).round() as usize;
+        for _ in 0..count {
+            if segment.is_silent() {
+                samples.push(0.0);
+                continue;
+            }
+            // the fundamental of the PWM square wave
+            samples.push((2.0 * std::f32::consts::PI * phase).sin());
+            phase = (phase + segment.frequency_hz as f32 / sample_rate).fract();
+        }
+    }
+    samples
+}
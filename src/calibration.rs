@@ -0,0 +1,180 @@
+#![allow(dead_code)]
+
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+use crate::geometry::{ArrayGeometry, Point};
+
+/// Where the sound source was while an event was recorded.
+#[derive(Debug, Copy, Clone)]
+pub enum Source {
+    // somewhere on the bisector of microphones a and b, only calibrates b against a
+    Bisector,
+    At(Point),
+}
+
+/// Arrival times of one sound at each channel, in nanoseconds from any common reference.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub source: Source,
+    pub arrivals: Vec<i64>,
+}
+
+/// Detection latency of each channel relative to channel a, in nanoseconds.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Calibration {
+    pub delays: Vec<i64>,
+}
+
+impl Calibration {
+    pub fn none(channels: usize) -> Self {
+        Self {
+            delays: vec![0; channels],
+        }
+    }
+
+    /// Removes the bias the channel latencies add to `arrival i - arrival j`.
+    pub fn correct_offset(&self, i: usize, j: usize, offset_ns: i128) -> i128 {
+        offset_ns - (self.delays[i] - self.delays[j]) as i128
+    }
+
+    /// Removes the channel latencies from arrival times in nanoseconds.
+    pub fn correct(&self, arrivals: &[i64]) -> Vec<i64> {
+        arrivals
+            .iter()
+            .zip(self.delays.iter())
+            .map(|(arrival, delay)| arrival - delay)
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.delays
+            .iter()
+            .flat_map(|delay| delay.to_le_bytes())
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.is_empty() || bytes.len() % 8 != 0 {
+            bail!("Unexpected calibration of {} bytes", bytes.len());
+        }
+        let delays = bytes
+            .chunks_exact(8)
+            .map(|chunk| Ok(i64::from_le_bytes(chunk.try_into()?)))
+            .collect::<Result<_>>()?;
+        Ok(Self { delays })
+    }
+}
+
+/// Result of `solve`: the delays and how well the events agree with them.
+#[derive(Debug, Clone)]
+pub struct Solution {
+    pub calibration: Calibration,
+    // standard deviation of the per event delay estimates of each channel, in nanoseconds
+    pub residuals: Vec<f64>,
+    pub samples: Vec<usize>,
+}
+
+/// Least squares estimate of the channel delays.
+///
+/// Every event gives one equation per calibrated channel:
+/// `delay i - delay a = measured (i - a) - expected (i - a)`,
+/// so the estimate of each delay is the mean of its equations.
+pub fn solve(geometry: &ArrayGeometry, events: &[Event]) -> Result<Solution> {
+    let channels = geometry.microphones.len();
+    let mut estimates: Vec<Vec<f64>> = vec![Vec::new(); channels];
+
+    for event in events {
+        if event.arrivals.len() != channels {
+            bail!("Unexpected event with {} arrivals", event.arrivals.len());
+        }
+        let measured = |i: usize| (event.arrivals[i] - event.arrivals[0]) as f64;
+        match event.source {
+            Source::Bisector => estimates[1].push(measured(1)),
+            Source::At(source) => {
+                for (i, estimate) in estimates.iter_mut().enumerate().skip(1) {
+                    let expected = geometry.expected_path_difference(i, 0, &source)
+                        / geometry.speed_of_sound()
+                        * 1e9;
+                    estimate.push(measured(i) - expected as f64);
+                }
+            }
+        }
+    }
+
+    let mut solution = Solution {
+        calibration: Calibration::none(channels),
+        residuals: vec![0.0; channels],
+        samples: vec![0; channels],
+    };
+    for (i, estimate) in estimates.iter().enumerate().skip(1) {
+        if estimate.is_empty() {
+            continue;
+        }
+        let mean = estimate.iter().sum::<f64>() / estimate.len() as f64;
+        let variance =
+            estimate.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / estimate.len() as f64;
+        solution.calibration.delays[i] = mean.round() as i64;
+        solution.residuals[i] = variance.sqrt();
+        solution.samples[i] = estimate.len();
+    }
+
+    if solution.samples[1] == 0 {
+        bail!("No events to calibrate from");
+    }
+
+    Ok(solution)
+}
+
+/// Collects the events of a calibration run, one source position after the other.
+pub struct Collector {
+    sources: Vec<Source>,
+    events_per_source: usize,
+    events: Vec<Event>,
+    last: Vec<i64>,
+}
+
+impl Collector {
+    pub fn new(sources: &[Source], events_per_source: usize) -> Self {
+        Self {
+            sources: sources.to_vec(),
+            events_per_source,
+            events: Vec::new(),
+            last: Vec::new(),
+        }
+    }
+
+    /// The position the source is expected at, `None` once all events are collected.
+    pub fn current_source(&self) -> Option<Source> {
+        self.sources
+            .get(self.events.len() / self.events_per_source)
+            .copied()
+    }
+
+    /// Returns true when the event completed the events of the current source position.
+    pub fn push(&mut self, arrivals: &[i64]) -> bool {
+        // the same detection is reported until the next sound
+        if self.last == arrivals {
+            return false;
+        }
+        self.last = arrivals.to_vec();
+        let source = match self.current_source() {
+            Some(source) => source,
+            None => return false,
+        };
+        self.events.push(Event {
+            source,
+            arrivals: arrivals.to_vec(),
+        });
+        self.events.len() % self.events_per_source == 0
+    }
+
+    pub fn done(&self) -> bool {
+        self.current_source().is_none()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+}
@@ -1,13 +1,34 @@
 #![allow(unused_imports)]
 #![allow(clippy::single_component_path_imports)]
 
+#[cfg(feature = "battery")]
+mod battery;
+mod beep_pattern;
+mod car_control;
 mod common;
+mod estimator;
+mod flight_recorder;
+#[cfg(feature = "obstacle")]
+mod gpio_echo;
+#[cfg(feature = "imu")]
+mod imu;
+mod mission;
+mod motor;
+#[cfg(feature = "obstacle")]
+mod obstacle;
+mod odometry;
+#[cfg(feature = "encoders")]
+mod pcnt_encoder;
+mod protocol;
+mod search;
+mod state_machine;
+mod timebase;
 
 use std::fs;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{TcpListener, TcpStream, UdpSocket};
 use std::path::PathBuf;
-use std::sync::{Condvar, Mutex};
+use std::sync::{mpsc, Condvar, Mutex};
 use std::{cell::RefCell, env, sync::atomic::*, sync::Arc, thread, time::*};
 
 use anyhow::bail;
@@ -30,6 +51,7 @@ use embedded_svc::httpd::*;
 use embedded_svc::io;
 use embedded_svc::ipv4;
 use embedded_svc::mqtt::client::{Client, Connection, MessageImpl, Publish, QoS};
+use embedded_svc::storage::{RawStorage, StorageBase};
 use embedded_svc::ping::Ping;
 use embedded_svc::sys_time::SystemTime;
 use embedded_svc::timer::TimerService;
@@ -44,6 +66,7 @@ use esp_idf_svc::httpd::ServerRegistry;
 use esp_idf_svc::mqtt::client::*;
 use esp_idf_svc::netif::*;
 use esp_idf_svc::nvs::*;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
 use esp_idf_svc::ping;
 use esp_idf_svc::sntp;
 use esp_idf_svc::sysloop::*;
@@ -88,136 +111,370 @@ use arc_swap::{ArcSwap, AsRaw};
 
 // reference https://github.com/esp-rs/esp-idf-hal/blob/447fcc3616e3a3643ca109d4bc7acf40754da9af/examples/ledc-threads.rs
 
-struct EnginePWMChannel<C0, H0, T0, P0, C1, H1, T1, P1> where
-    C0: HwChannel,
-    H0: HwTimer,
-    T0: Borrow<ledc::Timer<H0>>,
-    P0: gpio::OutputPin,
-    C1: HwChannel,
-    H1: HwTimer,
-    T1: Borrow<ledc::Timer<H1>>,
-    P1: gpio::OutputPin,
+type DutyUnsigned = u32;
+
+// a passive buzzer driven by a LEDC channel on a timer of its own, so the frequency can change
+struct Beeper<C, H, T, P> where
+    C: HwChannel,
+    H: HwTimer,
+    T: Borrow<ledc::Timer<H>>,
+    P: gpio::OutputPin,
 {
-    positive: Channel<C0, H0, T0, P0>,
-    negative: Channel<C1, H1, T1, P1>,
+    channel: Channel<C, H, T, P>,
+    frequency_hz: u32,
 }
 
-type DutyUnsigned = u32;
-type DutySigned = i32;
-
-impl<C0, H0, T0, P0, C1, H1, T1, P1> EnginePWMChannel<C0, H0, T0, P0, C1, H1, T1, P1> where
-    C0: HwChannel,
-    H0: HwTimer,
-    T0: Borrow<ledc::Timer<H0>>,
-    P0: gpio::OutputPin,
-    C1: HwChannel,
-    H1: HwTimer,
-    T1: Borrow<ledc::Timer<H1>>,
-    P1: gpio::OutputPin,
+impl<C, H, T, P> Beeper<C, H, T, P> where
+    C: HwChannel,
+    H: HwTimer,
+    T: Borrow<ledc::Timer<H>>,
+    P: gpio::OutputPin,
 {
-    fn get_max_duty_unsigned(&self) -> DutyUnsigned {
-        assert_eq!(self.positive.get_max_duty(), self.negative.get_max_duty());
-        self.positive.get_max_duty()
+    fn new(channel: Channel<C, H, T, P>, frequency_hz: u32) -> Self {
+        Self { channel, frequency_hz }
     }
-    fn set_duty(&mut self, duty: DutySigned) -> Result<()> {
-        if duty > 0 {
-            self.positive.set_duty(duty as DutyUnsigned)?;
-            self.negative.set_duty(0)?;
-        } else {
-            self.positive.set_duty(0)?;
-            self.negative.set_duty(-duty as DutyUnsigned)?;
+    fn silence(&mut self) -> Result<()> {
+        self.channel.set_duty(0)?;
+        Ok(())
+    }
+    fn tone(&mut self, frequency_hz: u32, duty: f32) -> Result<()> {
+        if frequency_hz != self.frequency_hz {
+            // the HAL only sets the frequency when creating the timer
+            esp!(unsafe { esp_idf_sys::ledc_set_freq(esp_idf_sys::ledc_mode_t_LEDC_LOW_SPEED_MODE, H::timer(), frequency_hz) })?;
+            self.frequency_hz = frequency_hz;
+        }
+        let duty = (duty.clamp(0.0, 1.0) * self.channel.get_max_duty() as f32) as DutyUnsigned;
+        self.channel.set_duty(duty)?;
+        Ok(())
+    }
+    // blocks while playing, `on_start` gets when the pattern started as soon as it sounds
+    fn play(&mut self, pattern: &[beep_pattern::Segment], on_start: impl FnOnce(Instant)) -> Result<()> {
+        let started = Instant::now();
+        let mut on_start = Some(on_start);
+        let mut end = started;
+        for segment in pattern {
+            if segment.is_silent() {
+                self.silence()?;
+            } else {
+                self.tone(segment.frequency_hz, segment.duty)?;
+            }
+            if let Some(on_start) = on_start.take() {
+                on_start(started);
+            }
+            end += segment.duration;
+            wait_until(end);
         }
+        self.silence()?;
         Ok(())
     }
 }
 
-struct CarEngines<C0, H0, T0, P0, C1, H1, T1, P1, C2, H2, T2, P2, C3, H3, T3, P3> where
-    C0: HwChannel,
-    H0: HwTimer,
-    T0: Borrow<ledc::Timer<H0>>,
-    P0: gpio::OutputPin,
-    C1: HwChannel,
-    H1: HwTimer,
-    T1: Borrow<ledc::Timer<H1>>,
-    P1: gpio::OutputPin,
-    C2: HwChannel,
-    H2: HwTimer,
-    T2: Borrow<ledc::Timer<H2>>,
-    P2: gpio::OutputPin,
-    C3: HwChannel,
-    H3: HwTimer,
-    T3: Borrow<ledc::Timer<H3>>,
-    P3: gpio::OutputPin,
-{
-    engine1: EnginePWMChannel<C0, H0, T0, P0, C1, H1, T1, P1>,
-    engine2: EnginePWMChannel<C2, H2, T2, P2, C3, H3, T3, P3>,
+// sleeping is only as precise as the FreeRTOS tick, so spin for the rest
+fn wait_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        let left = deadline - now;
+        if left > BEEP_SPIN_TIME {
+            thread::sleep(left - BEEP_SPIN_TIME);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
 }
 
-impl<C0, H0, T0, P0, C1, H1, T1, P1, C2, H2, T2, P2, C3, H3, T3, P3>
-CarEngines<C0, H0, T0, P0, C1, H1, T1, P1, C2, H2, T2, P2, C3, H3, T3, P3> where
-    C0: HwChannel,
-    H0: HwTimer,
-    T0: Borrow<ledc::Timer<H0>>,
-    P0: gpio::OutputPin,
-    C1: HwChannel,
-    H1: HwTimer,
-    T1: Borrow<ledc::Timer<H1>>,
-    P1: gpio::OutputPin,
-    C2: HwChannel,
-    H2: HwTimer,
-    T2: Borrow<ledc::Timer<H2>>,
-    P2: gpio::OutputPin,
-    C3: HwChannel,
-    H3: HwTimer,
-    T3: Borrow<ledc::Timer<H3>>,
-    P3: gpio::OutputPin,
-{
-    fn get_max_duty_unsigned(&self) -> DutyUnsigned {
-        assert_eq!(self.engine1.get_max_duty_unsigned(), self.engine2.get_max_duty_unsigned());
-        self.engine1.get_max_duty_unsigned()
-    }
-    fn set_duty_same(&mut self, duty: DutySigned) -> Result<()> {
-        self.engine1.set_duty(duty)?;
-        self.engine2.set_duty(duty)?;
-        Ok(())
+// connects again shortly after the tracker closes the connection, the link is down only while that fails
+fn recv_client_thread<CB: FnMut(common::ControlData) -> Result<()>, Link: FnMut(bool), Cont: Fn() -> bool>(cont: Cont, mut link: Link, mut cb: CB) -> Result<()> {
+    let mut buffer = vec![0; common::ControlData::size()];
+
+    while cont() {
+        info!("About to open a TCP connection to 192.168.71.1 port 8080");
+
+        let mut stream = match TcpStream::connect("192.168.71.1:8080") {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to connect to server: {:?}", e);
+                link(false);
+                std::thread::sleep(Duration::from_millis(1000));
+                continue;
+            }
+        };
+        link(true);
+
+        loop {
+            if !cont() { break; }
+            if let Err(e) = stream.read_exact(&mut buffer) {
+                info!("Connection to the tracker closed: {:?}", e);
+                break;
+            }
+            if !cont() { break; }
+            cb(*common::ControlData::from_slice(&buffer))?;
+        }
+        std::thread::sleep(RECONNECT_DELAY);
     }
+
+    Ok(())
 }
 
-fn recv_client_thread<CB: FnMut(common::ControlData) -> Result<()>, Cont: Fn() -> bool>(cont: Cont, mut cb: CB) -> Result<()> {
-    info!("About to open a TCP connection to 192.168.71.1 port 8080");
+// sends the flight recorder in its binary format to whoever connects to TCP port 8083
+fn flight_recorder_server(recorder: Arc<Mutex<flight_recorder::FlightRecorder>>) -> Result<()> {
+    info!("About to bind the flight recorder dump to port 8083");
+
+    let listener = TcpListener::bind("0.0.0.0:8083")?;
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let bytes = recorder.lock().unwrap().to_bytes();
+                if let Err(e) = stream.write_all(&bytes) {
+                    warn!("Failed to dump the flight recorder: {:?}", e);
+                }
+            }
+            Err(e) => error!("Error: {}", e),
+        }
+    }
 
+    Ok(())
+}
 
+// leaves the flight record on the serial console once the run is over, away from the control tick
+fn flight_record_printer(
+    recorder: Arc<Mutex<flight_recorder::FlightRecorder>>,
+    odometry: Arc<Mutex<odometry::Odometry>>,
+    requests: mpsc::Receiver<()>,
+) {
+    for () in requests {
+        let ticks = recorder.lock().unwrap().ticks();
+        println!("{}", flight_recorder::Tick::CSV_HEADER);
+        for tick in ticks {
+            println!("{}", tick.to_csv());
+        }
+        let (pose, distance) = {
+            let odometry = odometry.lock().unwrap();
+            (odometry.pose(), odometry.distance())
+        };
+        info!("Odometry: {:?}, {:.3} m driven", pose, distance);
+    }
+}
 
-    let mut stream = loop {
-        match TcpStream::connect("192.168.71.1:8080") {
-            Ok(stream) => break stream,
+fn load_mission(storage: &mut EspNvsStorage) -> Result<mission::Mission> {
+    match storage.get_raw(MISSION_KEY)? {
+        Some(bytes) => match std::str::from_utf8(&bytes).map_err(anyhow::Error::from).and_then(mission::Mission::parse) {
+            Ok(mission) => {
+                info!("Loaded a mission of {} goals", mission.goals().len());
+                Ok(mission)
+            }
+            // e.g. stored by an older firmware, it must not keep the car from driving
             Err(e) => {
-                warn!("Failed to connect to server: {:?}", e);
-                std::thread::sleep(Duration::from_millis(1000));
+                warn!("Dropping the stored mission, driving to the line: {:?}", e);
+                if let Err(e) = storage.remove(MISSION_KEY) {
+                    warn!("Failed to remove the stored mission: {:?}", e);
+                }
+                Ok(mission::Mission::default())
             }
+        },
+        None => {
+            info!("No mission stored, driving to the line");
+            Ok(mission::Mission::default())
         }
-    };
+    }
+}
 
-    let mut buffer = vec![0; common::ControlData::size()];
+fn read_mission(stream: &mut TcpStream) -> Result<(String, mission::Mission)> {
+    // the sender has to close its side, `nc -N` does
+    stream.set_read_timeout(Some(MISSION_UPLOAD_TIMEOUT))?;
+    let mut text = String::new();
+    stream.take(MISSION_MAX_BYTES + 1).read_to_string(&mut text)?;
+    if text.len() as u64 > MISSION_MAX_BYTES {
+        bail!("The mission is longer than {} bytes", MISSION_MAX_BYTES);
+    }
+    let mission = mission::Mission::parse(&text)?;
+    Ok((text, mission))
+}
 
-    loop {
-        if !cont() { break; }
-        stream.read_exact(&mut buffer)?;
-        if !cont() { break; }
-        cb(*common::ControlData::from_slice(&buffer))?;
+// takes a mission as text from whoever connects to TCP port 8084, stores it and starts it over,
+// answering with the number of goals or what is wrong with it
+fn mission_server(control: Arc<Mutex<CarControl>>, mut storage: EspNvsStorage) -> Result<()> {
+    info!("About to bind the mission upload to port 8084");
+
+    let listener = TcpListener::bind("0.0.0.0:8084")?;
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let answer = match read_mission(&mut stream) {
+                    Ok((text, mission)) => {
+                        if let Err(e) = storage.put_raw(MISSION_KEY, text.as_bytes()) {
+                            warn!("Failed to store the mission: {:?}", e);
+                        }
+                        info!("Uploaded a mission of {} goals", mission.goals().len());
+                        let answer = format!("ok: {} goals\n", mission.goals().len());
+                        control.lock().unwrap().set_mission(mission);
+                        answer
+                    }
+                    Err(e) => {
+                        warn!("Rejected a mission: {}", e);
+                        format!("error: {}\n", e)
+                    }
+                };
+                if let Err(e) = stream.write_all(answer.as_bytes()) {
+                    warn!("Failed to answer the mission upload: {:?}", e);
+                }
+            }
+            Err(e) => error!("Error: {}", e),
+        }
     }
 
     Ok(())
 }
 
-#[derive(PartialEq)]
-enum State {
-    Init,
-    ForwardToLine,
-    Done,
+// keeps the car clock synchronized with the time base of the tracker
+#[cfg(feature = "tof")]
+fn clock_sync_thread(timebase: Arc<timebase::Timebase>, sync: Arc<Mutex<timebase::ClockSync>>, socket: UdpSocket) -> Result<()> {
+    info!("About to synchronize the clock with 192.168.71.1 port 8081");
+
+    socket.set_read_timeout(Some(CLOCK_SYNC_INTERVAL))?;
+
+    let mut buffer = vec![0; common::TimePacket::size()];
+
+    loop {
+        let request = common::TimePacket {
+            kind: common::TIME_SYNC_REQUEST,
+            sent: timebase.now(),
+            tracker: 0,
+        };
+        if let Err(e) = socket.send(request.to_slice()) {
+            warn!("Failed to send clock sync request: {:?}", e);
+        }
+        match socket.recv(&mut buffer) {
+            Ok(len) if len == common::TimePacket::size() => {
+                let response = *common::TimePacket::from_slice(&buffer);
+                if response.kind == common::TIME_SYNC_RESPONSE {
+                    sync.lock().unwrap().update(response.sent, response.tracker, timebase.now());
+                }
+            }
+            Ok(len) => warn!("Unexpected time packet of {} bytes", len),
+            Err(e) => warn!("No clock sync response: {:?}", e),
+        }
+        std::thread::sleep(CLOCK_SYNC_INTERVAL);
+    }
 }
 
-const BEEP_HALF_CYCLE: Duration = Duration::from_millis(200);
+use car_control::{CarControl, State};
+use state_machine::Action;
+use motor::MotorDriver;
+#[cfg(feature = "encoders")]
+use odometry::Encoder;
+#[cfg(feature = "imu")]
+use imu::Imu;
+#[cfg(feature = "obstacle")]
+use obstacle::RangeSensor;
+
+// what the car emits every BEEP_PERIOD while driving, keep in sync with `tone_config` of the tracker
+const BEEP_FREQUENCY_HZ: u32 = 2700;
+#[cfg(not(feature = "coded"))]
+const BEEP_CODE: beep_pattern::Code = beep_pattern::Code::Square { on: Duration::from_millis(200) };
+#[cfg(feature = "coded")]
+const BEEP_CODE: beep_pattern::Code = beep_pattern::CODED;
+const BEEP_PERIOD: Duration = Duration::from_millis(400);
+// waits shorter than this are spun instead of slept
+const BEEP_SPIN_TIME: Duration = Duration::from_millis(20);
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_millis(1000);
+// the tracker closes the connection after sending its latest control data, ask again after this
+const RECONNECT_DELAY: Duration = Duration::from_millis(50);
+
+// control ticks kept by the flight recorder, a minute at 0.1 s per tick
+const FLIGHT_RECORDER_TICKS: usize = 600;
+
+// where the mission is kept across restarts, see `src/mission.rs` for its format
+const MISSION_KEY: &str = "mission";
+const MISSION_MAX_BYTES: u64 = 4000;
+const MISSION_UPLOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how the car follows the tracker
+fn control_config() -> car_control::CarConfig {
+    car_control::CarConfig {
+        // full duty when the car is 25 cm (of path difference) off the line
+        kp: 4.0,
+        // a tenth of the duty moves from one engine to the other when the car is 11 degrees off its heading
+        kp_heading: 0.5,
+        search: search::SearchConfig {
+            // about a turn in 4 s
+            turn: 0.3,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+// how each engine follows the control output, left then right; trim one if the car pulls to a side
+fn motor_output() -> [motor::OutputConfig; 2] {
+    let engine = motor::OutputConfig {
+        // the engines stall below this
+        min_duty: 0.15,
+        stop: motor::Stop::Brake,
+        ..Default::default()
+    };
+    [engine, engine]
+}
+const CONTROL_PERIOD: Duration = Duration::from_millis(100);
+// of the control thread, for the I2C drivers and the log lines
+const CONTROL_STACK_SIZE: usize = 8 * 1024;
+
+// the wheels, with encoders of 20 slots on the motor shaft and 1:48 gears, counting 4 edges per slot
+const ODOMETRY: odometry::OdometryConfig = odometry::OdometryConfig {
+    counts_per_revolution: 20.0 * 48.0 * 4.0,
+    wheel_diameter: 0.065,
+    track_width: 0.13,
+};
+// signals A and B of the left and right encoder
+#[cfg(feature = "encoders")]
+const ENCODER_PINS: [(i32, i32); 2] = [(18, 19), (22, 23)];
+// how the wheels follow the control output with the encoders, full output being full speed
+#[cfg(feature = "encoders")]
+const WHEEL_SPEED: odometry::SpeedConfig = odometry::SpeedConfig {
+    max_speed: 0.8,
+    kp: 0.5,
+    ki: 0.1,
+};
+
+// gyro readings averaged into its bias at start, the car must stand still meanwhile
+#[cfg(feature = "imu")]
+const GYRO_CALIBRATION_SAMPLES: usize = 200;
+#[cfg(feature = "imu")]
+const GYRO_CALIBRATION_MAX_DEVIATION: f32 = 0.02;
+#[cfg(feature = "imu")]
+const YAW: imu::YawConfig = imu::YawConfig {
+    // the magnetometer corrects the drift of the gyro over a few seconds
+    gyro_weight: 0.98,
+};
+
+// when the car stops for an obstacle ahead
+#[cfg(feature = "obstacle")]
+const SAFETY: obstacle::SafetyConfig = obstacle::SafetyConfig {
+    stop_distance: 0.25,
+    clear_distance: 0.35,
+    // a few missed measurements
+    max_age: Duration::from_millis(300),
+};
+// between two measurements of the range sensor, so that the echoes of one are gone before the next
+#[cfg(feature = "obstacle")]
+const RANGE_PERIOD: Duration = Duration::from_millis(60);
+
+// a 2S LiPo, on GPIO35 through a 100k/33k divider
+#[cfg(feature = "battery")]
+const BATTERY: battery::BatteryConfig = battery::BatteryConfig {
+    divider: (100.0 + 33.0) / 33.0,
+    nominal: 7.4,
+    // 3.3 V per cell
+    low: 6.6,
+    low_for: Duration::from_secs(2),
+    smoothing: Duration::from_millis(500),
+    // the engines do not get more than a third stronger
+    max_compensation: 1.33,
+};
 
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
@@ -227,55 +484,159 @@ fn main() -> Result<()> {
 
     let peripherals = Peripherals::take().unwrap();
 
-    let mut wifi = common::init_wifi_client()?;
+    let default_nvs = Arc::new(EspDefaultNvs::new()?);
+
+    let mut wifi = common::init_wifi_client(default_nvs.clone())?;
+
+    let mut storage = EspNvsStorage::new_default(default_nvs, "car", true)?;
 
 
     let config = config::TimerConfig::default().frequency(25.kHz().into());
     let timer = Arc::new(ledc::Timer::new(peripherals.ledc.timer0, &config)?);
 
-    let mut car_engines = CarEngines {
-        engine1: EnginePWMChannel {
-            positive: Channel::new(peripherals.ledc.channel0, timer.clone(), peripherals.pins.gpio4)?,
-            negative: Channel::new(peripherals.ledc.channel1, timer.clone(), peripherals.pins.gpio5)?,
-        },
-        engine2: EnginePWMChannel {
-            positive: Channel::new(peripherals.ledc.channel2, timer.clone(), peripherals.pins.gpio6)?,
-            negative: Channel::new(peripherals.ledc.channel3, timer.clone(), peripherals.pins.gpio7)?,
-        },
+    // the car logic only sees MotorDriver, other drivers are motor::PwmDir and motor::PwmInputs (L298N)
+    let left: Box<dyn MotorDriver + Send> = Box::new(motor::HBridge::new(
+        Channel::new(peripherals.ledc.channel0, timer.clone(), peripherals.pins.gpio4)?,
+        Channel::new(peripherals.ledc.channel1, timer.clone(), peripherals.pins.gpio5)?,
+    ));
+    let right: Box<dyn MotorDriver + Send> = Box::new(motor::HBridge::new(
+        Channel::new(peripherals.ledc.channel2, timer.clone(), peripherals.pins.gpio6)?,
+        Channel::new(peripherals.ledc.channel3, timer.clone(), peripherals.pins.gpio7)?,
+    ));
+    let output = motor_output();
+    let mut engines = [
+        motor::OutputStage::new(left, output[0]),
+        motor::OutputStage::new(right, output[1]),
+    ];
+
+    let beep_config = config::TimerConfig::default().frequency(BEEP_FREQUENCY_HZ.Hz().into());
+    let beep_timer = ledc::Timer::new(peripherals.ledc.timer1, &beep_config)?;
+    let mut beeper = Beeper::new(Channel::new(peripherals.ledc.channel4, beep_timer, peripherals.pins.gpio0)?, BEEP_FREQUENCY_HZ);
+    beeper.silence()?;
+
+
+    #[cfg(feature = "encoders")]
+    let mut encoders = [
+        pcnt_encoder::PcntEncoder::new(esp_idf_sys::pcnt_unit_t_PCNT_UNIT_0, ENCODER_PINS[0].0, ENCODER_PINS[0].1)?,
+        pcnt_encoder::PcntEncoder::new(esp_idf_sys::pcnt_unit_t_PCNT_UNIT_1, ENCODER_PINS[1].0, ENCODER_PINS[1].1)?,
+    ];
+    #[cfg(feature = "encoders")]
+    let mut wheel_speeds = [odometry::SpeedControl::new(WHEEL_SPEED), odometry::SpeedControl::new(WHEEL_SPEED)];
+
+    #[cfg(feature = "imu")]
+    let (mut imu, mut yaw) = {
+        // SDA on GPIO25, SCL on GPIO26
+        let config = <i2c::config::MasterConfig as Default>::default().baudrate(400.kHz().into());
+        let i2c = i2c::Master::<i2c::I2C0, _, _>::new(
+            peripherals.i2c0,
+            i2c::MasterPins { sda: peripherals.pins.gpio25, scl: peripherals.pins.gpio26 },
+            config,
+        )?;
+        // or imu::Mpu6050::new(i2c, imu::MPU6050_ADDRESS), without magnetometer
+        let mut imu = imu::Icm20948::new(i2c, imu::ICM20948_ADDRESS)?;
+        let bias = loop {
+            let mut samples = Vec::with_capacity(GYRO_CALIBRATION_SAMPLES);
+            for _ in 0..GYRO_CALIBRATION_SAMPLES {
+                samples.push(imu.read()?.gyro);
+                thread::sleep(Duration::from_millis(5));
+            }
+            match imu::gyro_bias(&samples, GYRO_CALIBRATION_MAX_DEVIATION) {
+                Ok(bias) => break bias,
+                Err(e) => warn!("{}, calibrating again", e),
+            }
+        };
+        info!("Gyro bias {:?} rad/s", bias);
+        (imu, imu::YawFilter::new(YAW, bias[2] as f64))
     };
-    let mut car_beep = peripherals.pins.gpio0.into_output()?;
 
-    // disable = low enable = high
-    let beep_disable_val = PinState::Low;
-    let beep_enable_val = PinState::High;
+    let control = Arc::new(Mutex::new(CarControl::new(control_config())));
+    {
+        let mut control = control.lock().unwrap();
+        control.set_mission(load_mission(&mut storage)?);
+        control.arm();
+    }
 
+    // stays where it started without encoders
+    let odometry = Arc::new(Mutex::new(odometry::Odometry::new(ODOMETRY)));
 
-    let max_duty = car_engines.get_max_duty_unsigned();
+    #[cfg(feature = "battery")]
+    let (mut battery, mut battery_pin, mut battery_adc) = (
+        battery::Battery::new(BATTERY),
+        peripherals.pins.gpio35.into_analog_atten_11db()?,
+        adc::PoweredAdc::new(peripherals.adc1, adc::config::Config::new().calibration(true))?,
+    );
 
-    let mut pid: Pid<f64> = Pid::new(10.0, 0.0, 0.0, 100.0, 100.0, 100.0, 1000.0, 0.0);
+    let recorder = Arc::new(Mutex::new(flight_recorder::FlightRecorder::new(FLIGHT_RECORDER_TICKS)));
 
-    let control: Arc<ArcSwap<_>> = Arc::new(ArcSwap::from(Arc::new(common::ControlData::empty())));
+    let mut children = vec![];
 
-    let state: Arc<ArcSwap<_>> = Arc::new(ArcSwap::from(Arc::new(State::Init)));
+    let timebase = Arc::new(timebase::Timebase::new());
 
-    let mut children = vec![];
+    #[cfg(feature = "tof")]
+    let sync = Arc::new(Mutex::new(timebase::ClockSync::new()));
+
+    #[cfg(feature = "tof")]
+    let time_socket = {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect("192.168.71.1:8081")?;
+        let timebase = timebase.clone();
+        let sync = sync.clone();
+        let sync_socket = socket.try_clone()?;
+        children.push(thread::spawn(move || clock_sync_thread(timebase, sync, sync_socket).unwrap()));
+        socket
+    };
+
+    {
+        let recorder = recorder.clone();
+        children.push(thread::spawn(move || flight_recorder_server(recorder).unwrap()));
+    }
+
+    // one record waiting to be printed is enough, it is printed as it is by then
+    let (print_flight_record, flight_record_requests) = mpsc::sync_channel(1);
+    {
+        let recorder = recorder.clone();
+        let odometry = odometry.clone();
+        children.push(thread::spawn(move || flight_record_printer(recorder, odometry, flight_record_requests)));
+    }
+
+    {
+        let control = control.clone();
+        children.push(thread::spawn(move || mission_server(control, storage).unwrap()));
+    }
+
+    #[cfg(feature = "obstacle")]
+    let supervisor = {
+        let supervisor = Arc::new(Mutex::new(obstacle::Supervisor::new(SAFETY)));
+        // trigger on GPIO32, echo on GPIO33 through a divider, the sensor runs on 5 V
+        let mut sensor = obstacle::HcSr04::new(peripherals.pins.gpio32.into_output()?, gpio_echo::GpioEcho::new(33)?)?;
+        let shared = supervisor.clone();
+        children.push(thread::spawn(move || loop {
+            match sensor.distance() {
+                Ok(distance) => shared.lock().unwrap().measure(distance, Instant::now()),
+                Err(e) => {
+                    warn!("Failed to measure the distance ahead: {:?}", e);
+                    shared.lock().unwrap().fail(Instant::now());
+                }
+            }
+            thread::sleep(RANGE_PERIOD);
+        }));
+        supervisor
+    };
 
     println!("Rust main thread: {:?}", thread::current());
 
     {
         let control = control.clone();
-        let state = state.clone();
-        let state0 = state.clone();
+        let control0 = control.clone();
+        let control1 = control.clone();
         children.push(thread::spawn(move || recv_client_thread(
-            move || { **state0.load() != State::Done },
+            // an emergency stop can be reset, the other ends of the run are final
+            move || { let state = control0.lock().unwrap().state(); !state.is_final() || state == State::EStop },
+            move |link| control1.lock().unwrap().set_link(link),
             move |data| {
-                println!("Got data {:?}", data);
-                control.store(Arc::new(data));
-                let load = state.load();
-                let current = load.as_raw();
-                if **load == State::Init {
-                    drop(load);
-                    state.compare_and_swap(current, Arc::new(State::ForwardToLine));
+                // the same control data comes again until the tracker has the next measurement
+                if control.lock().unwrap().receive(data, Instant::now()) {
+                    println!("Got data {:?}", data);
                 }
                 Ok(())
             }).unwrap()));
@@ -283,55 +644,204 @@ fn main() -> Result<()> {
 
     {
         let control = control.clone();
-        let state = state.clone();
+        let faults = control.clone();
+        let timebase = timebase.clone();
+        let odometry = odometry.clone();
+        let mut previous_reached = 0;
         let mut task = move || -> Result<()> {
-            match **state.load() {
-                State::Init => {
-                    car_engines.engine1.set_duty(0)?;
-                    car_engines.engine2.set_duty(0)?;
+            #[allow(unused_mut)]
+            let mut heading = None;
+            #[allow(unused_mut)]
+            let mut position = None;
+            #[allow(unused_mut)]
+            let mut fault = None;
+            #[cfg(feature = "encoders")]
+            {
+                let mut odometry = odometry.lock().unwrap();
+                match (encoders[0].count(), encoders[1].count()) {
+                    (Ok(left), Ok(right)) => {
+                        odometry.update(left, right, CONTROL_PERIOD);
+                        let pose = odometry.pose();
+                        heading = Some(pose.heading);
+                        position = Some((pose.x, pose.y));
+                    }
+                    (Err(e), _) | (_, Err(e)) => {
+                        error!("Failed to read the encoders: {:?}", e);
+                        fault = Some("encoders failed");
+                    }
                 }
-                State::ForwardToLine => {
-                    let output = pid.next_control_output(control.load().offset as f64).output;
-                    let duty = ((output as f64 / 1000.0) * (max_duty as f64)) as DutySigned;
-                    car_engines.engine1.set_duty(duty)?;
-                    car_engines.engine2.set_duty(duty)?;
-                    // todo: alternative control a little bit in case the link is slow
+            }
+            #[cfg(feature = "imu")]
+            {
+                // the gyro does not slip like the wheels
+                match imu.read() {
+                    Ok(sample) => heading = Some(yaw.update(sample.gyro[2] as f64, CONTROL_PERIOD, sample.magnetometer.map(imu::magnetic_heading))),
+                    Err(e) => {
+                        error!("Failed to read the IMU: {:?}", e);
+                        fault = Some("IMU failed");
+                    }
                 }
-                State::Done => {
-                    car_engines.engine1.set_duty(0)?;
-                    car_engines.engine2.set_duty(0)?;
-                    //main_timer.cancel();
+            }
+            #[allow(unused_mut)]
+            let mut obstacle = None;
+            #[allow(unused_mut)]
+            let mut voltage = None;
+            #[cfg(feature = "battery")]
+            {
+                match battery_adc.read(&mut battery_pin) {
+                    Ok(millivolts) => battery.measure(millivolts, Instant::now()),
+                    Err(e) => warn!("Failed to read the battery voltage: {:?}", e),
+                }
+                voltage = battery.voltage();
+                for engine in engines.iter_mut() {
+                    engine.set_supply_compensation(battery.compensation() as f64);
+                }
+            }
+            let (command, state, data, received, transitions, reached, goals) = {
+                let mut control = control.lock().unwrap();
+                control.set_heading(heading);
+                control.set_position(position);
+                if let Some(reason) = fault {
+                    control.fault(reason);
+                }
+                #[cfg(feature = "battery")]
+                {
+                    if battery.low() {
+                        control.low_battery();
+                    }
+                }
+                #[cfg(feature = "obstacle")]
+                {
+                    let supervisor = supervisor.lock().unwrap();
+                    control.set_blocked(supervisor.blocked(Instant::now()));
+                    obstacle = supervisor.distance();
+                }
+                let command = control.tick(Instant::now());
+                let mission = control.mission();
+                (command, control.state(), control.data(), control.received(), control.take_transitions(), mission.reached(), mission.goals().len())
+            };
+            let mut brake = false;
+            for transition in &transitions {
+                info!("Car state {:?} -> {:?}: {}", transition.from, transition.to, transition.reason);
+                for action in &transition.actions {
+                    match action {
+                        Action::Brake => brake = true,
+                        Action::SaveFlightRecord => {
+                            // the run is over, printing its record takes far longer than a tick
+                            let _ = print_flight_record.try_send(());
+                        }
+                        // done by the car control
+                        Action::ResetController | Action::HoldHeading | Action::NextGoal => {}
+                    }
                 }
             }
+            if reached != previous_reached {
+                info!("Mission: {} of {} goals reached", reached, goals);
+                previous_reached = reached;
+            }
+            #[allow(unused_mut)]
+            let mut outputs = [command.output - command.turn, command.output + command.turn];
+            #[cfg(feature = "encoders")]
+            {
+                // closed loop on the wheel speeds, so the car keeps moving as told between the measurements
+                let speeds = odometry.lock().unwrap().speeds();
+                for (i, measured) in [speeds.0, speeds.1].iter().enumerate() {
+                    outputs[i] = wheel_speeds[i].next(outputs[i] * WHEEL_SPEED.max_speed, *measured);
+                }
+            }
+            let mut duties = [0; 2];
+            for ((engine, duty), output) in engines.iter_mut().zip(duties.iter_mut()).zip(outputs.iter()) {
+                if brake {
+                    engine.brake()?;
+                } else {
+                    *duty = engine.drive(*output, CONTROL_PERIOD)?;
+                }
+            }
+            if matches!(state, State::Searching | State::Approaching | State::Blocked | State::Holding) {
+                recorder.lock().unwrap().push(flight_recorder::Tick {
+                    time: timebase.now(),
+                    offset: data.offset as i64,
+                    filtered: data.filtered,
+                    predicted: command.predicted.map(|value| value as f32).unwrap_or(f32::NAN),
+                    age_ms: received.map(|at| at.elapsed().as_millis() as u32).unwrap_or(u32::MAX),
+                    p: command.p as f32,
+                    i: command.i as f32,
+                    d: command.d as f32,
+                    output: command.output as f32,
+                    turn: command.turn as f32,
+                    heading: heading.map(|value| value as f32).unwrap_or(f32::NAN),
+                    obstacle: obstacle.unwrap_or(f32::NAN),
+                    battery: voltage.unwrap_or(f32::NAN),
+                    duty1: duties[0],
+                    duty2: duties[1],
+                    goal: reached as u32,
+                });
+            }
             Ok(())
         };
-        task()?;
-        let mut engines_timer = EspTimerService::new()?.timer(move || task().unwrap())?;
-        // 0.1s
-        engines_timer.every(Duration::from_millis(100))?;
+        // not a timer callback: the tick reads the I2C sensors and takes the locks of the other threads,
+        // which needs more stack than the shared timer task has and must not stall its other timers
+        children.push(thread::Builder::new().stack_size(CONTROL_STACK_SIZE).spawn(move || {
+            let mut next = Instant::now();
+            loop {
+                if let Err(e) = task() {
+                    // the car stops on the next tick
+                    error!("Control tick failed: {:?}", e);
+                    faults.lock().unwrap().fault("control tick failed");
+                }
+                next += CONTROL_PERIOD;
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+        })?);
     }
 
     {
-        let state = state.clone();
-        let mut beeping = beep_disable_val;
+        let control = control.clone();
+        let pattern = BEEP_CODE.pattern(BEEP_FREQUENCY_HZ);
+        let low_battery = beep_pattern::low_battery();
         let mut task = move || -> Result<()> {
-            match **state.load() {
-                State::Init => {
-                    car_beep.set_state(beep_disable_val)?;
+            let state = control.lock().unwrap().state();
+            match state {
+                State::Idle | State::WaitingForLink | State::Fault | State::EStop => {
+                    beeper.silence()?;
                 }
-                State::ForwardToLine => {
-                    car_beep.set_state(beeping)?;
-                    beeping = !beeping;
+                State::Searching | State::Approaching | State::Blocked | State::Holding => {
+                    // report the start of the pattern in the time base of the tracker as soon as it sounds,
+                    // not after playing it, so that the tracker mostly has it before it hears the beep
+                    #[allow(unused_variables)]
+                    beeper.play(&pattern, |started| {
+                        #[cfg(feature = "tof")]
+                        if let Some(tracker) = sync.lock().unwrap().to_remote(timebase.at(started)) {
+                            let report = common::TimePacket {
+                                kind: common::TIME_BEEP_REPORT,
+                                sent: 0,
+                                tracker,
+                            };
+                            if let Err(e) = time_socket.send(report.to_slice()) {
+                                warn!("Failed to send beep report: {:?}", e);
+                            }
+                        }
+                    })?;
                 }
                 State::Done => {
-                    car_beep.set_state(beep_enable_val)?;
+                    beeper.tone(BEEP_FREQUENCY_HZ, 0.5)?;
+                }
+                State::LowBattery => {
+                    beeper.play(&low_battery, |_| {})?;
+                    beeper.silence()?;
                 }
             }
             Ok(())
         };
-        task()?;
-        let mut beep_timer = EspTimerService::new()?.timer(move || task().unwrap())?;
-        beep_timer.every(BEEP_HALF_CYCLE)?;
+        // playing blocks, so it gets a thread instead of a timer callback
+        children.push(thread::spawn(move || {
+            let mut next = Instant::now();
+            loop {
+                task().unwrap();
+                next += BEEP_PERIOD;
+                wait_until(next);
+            }
+        }));
     }
 
     for child in children {
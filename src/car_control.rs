@@ -0,0 +1,378 @@
+#![allow(dead_code)]
+
+// How the car reacts to the control data of the tracker, without the hardware; shared with the host tools
+
+use std::time::{Duration, Instant};
+
+use pid::Pid;
+
+use crate::estimator::{Estimator, EstimatorConfig};
+use crate::mission::{Goal, Mission, MissionConfig};
+use crate::odometry::wrap_angle;
+use crate::protocol::ControlData;
+use crate::search::{self, SearchConfig};
+pub use crate::state_machine::State;
+use crate::state_machine::{Action, Inputs, MachineConfig, StateMachine, Transition};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct CarConfig {
+    // measurements the tracker is less confident about are ignored
+    pub min_confidence: f32,
+    // gains of the PID working on the filtered path difference in metres,
+    // with the output a fraction of the max duty
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    // turn per radian off the heading the car keeps or turns to, when the heading is known
+    pub kp_heading: f64,
+    // how the path difference is predicted between the measurements
+    pub estimator: EstimatorConfig,
+    // timeouts of the states
+    pub machine: MachineConfig,
+    // when the car is at a goal, and how it drives to a position
+    pub mission: MissionConfig,
+    // how the car moves while it is not heard
+    pub search: SearchConfig,
+}
+
+impl Default for CarConfig {
+    fn default() -> Self {
+        Self {
+            min_confidence: 0.3,
+            // full duty when the car is 25 cm (of path difference) off the line
+            kp: 4.0,
+            ki: 0.0,
+            kd: 0.0,
+            kp_heading: 0.5,
+            estimator: EstimatorConfig::default(),
+            machine: MachineConfig::default(),
+            mission: MissionConfig::default(),
+            search: SearchConfig::default(),
+        }
+    }
+}
+
+/// What the engines do on a control tick.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Command {
+    // fraction of the max duty, from -1 to 1
+    pub output: f64,
+    // taken from the left engine and given to the right one, positive turns counterclockwise
+    pub turn: f64,
+    // terms of the PID
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+    // the path difference the PID worked on, None when it did not run
+    pub predicted: Option<f64>,
+}
+
+pub struct CarControl {
+    config: CarConfig,
+    pid: Pid<f64>,
+    machine: StateMachine,
+    // what the car was told, the rest is made on the tick
+    inputs: Inputs,
+    mission: Mission,
+    // taken by the car, which does the actions on the hardware
+    transitions: Vec<Transition>,
+    data: ControlData,
+    received: Option<Instant>,
+    estimator: Estimator,
+    // in radians, counterclockwise from where the car faced at the start
+    heading: Option<f64>,
+    heading_target: Option<f64>,
+    // the first heading of the sensors, in their frame
+    heading_origin: Option<f64>,
+    // in metres, in the frame of the odometry
+    position: Option<(f64, f64)>,
+}
+
+impl CarControl {
+    pub fn new(config: CarConfig) -> Self {
+        Self {
+            config,
+            pid: Pid::new(config.kp, config.ki, config.kd, 1.0, 1.0, 1.0, 1.0, 0.0),
+            machine: StateMachine::new(config.machine, Instant::now()),
+            inputs: Inputs::default(),
+            mission: Mission::default(),
+            transitions: Vec::new(),
+            data: ControlData::empty(),
+            received: None,
+            estimator: Estimator::new(config.estimator),
+            heading: None,
+            heading_target: None,
+            heading_origin: None,
+            position: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.machine.state()
+    }
+
+    /// The control data the car follows.
+    pub fn data(&self) -> ControlData {
+        self.data
+    }
+
+    /// When `data` was received, None before the first.
+    pub fn received(&self) -> Option<Instant> {
+        self.received
+    }
+
+    pub fn heading(&self) -> Option<f64> {
+        self.heading
+    }
+
+    /// The heading of the car from its sensors, None without them. The sensors may count from
+    /// anywhere (the IMU counts from magnetic north), the car counts from their first heading,
+    /// like the odometry does.
+    pub fn set_heading(&mut self, heading: Option<f64>) {
+        if self.heading_origin.is_none() {
+            self.heading_origin = heading;
+        }
+        self.heading = heading
+            .zip(self.heading_origin)
+            .map(|(heading, origin)| wrap_angle(heading - origin));
+    }
+
+    /// Where the car is from its odometry, None without it.
+    pub fn set_position(&mut self, position: Option<(f64, f64)>) {
+        self.position = position;
+    }
+
+    pub fn mission(&self) -> &Mission {
+        &self.mission
+    }
+
+    /// Works through `mission` from its first goal on, instead of the one before.
+    pub fn set_mission(&mut self, mut mission: Mission) {
+        mission.restart();
+        self.mission = mission;
+        self.pid.reset_integral_term();
+    }
+
+    /// Takes control data received at `now`; false if it was ignored.
+    pub fn receive(&mut self, mut data: ControlData, now: Instant) -> bool {
+        if data.confidence < self.config.min_confidence {
+            return false;
+        }
+        // the tracker sends a measurement again until it has the next one
+        if self.received.is_some() && data.sequence == self.data.sequence {
+            // with its ranges, once the tracker has the report of the beep
+            self.data.distance = data.distance;
+            return false;
+        }
+        // the less certain the measurement, the less it moves the car
+        if self.received.is_some() {
+            let previous = self.data.filtered;
+            data.filtered = previous + data.confidence * (data.filtered - previous);
+        }
+        self.data = data;
+        self.received = Some(now);
+        self.estimator.measure(
+            data.sequence,
+            data.filtered as f64,
+            data.rate as f64,
+            Duration::from_micros(data.age as u64),
+            now,
+        );
+        true
+    }
+
+    /// The car is set up and may start the run.
+    pub fn arm(&mut self) {
+        self.inputs.armed = true;
+    }
+
+    /// Whether the car is connected to the tracker.
+    pub fn set_link(&mut self, link: bool) {
+        self.inputs.link = link;
+    }
+
+    /// Whether the safety supervisor sees an obstacle ahead.
+    pub fn set_blocked(&mut self, blocked: bool) {
+        self.inputs.blocked = blocked;
+    }
+
+    /// Stops the run for good, unless it is over already.
+    pub fn low_battery(&mut self) {
+        self.inputs.low_battery = true;
+    }
+
+    /// Stops the run for good because of `reason`, unless it is over already.
+    pub fn fault(&mut self, reason: &'static str) {
+        self.inputs.fault = Some(reason);
+    }
+
+    /// Stops until `reset`.
+    pub fn emergency_stop(&mut self) {
+        self.inputs.emergency_stop = true;
+    }
+
+    /// Starts over after an emergency stop.
+    pub fn reset(&mut self) {
+        self.inputs.emergency_stop = false;
+        self.inputs.fault = None;
+        self.inputs.finish = false;
+        self.inputs.reset = true;
+    }
+
+    pub fn finish(&mut self) {
+        self.inputs.finish = true;
+    }
+
+    /// The transitions since the last call, oldest first.
+    pub fn take_transitions(&mut self) -> Vec<Transition> {
+        std::mem::take(&mut self.transitions)
+    }
+
+    // how far the car is off `goal`, in tolerances of the goal
+    fn goal_error(&self, goal: Goal, predicted: Option<f64>) -> Option<f64> {
+        let config = &self.config.mission;
+        match goal {
+            Goal::Offset(offset) => {
+                predicted.map(|predicted| (predicted - offset).abs() / config.offset_tolerance)
+            }
+            Goal::Bearing(bearing) => self
+                .heading
+                .map(|heading| wrap_angle(bearing - heading).abs() / config.bearing_tolerance),
+            Goal::Position { x, y } => self
+                .position
+                .map(|(at_x, at_y)| (x - at_x).hypot(y - at_y) / config.position_tolerance),
+            Goal::Hold(_) => Some(0.0),
+        }
+    }
+
+    fn machine_inputs(&self, predicted: Option<f64>) -> Inputs {
+        let goal = self.mission.current();
+        let fault = match goal {
+            Some(Goal::Bearing(_)) if self.heading.is_none() => {
+                Some("no heading for a bearing goal")
+            }
+            Some(Goal::Position { .. }) if self.position.is_none() || self.heading.is_none() => {
+                Some("no position for a position goal")
+            }
+            _ => None,
+        };
+        Inputs {
+            fix: predicted.is_some(),
+            needs_fix: matches!(goal, Some(goal) if goal.needs_fix()),
+            goal_error: goal.and_then(|goal| self.goal_error(goal, predicted)),
+            hold: goal.map_or(Duration::from_secs(0), |goal| {
+                self.config.mission.hold(goal)
+            }),
+            last_goal: self.mission.is_last(),
+            // turning on the spot or standing is fine
+            blocked: self.inputs.blocked && matches!(goal, Some(goal) if goal.drives()),
+            fault: self.inputs.fault.or(fault),
+            ..self.inputs
+        }
+    }
+
+    // towards the current goal
+    fn approach(&mut self, predicted: Option<f64>) -> Command {
+        let kp_heading = self.config.kp_heading;
+        let turn_to = move |target: f64, heading: Option<f64>| match heading {
+            Some(heading) => kp_heading * wrap_angle(target - heading),
+            None => 0.0,
+        };
+        match (self.mission.current(), predicted) {
+            (Some(Goal::Offset(offset)), Some(predicted)) => {
+                let output = self.pid.next_control_output(predicted - offset);
+                Command {
+                    output: output.output,
+                    turn: self
+                        .heading_target
+                        .map_or(0.0, |target| turn_to(target, self.heading)),
+                    p: output.p,
+                    i: output.i,
+                    d: output.d,
+                    predicted: Some(predicted),
+                }
+            }
+            (Some(Goal::Bearing(bearing)), _) => Command {
+                turn: turn_to(bearing, self.heading),
+                ..Command::default()
+            },
+            (Some(Goal::Position { x, y }), _) => match self.position {
+                Some((at_x, at_y)) => {
+                    let bearing = (y - at_y).atan2(x - at_x);
+                    let off = self
+                        .heading
+                        .map_or(0.0, |heading| wrap_angle(bearing - heading));
+                    // forward only when facing it, turning first
+                    let speed =
+                        (self.config.mission.kp_position * (x - at_x).hypot(y - at_y)).min(1.0);
+                    Command {
+                        output: speed * off.cos().max(0.0),
+                        turn: turn_to(bearing, self.heading),
+                        ..Command::default()
+                    }
+                }
+                None => Command::default(),
+            },
+            _ => Command::default(),
+        }
+    }
+
+    /// Runs the state machine and the control loop once at `now`; the engines stand still
+    /// unless searching or approaching a goal.
+    pub fn tick(&mut self, now: Instant) -> Command {
+        let predicted = self.estimator.predict(now);
+        // no state leads back to itself on the same inputs, and there are only so many goals,
+        // so this settles
+        while let Some(transition) = self.machine.update(&self.machine_inputs(predicted), now) {
+            for action in &transition.actions {
+                match action {
+                    Action::ResetController => self.pid.reset_integral_term(),
+                    // drive straight on
+                    Action::HoldHeading => self.heading_target = self.heading,
+                    Action::NextGoal => {
+                        self.mission.next_goal();
+                        self.pid.reset_integral_term();
+                    }
+                    // for the hardware
+                    Action::Brake | Action::SaveFlightRecord => {}
+                }
+            }
+            self.transitions.push(transition);
+        }
+        // a reset only leaves an emergency stop
+        if self.machine.state() != State::EStop {
+            self.inputs.reset = false;
+        }
+
+        let command = match self.machine.state() {
+            State::Approaching | State::Blocked => self.approach(predicted),
+            State::Searching => {
+                let elapsed = now.saturating_duration_since(self.machine.entered());
+                let (output, turn) = search::motion(&self.config.search, elapsed);
+                Command {
+                    // the search must not run into obstacles either
+                    output: if self.inputs.blocked {
+                        output.min(0.0)
+                    } else {
+                        output
+                    },
+                    turn,
+                    ..Command::default()
+                }
+            }
+            _ => Command::default(),
+        };
+        let command = if self.machine.state() == State::Blocked {
+            // backing away is fine
+            Command {
+                output: command.output.min(0.0),
+                turn: 0.0,
+                ..command
+            }
+        } else {
+            command
+        };
+        self.estimator.command(now, command.output);
+        command
+    }
+}
@@ -0,0 +1,309 @@
+// Host client for the tracker: connects to its control data port and prints what it sends,
+// or what the car would do with it (`--fake-car`).
+// Run with `cargo run --bin client --target x86_64-unknown-linux-gnu -- [options]`.
+
+mod car_control;
+mod estimator;
+mod mission;
+mod odometry;
+mod protocol;
+mod search;
+mod state_machine;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use car_control::{CarConfig, CarControl};
+use mission::Mission;
+use protocol::ControlData;
+
+const USAGE: &str = "Usage: client [--address <host:port>] [--format table|csv|json] [--output <file>] [--fake-car [--mission <file>]]";
+
+const DEFAULT_ADDRESS: &str = "192.168.71.1:8080";
+// the tracker may close the connection after each record, then the client connects again
+const RECONNECT_DELAY: Duration = Duration::from_millis(50);
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(1000);
+// the control tick of the car
+const CONTROL_PERIOD: Duration = Duration::from_millis(100);
+// rows between repeated headers of the table
+const TABLE_HEADER_EVERY: usize = 20;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+enum Value {
+    Int(i128),
+    Float(f64),
+    Text(String),
+    Floats(Vec<f32>),
+}
+
+impl Value {
+    fn plain(&self) -> String {
+        match self {
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) => format!("{:.4}", value),
+            Value::Text(value) => value.clone(),
+            Value::Floats(values) => values
+                .iter()
+                .map(|value| format!("{:.3}", value))
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    fn json(&self) -> String {
+        let number = |value: f64| {
+            if value.is_finite() {
+                // most values were f32 on the tracker, more digits are noise
+                format!("{:.6}", value)
+            } else {
+                "null".to_string()
+            }
+        };
+        match self {
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) => number(*value),
+            Value::Text(value) => {
+                format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Value::Floats(values) => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|value| number(*value as f64))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+struct Printer {
+    format: Format,
+    outputs: Vec<Box<dyn Write>>,
+    rows: usize,
+}
+
+impl Printer {
+    fn print(&mut self, row: &[(&str, Value)]) -> Result<()> {
+        let mut text = String::new();
+        match self.format {
+            Format::Table => {
+                if self.rows % TABLE_HEADER_EVERY == 0 {
+                    for (name, _) in row {
+                        text.push_str(&format!("{:>14}", name));
+                    }
+                    text.push('\n');
+                }
+                for (_, value) in row {
+                    text.push_str(&format!("{:>14}", value.plain()));
+                }
+            }
+            Format::Csv => {
+                if self.rows == 0 {
+                    let names: Vec<&str> = row.iter().map(|(name, _)| *name).collect();
+                    text.push_str(&names.join(","));
+                    text.push('\n');
+                }
+                let values: Vec<String> = row.iter().map(|(_, value)| value.plain()).collect();
+                text.push_str(&values.join(","));
+            }
+            Format::Json => {
+                let fields: Vec<String> = row
+                    .iter()
+                    .map(|(name, value)| format!("\"{}\":{}", name, value.json()))
+                    .collect();
+                text.push_str(&format!("{{{}}}", fields.join(",")));
+            }
+        }
+        text.push('\n');
+        for output in self.outputs.iter_mut() {
+            output.write_all(text.as_bytes())?;
+            output.flush()?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+}
+
+fn data_row(time: f64, data: &ControlData) -> Vec<(&'static str, Value)> {
+    // copy the fields out of the packed struct
+    let distance = data.distance;
+    vec![
+        ("time_s", Value::Float(time)),
+        ("offset_ns", Value::Int(data.offset)),
+        ("path_diff_m", Value::Float(data.path_difference as f64)),
+        ("filtered_m", Value::Float(data.filtered as f64)),
+        ("rate_m_s", Value::Float(data.rate as f64)),
+        (
+            "bearing_deg",
+            Value::Float((data.bearing as f64).to_degrees()),
+        ),
+        ("confidence", Value::Float(data.confidence as f64)),
+        (
+            "distance_m",
+            Value::Floats(distance.iter().copied().filter(|d| !d.is_nan()).collect()),
+        ),
+    ]
+}
+
+// reads control data forever, connecting again whenever the tracker closes the connection
+fn receive(address: String, sender: mpsc::Sender<(Instant, ControlData)>) {
+    let mut buffer = vec![0; ControlData::size()];
+    loop {
+        let mut stream = match TcpStream::connect(&address) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to connect to {}: {}", address, e);
+                thread::sleep(CONNECT_RETRY_DELAY);
+                continue;
+            }
+        };
+        while stream.read_exact(&mut buffer).is_ok() {
+            let data = *ControlData::from_slice(&buffer);
+            if sender.send((Instant::now(), data)).is_err() {
+                return;
+            }
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+fn main() -> Result<()> {
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut format = Format::Table;
+    let mut output = None;
+    let mut fake_car = false;
+    let mut mission = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" => address = args.next().unwrap_or_default(),
+            "--format" => {
+                format = match args.next().as_deref() {
+                    Some("table") => Format::Table,
+                    Some("csv") => Format::Csv,
+                    Some("json") => Format::Json,
+                    _ => bail!(USAGE),
+                }
+            }
+            "--output" => output = args.next(),
+            "--fake-car" => fake_car = true,
+            "--mission" => mission = args.next(),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => bail!(USAGE),
+        }
+    }
+    if mission.is_some() && !fake_car {
+        bail!(USAGE);
+    }
+    let mission = match mission {
+        Some(path) => Some(Mission::parse(&fs::read_to_string(path)?)?),
+        None => None,
+    };
+
+    let mut outputs: Vec<Box<dyn Write>> = vec![Box::new(io::stdout())];
+    if let Some(path) = output {
+        outputs.push(Box::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ));
+    }
+    let mut printer = Printer {
+        format,
+        outputs,
+        rows: 0,
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    {
+        let address = address.clone();
+        thread::spawn(move || receive(address, sender));
+    }
+    eprintln!("Receiving from {}", address);
+
+    let start = Instant::now();
+
+    if !fake_car {
+        let mut last: Option<Vec<u8>> = None;
+        for (at, data) in receiver {
+            // the tracker sends the same measurement until it has a new one
+            if last.as_deref() == Some(data.to_slice()) {
+                continue;
+            }
+            last = Some(data.to_slice().to_vec());
+            printer.print(&data_row(at.duration_since(start).as_secs_f64(), &data))?;
+        }
+        return Ok(());
+    }
+
+    // what the car would do on each of its control ticks
+    let mut control = CarControl::new(CarConfig::default());
+    if let Some(mission) = mission {
+        control.set_mission(mission);
+    }
+    control.arm();
+    let mut next = Instant::now();
+    loop {
+        next += CONTROL_PERIOD;
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+        let mut received = 0;
+        let mut ignored = 0;
+        for (at, data) in receiver.try_iter() {
+            // the connections come and go, anything received means the link is up
+            control.set_link(true);
+            received += 1;
+            if !control.receive(data, at) {
+                ignored += 1;
+            }
+        }
+        let command = control.tick(Instant::now());
+        for transition in control.take_transitions() {
+            eprintln!(
+                "{:?} -> {:?}: {}",
+                transition.from, transition.to, transition.reason
+            );
+        }
+        let data = control.data();
+        let age = control
+            .received()
+            .map(|at| Instant::now().duration_since(at).as_secs_f64() * 1000.0)
+            .unwrap_or(f64::NAN);
+        printer.print(&[
+            ("time_s", Value::Float(start.elapsed().as_secs_f64())),
+            ("state", Value::Text(format!("{:?}", control.state()))),
+            ("goal", Value::Int(control.mission().reached() as i128)),
+            ("received", Value::Int(received)),
+            ("ignored", Value::Int(ignored)),
+            ("filtered_m", Value::Float(data.filtered as f64)),
+            (
+                "predicted_m",
+                Value::Float(command.predicted.unwrap_or(f64::NAN)),
+            ),
+            ("age_ms", Value::Float(age)),
+            ("p", Value::Float(command.p)),
+            ("i", Value::Float(command.i)),
+            ("d", Value::Float(command.d)),
+            ("output", Value::Float(command.output)),
+        ])?;
+    }
+}
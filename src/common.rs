@@ -191,10 +191,9 @@ fn wifi_server(
     Ok(wifi)
 }
 
-pub fn init_wifi_client() -> Result<Box<EspWifi>> {
+pub fn init_wifi_client(default_nvs: Arc<EspDefaultNvs>) -> Result<Box<EspWifi>> {
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
-    let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
     wifi_client(
         netif_stack.clone(),
@@ -203,10 +202,9 @@ pub fn init_wifi_client() -> Result<Box<EspWifi>> {
     )
 }
 
-pub fn init_wifi_server() -> Result<Box<EspWifi>> {
+pub fn init_wifi_server(default_nvs: Arc<EspDefaultNvs>) -> Result<Box<EspWifi>> {
     let netif_stack = Arc::new(EspNetifStack::new()?);
     let sys_loop_stack = Arc::new(EspSysLoopStack::new()?);
-    let default_nvs = Arc::new(EspDefaultNvs::new()?);
 
     wifi_server(
         netif_stack.clone(),
@@ -215,44 +213,5 @@ pub fn init_wifi_server() -> Result<Box<EspWifi>> {
     )
 }
 
-#[derive(PartialEq, Debug, Copy, Clone)]
-#[repr(packed)]
-pub struct ControlData {
-    pub offset: i128,
-}
-
-// https://stackoverflow.com/questions/25917260/getting-raw-bytes-from-packed-struct/25918452#25918452
-pub fn raw_byte_repr<'a, T>(ptr: &'a T) -> &'a [u8]
-{
-    let p: *const T = ptr;     // the same operator is used as with references
-    let p: *const u8 = p as *const u8;  // convert between pointer types
-    unsafe {
-        slice::from_raw_parts(p, mem::size_of::<T>())
-    }
-}
-
-pub fn struct_repr<'a, T>(ptr: &'a [u8]) -> &'a T {
-    if ptr.len() != std::mem::size_of::<T>() {
-        panic!("Unexpected len");
-    }
-    let p:*const u8 = ptr.as_ptr();
-    let p: *const T = p as *const T;
-    unsafe {
-        std::mem::transmute(p)
-    }
-}
-
-impl ControlData {
-    pub fn empty() -> Self {
-        Self { offset: 0 }
-    }
-    pub fn size() -> usize {
-        mem::size_of::<ControlData>()
-    }
-    pub fn to_slice(&self) -> &[u8] {
-        raw_byte_repr(self)
-    }
-    pub fn from_slice(x: &[u8]) -> &Self {
-        struct_repr(x)
-    }
-}
\ No newline at end of file
+// what the tracker and the car exchange, shared with the host tools
+pub use crate::protocol::*;
@@ -0,0 +1,145 @@
+#![allow(dead_code)]
+
+// Turns the edges of the digital receivers into measurements; shared by the tracker and the replay tool
+
+use std::time::{Duration, Instant};
+
+use crate::calibration::Calibration;
+use crate::geometry::ArrayGeometry;
+use crate::quality;
+
+// relative time
+#[derive(Debug, Clone)]
+pub struct StateData {
+    // arrival time at each receiver
+    pub arrivals: Vec<Instant>,
+    // how long the previous valid pulse of each receiver lasted, from its first edge
+    pub pulses: Vec<Duration>,
+}
+
+impl StateData {
+    pub fn earliest(&self) -> Instant {
+        *self.arrivals.iter().min().unwrap()
+    }
+
+    // arrival times in nanoseconds after the earliest one
+    pub fn relative_arrivals(&self) -> Vec<i64> {
+        let earliest = self.earliest();
+        self.arrivals
+            .iter()
+            .map(|arrival| arrival.duration_since(earliest).as_nanos() as i64)
+            .collect()
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct DetectorConfig {
+    // how long every receiver has to hold its level before a burst counts
+    pub valid_time: Duration,
+    // the latest receiver has to hear the burst within this time of the earliest one
+    pub sound_range_time: Duration,
+    // edges this soon after the first one of a burst are echoes, not a new arrival;
+    // longer than a beep but shorter than the beep period
+    pub blanking_time: Duration,
+    // drop bursts whose delays the array baseline cannot explain, allowing for the receiver jitter
+    pub check_physical_delays: bool,
+    pub physical_delay_margin: Duration,
+}
+
+/// First-arrival detection of bursts from the sampled levels of the receivers.
+pub struct Detector {
+    config: DetectorConfig,
+    last: Vec<Instant>,
+    last_val: Vec<bool>,
+    pulses: Vec<Duration>,
+    // first edge of the current burst on each receiver, reflections come later
+    first: Vec<Instant>,
+}
+
+impl Detector {
+    pub fn new(config: DetectorConfig, receivers: usize, now: Instant) -> Self {
+        Self {
+            config,
+            last: vec![now; receivers],
+            last_val: vec![false; receivers],
+            pulses: vec![Duration::ZERO; receivers],
+            first: vec![now; receivers],
+        }
+    }
+
+    /// Feeds whether receiver `i` hears a sound at `now`; true if that is an edge.
+    pub fn sample(&mut self, i: usize, valid: bool, now: Instant) -> bool {
+        if valid == self.last_val[i] {
+            return false;
+        }
+        if self.last_val[i] {
+            // echoes may have split the pulse
+            self.pulses[i] = now.duration_since(self.first[i]);
+        } else if now.duration_since(self.first[i]) > self.config.blanking_time {
+            self.first[i] = now;
+        }
+        self.last_val[i] = valid;
+        self.last[i] = now;
+        true
+    }
+
+    /// The burst all receivers hear at `now`, once their levels held long enough.
+    pub fn burst(&self, geometry: &ArrayGeometry, now: Instant) -> Option<StateData> {
+        let first_earliest = *self.first.iter().min()?;
+        if !(self.last_val.iter().all(|val| *val)
+            && self
+                .last
+                .iter()
+                .all(|last| now.duration_since(*last) > self.config.valid_time)
+            && self
+                .first
+                .iter()
+                .all(|first| first.duration_since(first_earliest) < self.config.sound_range_time))
+        {
+            return None;
+        }
+        let data = StateData {
+            arrivals: self.first.clone(),
+            pulses: self.pulses.clone(),
+        };
+        if self.config.check_physical_delays
+            && !geometry.is_physical(
+                &data.relative_arrivals(),
+                self.config.physical_delay_margin.as_nanos() as i64,
+            )
+        {
+            return None;
+        }
+        Some(data)
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Measurement {
+    // between receivers a and b, in nanoseconds
+    pub offset: i128,
+    pub path_difference: f32,
+    pub bearing: f32,
+    pub confidence: f32,
+}
+
+pub fn calculate(
+    geometry: &ArrayGeometry,
+    data: &StateData,
+    calibration: &Calibration,
+    range_time: Duration,
+) -> Measurement {
+    let arrivals = calibration.correct(&data.relative_arrivals());
+    let offset = (arrivals[0] - arrivals[1]) as i128;
+    let path_difference = geometry.path_difference(offset);
+    // with more than two receivers the direction is known all around the tracker
+    let bearing = geometry
+        .direction(&arrivals)
+        .unwrap_or_else(|| geometry.bearing(0, 1, path_difference));
+    Measurement {
+        offset,
+        path_difference,
+        bearing,
+        confidence: quality::confidence(geometry, &arrivals, &data.pulses, range_time),
+    }
+}
@@ -0,0 +1,305 @@
+#![allow(dead_code)]
+
+use std::f32::consts::PI;
+use std::ops::{Add, Mul, Sub};
+use std::time::Duration;
+
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub const fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    pub fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    pub fn norm(self) -> f32 {
+        (self.re * self.re + self.im * self.im).sqrt()
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+/// In-place iterative radix-2 FFT; `data.len()` must be a power of two.
+pub fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    assert!(n.is_power_of_two(), "FFT size {} is not a power of two", n);
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        let step = Complex::new(angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let mut twiddle = Complex::new(1.0, 0.0);
+            for k in 0..len / 2 {
+                let even = data[start + k];
+                let odd = data[start + k + len / 2] * twiddle;
+                data[start + k] = even + odd;
+                data[start + k + len / 2] = even - odd;
+                twiddle = twiddle * step;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        for value in data.iter_mut() {
+            *value = value.scale(1.0 / n as f32);
+        }
+    }
+}
+
+/// Spectrum of a real signal, zero padded to `size` samples.
+pub fn spectrum(signal: &[f32], size: usize) -> Vec<Complex> {
+    let mut data = vec![Complex::default(); size];
+    for (value, sample) in data.iter_mut().zip(signal.iter()) {
+        value.re = *sample;
+    }
+    fft(&mut data, false);
+    data
+}
+
+/// Position of the peak of `values` refined by a parabola through its neighbours.
+pub fn interpolate_peak(values: &[f32], peak: usize) -> f32 {
+    if peak == 0 || peak + 1 >= values.len() {
+        return peak as f32;
+    }
+    let (left, center, right) = (values[peak - 1], values[peak], values[peak + 1]);
+    let denominator = left - 2.0 * center + right;
+    if denominator.abs() < f32::EPSILON {
+        return peak as f32;
+    }
+    peak as f32 + 0.5 * (left - right) / denominator
+}
+
+/// Circular cross-correlation of two spectra, each bin weighted by `weight(cross spectrum)`.
+fn cross_correlation<W: Fn(Complex) -> Complex>(
+    a: &[Complex],
+    b: &[Complex],
+    weight: W,
+) -> Vec<f32> {
+    let mut cross: Vec<Complex> = a
+        .iter()
+        .zip(b.iter())
+        .map(|(a, b)| weight(*a * b.conj()))
+        .collect();
+    fft(&mut cross, true);
+    cross.iter().map(|value| value.re).collect()
+}
+
+/// Delay of `a` relative to `b` in (fractional) samples, positive when `a` hears the sound later,
+/// estimated with the generalized cross-correlation with phase transform (GCC-PHAT).
+/// Only lags up to `max_lag` samples are considered, i.e. what the array baseline allows.
+pub fn gcc_phat(a: &[f32], b: &[f32], max_lag: usize) -> Option<f32> {
+    let size = (a.len().max(b.len()) + max_lag).next_power_of_two() * 2;
+    let correlation = cross_correlation(&spectrum(a, size), &spectrum(b, size), |cross| {
+        let norm = cross.norm();
+        if norm > f32::EPSILON {
+            cross.scale(1.0 / norm)
+        } else {
+            Complex::default()
+        }
+    });
+    lag_of_peak(&correlation, max_lag)
+}
+
+/// Picks the highest of the lags -max_lag..=max_lag of a circular correlation.
+pub fn lag_of_peak(correlation: &[f32], max_lag: usize) -> Option<f32> {
+    let size = correlation.len();
+    let max_lag = max_lag.min(size / 2 - 1);
+    // unwrap the circular lags around zero
+    let window: Vec<f32> = (0..=2 * max_lag)
+        .map(|k| correlation[(size + k - max_lag) % size])
+        .collect();
+    let (peak, value) = window
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+    if *value <= 0.0 {
+        return None;
+    }
+    Some(interpolate_peak(&window, peak) - max_lag as f32)
+}
+
+/// Fixed size buffer keeping the latest samples of a channel.
+pub struct RingBuffer {
+    samples: Vec<f32>,
+    next: usize,
+    filled: bool,
+}
+
+impl RingBuffer {
+    pub fn new(size: usize) -> Self {
+        Self {
+            samples: vec![0.0; size],
+            next: 0,
+            filled: false,
+        }
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        self.samples[self.next] = sample;
+        self.next += 1;
+        if self.next == self.samples.len() {
+            self.next = 0;
+            self.filled = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        if self.filled {
+            self.samples.len()
+        } else {
+            self.next
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The latest `count` samples, oldest first.
+    pub fn latest(&self, count: usize) -> Vec<f32> {
+        let count = count.min(self.len());
+        let size = self.samples.len();
+        (0..count)
+            .map(|k| self.samples[(self.next + size - count + k) % size])
+            .collect()
+    }
+}
+
+/// Mean square of the samples, after removing their DC offset.
+pub fn energy(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+    samples.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / samples.len() as f32
+}
+
+/// Envelope of the cross-correlation of `signal` with `template` starting at each sample of `signal`.
+/// Correlates with the analytic template, so the carrier phase of the received sound does not matter.
+pub fn matched_filter(signal: &[f32], template: &[f32]) -> Vec<f32> {
+    let size = (signal.len() + template.len()).next_power_of_two();
+    let mut cross: Vec<Complex> = spectrum(signal, size)
+        .iter()
+        .zip(spectrum(template, size).iter())
+        .enumerate()
+        .map(|(k, (s, t))| {
+            let value = *s * t.conj();
+            // only the positive frequencies make up the analytic signal
+            if k == 0 || k == size / 2 {
+                value
+            } else if k < size / 2 {
+                value.scale(2.0)
+            } else {
+                Complex::default()
+            }
+        })
+        .collect();
+    fft(&mut cross, true);
+    cross
+        .iter()
+        .take(signal.len())
+        .map(|value| value.norm())
+        .collect()
+}
+
+/// Where `template` starts in `signal`, in (fractional) samples, found with a matched filter.
+///
+/// The earliest peak reaching `min_peak_share` of the highest one wins, so a louder echo
+/// does not hide the direct sound. None unless the normalized correlation at the highest
+/// peak is at least `min_score` (from 0 to 1).
+pub fn detect(
+    signal: &[f32],
+    template: &[f32],
+    min_score: f32,
+    min_peak_share: f32,
+) -> Option<f32> {
+    if signal.is_empty() || template.is_empty() {
+        return None;
+    }
+    let envelope = matched_filter(signal, template);
+
+    let template_energy: f32 = template.iter().map(|x| x * x).sum();
+    let mut cumulative = vec![0.0f32; signal.len() + 1];
+    for (k, sample) in signal.iter().enumerate() {
+        cumulative[k + 1] = cumulative[k] + sample * sample;
+    }
+    // energy of the signal under the template starting at `start`
+    let window_energy =
+        |start: usize| cumulative[(start + template.len()).min(signal.len())] - cumulative[start];
+
+    let (best, peak) = envelope
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+    let norm = (template_energy * window_energy(best)).sqrt();
+    if norm <= f32::EPSILON || peak / norm < min_score {
+        return None;
+    }
+
+    let threshold = peak * min_peak_share;
+    let first = (0..envelope.len()).find(|&k| {
+        envelope[k] >= threshold
+            && (k == 0 || envelope[k - 1] <= envelope[k])
+            && (k + 1 == envelope.len() || envelope[k + 1] <= envelope[k])
+    })?;
+    Some(interpolate_peak(&envelope, first))
+}
+
+/// Seconds from the first sample of channel 0 to `position` (in samples) on a channel sampled
+/// `skew` after channel 0, as an ADC scanning the channels in turn samples them.
+pub fn deskew(position: f32, rate: f64, skew: Duration) -> f64 {
+    position as f64 / rate + skew.as_secs_f64()
+}
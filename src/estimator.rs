@@ -0,0 +1,147 @@
+#![allow(dead_code)]
+
+// Predicts the path difference between the measurements of the tracker, from the last measurement,
+// how old it is and what the car did since
+
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct EstimatorConfig {
+    // from the tracker sending the control data to it arriving on the car
+    pub latency: Duration,
+    // no prediction further than this from the last measurement
+    pub horizon: Duration,
+    // how much each pair of measurements moves the learnt effect of the output, from 0 to 1
+    pub learning: f64,
+    // the output (integrated over time) between two measurements must reach this to learn from them,
+    // in fractions of the max duty times seconds
+    pub min_excitation: f64,
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(10),
+            horizon: Duration::from_millis(1000),
+            learning: 0.3,
+            min_excitation: 0.02,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+struct Measured {
+    sequence: u32,
+    // when the beep was
+    at: Instant,
+    path_difference: f64,
+    rate: f64,
+}
+
+pub struct Estimator {
+    config: EstimatorConfig,
+    last: Option<Measured>,
+    // path difference change per second at full output, once learnt
+    sensitivity: Option<f64>,
+    // the outputs commanded, each holding until the next
+    commands: Vec<(Instant, f64)>,
+}
+
+impl Estimator {
+    pub fn new(config: EstimatorConfig) -> Self {
+        Self {
+            config,
+            last: None,
+            sensitivity: None,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn sensitivity(&self) -> Option<f64> {
+        self.sensitivity
+    }
+
+    /// The output commanded from `now` on.
+    pub fn command(&mut self, now: Instant, output: f64) {
+        self.commands.push((now, output));
+        // keep what the integral from the last measurement needs, but nothing beyond the horizon
+        let keep_from = now
+            .checked_sub(self.config.horizon)
+            .max(self.last.map(|last| last.at));
+        if let Some(keep_from) = keep_from {
+            let first = self
+                .commands
+                .iter()
+                .rposition(|(at, _)| *at <= keep_from)
+                .unwrap_or(0);
+            self.commands.drain(..first);
+        }
+    }
+
+    /// Takes measurement `sequence`, heard `age` before the tracker sent it and received at
+    /// `received`, with how fast it changes according to the tracker; one taken before is ignored.
+    pub fn measure(
+        &mut self,
+        sequence: u32,
+        path_difference: f64,
+        rate: f64,
+        age: Duration,
+        received: Instant,
+    ) {
+        if matches!(self.last, Some(last) if last.sequence == sequence) {
+            return;
+        }
+        let at = received
+            .checked_sub(age + self.config.latency)
+            .unwrap_or(received);
+        let measured = Measured {
+            sequence,
+            at,
+            path_difference,
+            rate,
+        };
+        if let Some(last) = self.last {
+            // the outputs from longer than the horizon ago are gone
+            let since = received.saturating_duration_since(last.at);
+            let excitation = self.integral(last.at, at);
+            if since <= self.config.horizon && excitation.abs() >= self.config.min_excitation {
+                let observed = (path_difference - last.path_difference) / excitation;
+                self.sensitivity = Some(match self.sensitivity {
+                    Some(sensitivity) => {
+                        sensitivity + self.config.learning * (observed - sensitivity)
+                    }
+                    None => observed,
+                });
+            }
+        }
+        self.last = Some(measured);
+    }
+
+    /// The path difference at `now`; None before the first measurement or beyond the horizon.
+    pub fn predict(&self, now: Instant) -> Option<f64> {
+        let last = self.last?;
+        let age = now.saturating_duration_since(last.at);
+        if age > self.config.horizon {
+            return None;
+        }
+        Some(match self.sensitivity {
+            Some(sensitivity) => last.path_difference + sensitivity * self.integral(last.at, now),
+            // nothing learnt yet, carry on as the tracker saw it change
+            None => last.path_difference + last.rate * age.as_secs_f64(),
+        })
+    }
+
+    // of the commanded output over time, zero before the first command
+    fn integral(&self, from: Instant, to: Instant) -> f64 {
+        let mut sum = 0.0;
+        for (i, (start, output)) in self.commands.iter().enumerate() {
+            let end = self.commands.get(i + 1).map_or(to, |(next, _)| *next);
+            let start = (*start).max(from);
+            let end = end.min(to);
+            if end > start {
+                sum += output * (end - start).as_secs_f64();
+            }
+        }
+        sum
+    }
+}
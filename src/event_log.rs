@@ -0,0 +1,364 @@
+#![allow(dead_code)]
+
+// Log of the raw receiver edges and the measurements of the tracker, to replay field runs on the host.
+//
+// Every record is its kind (u8), the length of its payload (u16) and the payload, all little endian.
+
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use crate::calibration::Calibration;
+use crate::detection::{self, Detector, DetectorConfig, Measurement};
+use crate::geometry::{ArrayGeometry, Point};
+use crate::timebase::{Micros, Timebase};
+
+pub const LOG_VERSION: u8 = 1;
+
+const KIND_HEADER: u8 = 0;
+const KIND_EDGE: u8 = 1;
+const KIND_MEASUREMENT: u8 = 2;
+
+/// What the replay needs to know about the tracker which wrote the log.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Header {
+    pub microphones: Vec<Point>,
+    pub speed_of_sound: f32,
+    // calibrated delay of each channel, in nanoseconds
+    pub delays: Vec<i64>,
+    pub detector: DetectorConfig,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Record {
+    Header(Header),
+    // receiver `channel` went to `level` (true = hears a sound)
+    Edge {
+        time: Micros,
+        channel: u8,
+        level: bool,
+    },
+    // computed from the burst whose earliest arrival was at `time`
+    Measurement {
+        time: Micros,
+        measurement: Measurement,
+    },
+}
+
+fn micros(duration: Duration) -> u32 {
+    duration.as_micros() as u32
+}
+
+// reads the payload front to back
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.bytes.len() < N {
+            bail!("Record ends early");
+        }
+        let (head, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(head.try_into()?)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    fn duration(&mut self) -> Result<Duration> {
+        Ok(Duration::from_micros(self.u32()? as u64))
+    }
+}
+
+impl Record {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let kind = match self {
+            Record::Header(header) => {
+                payload.push(LOG_VERSION);
+                payload.push(header.microphones.len() as u8);
+                for microphone in &header.microphones {
+                    payload.extend_from_slice(&microphone.x.to_le_bytes());
+                    payload.extend_from_slice(&microphone.y.to_le_bytes());
+                }
+                payload.extend_from_slice(&header.speed_of_sound.to_le_bytes());
+                for delay in &header.delays {
+                    payload.extend_from_slice(&delay.to_le_bytes());
+                }
+                let detector = &header.detector;
+                payload.extend_from_slice(&micros(detector.valid_time).to_le_bytes());
+                payload.extend_from_slice(&micros(detector.sound_range_time).to_le_bytes());
+                payload.extend_from_slice(&micros(detector.blanking_time).to_le_bytes());
+                payload.push(detector.check_physical_delays as u8);
+                payload.extend_from_slice(&micros(detector.physical_delay_margin).to_le_bytes());
+                KIND_HEADER
+            }
+            Record::Edge {
+                time,
+                channel,
+                level,
+            } => {
+                payload.extend_from_slice(&time.to_le_bytes());
+                payload.push(*channel);
+                payload.push(*level as u8);
+                KIND_EDGE
+            }
+            Record::Measurement { time, measurement } => {
+                payload.extend_from_slice(&time.to_le_bytes());
+                payload.extend_from_slice(&(measurement.offset as i64).to_le_bytes());
+                payload.extend_from_slice(&measurement.path_difference.to_le_bytes());
+                payload.extend_from_slice(&measurement.bearing.to_le_bytes());
+                payload.extend_from_slice(&measurement.confidence.to_le_bytes());
+                KIND_MEASUREMENT
+            }
+        };
+        let mut bytes = vec![kind];
+        bytes.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    fn from_payload(kind: u8, payload: &[u8]) -> Result<Option<Self>> {
+        let mut reader = Reader { bytes: payload };
+        let record = match kind {
+            KIND_HEADER => {
+                let version = reader.u8()?;
+                if version != LOG_VERSION {
+                    bail!(
+                        "Log version {} is not supported, expected {}",
+                        version,
+                        LOG_VERSION
+                    );
+                }
+                let receivers = reader.u8()? as usize;
+                let microphones = (0..receivers)
+                    .map(|_| Ok(Point::new(reader.f32()?, reader.f32()?)))
+                    .collect::<Result<Vec<_>>>()?;
+                let speed_of_sound = reader.f32()?;
+                let delays = (0..receivers)
+                    .map(|_| reader.i64())
+                    .collect::<Result<Vec<_>>>()?;
+                let detector = DetectorConfig {
+                    valid_time: reader.duration()?,
+                    sound_range_time: reader.duration()?,
+                    blanking_time: reader.duration()?,
+                    check_physical_delays: reader.u8()? != 0,
+                    physical_delay_margin: reader.duration()?,
+                };
+                Record::Header(Header {
+                    microphones,
+                    speed_of_sound,
+                    delays,
+                    detector,
+                })
+            }
+            KIND_EDGE => Record::Edge {
+                time: reader.u64()?,
+                channel: reader.u8()?,
+                level: reader.u8()? != 0,
+            },
+            KIND_MEASUREMENT => Record::Measurement {
+                time: reader.u64()?,
+                measurement: Measurement {
+                    offset: reader.i64()? as i128,
+                    path_difference: reader.f32()?,
+                    bearing: reader.f32()?,
+                    confidence: reader.f32()?,
+                },
+            },
+            // written by a newer tracker
+            _ => return Ok(None),
+        };
+        Ok(Some(record))
+    }
+}
+
+/// All complete records of a log; a record cut off at the end (the tracker was reset) is dropped.
+pub fn parse(mut bytes: &[u8]) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    while bytes.len() >= 3 {
+        let kind = bytes[0];
+        let len = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+        if bytes.len() < 3 + len {
+            break;
+        }
+        if let Some(record) = Record::from_payload(kind, &bytes[3..3 + len])? {
+            records.push(record);
+        }
+        bytes = &bytes[3 + len..];
+    }
+    Ok(records)
+}
+
+/// Hands the records to the thread serving them, without ever blocking the detection.
+pub struct Recorder {
+    sender: Option<SyncSender<Vec<u8>>>,
+    timebase: Timebase,
+    // earliest arrival of the last measurement, the tracker reports each burst repeatedly
+    last_measurement: Cell<Option<Micros>>,
+}
+
+impl Recorder {
+    pub fn new(sender: SyncSender<Vec<u8>>) -> Self {
+        Self {
+            sender: Some(sender),
+            timebase: Timebase::new(),
+            last_measurement: Cell::new(None),
+        }
+    }
+
+    pub fn off() -> Self {
+        Self {
+            sender: None,
+            timebase: Timebase::new(),
+            last_measurement: Cell::new(None),
+        }
+    }
+
+    fn send(&self, record: Record) {
+        if let Some(sender) = &self.sender {
+            // a full queue drops the record rather than stalling the receivers
+            let _ = sender.try_send(record.to_bytes());
+        }
+    }
+
+    pub fn edge(&self, channel: usize, level: bool, at: Instant) {
+        if self.sender.is_none() {
+            return;
+        }
+        self.send(Record::Edge {
+            time: self.timebase.at(at),
+            channel: channel as u8,
+            level,
+        });
+    }
+
+    pub fn measurement(&self, earliest: Instant, measurement: &Measurement) {
+        if self.sender.is_none() {
+            return;
+        }
+        let time = self.timebase.at(earliest);
+        if self.last_measurement.replace(Some(time)) == Some(time) {
+            return;
+        }
+        self.send(Record::Measurement {
+            time,
+            measurement: *measurement,
+        });
+    }
+}
+
+/// The measurements recorded in a log, in the order of their bursts.
+pub fn recorded(records: &[Record]) -> Vec<(Micros, Measurement)> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            Record::Measurement { time, measurement } => Some((*time, *measurement)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Feeds the logged edges through the detection of the tracker, polling the levels every
+/// `poll`, and computes a measurement for each burst like the tracker does.
+pub fn replay(records: &[Record], poll: Duration) -> Result<Vec<(Micros, Measurement)>> {
+    let header = match records.first() {
+        Some(Record::Header(header)) => header.clone(),
+        _ => bail!("The log does not start with a header"),
+    };
+    let receivers = header.microphones.len();
+    let geometry = ArrayGeometry {
+        // the geometry of the tracker is static, a log is replayed once per run of the tool
+        microphones: Box::leak(header.microphones.clone().into_boxed_slice()),
+        temperature_c: 20.0,
+        speed_of_sound: Some(header.speed_of_sound),
+    };
+    let calibration = Calibration {
+        delays: header.delays.clone(),
+    };
+
+    let timebase = Timebase::new();
+    let mut detector = Detector::new(header.detector, receivers, timebase.instant(0));
+    let mut measurements: Vec<(Micros, Measurement)> = Vec::new();
+    let step = poll.as_micros().max(1) as Micros;
+    let mut now: Option<Micros> = None;
+
+    let mut poll_until = |detector: &Detector, from: Micros, until: Micros| {
+        let mut time = from;
+        while time < until {
+            if let Some(data) = detector.burst(&geometry, timebase.instant(time)) {
+                let earliest = timebase.at(data.earliest());
+                if measurements.last().map(|(last, _)| *last) != Some(earliest) {
+                    let measurement = detection::calculate(
+                        &geometry,
+                        &data,
+                        &calibration,
+                        header.detector.sound_range_time,
+                    );
+                    measurements.push((earliest, measurement));
+                }
+            }
+            time += step;
+        }
+    };
+
+    for record in &records[1..] {
+        if let Record::Edge {
+            time,
+            channel,
+            level,
+        } = record
+        {
+            if *channel as usize >= receivers {
+                bail!(
+                    "Edge of receiver {} but only {} receivers",
+                    channel,
+                    receivers
+                );
+            }
+            if let Some(now) = now {
+                poll_until(&detector, now, *time);
+            }
+            detector.sample(*channel as usize, *level, timebase.instant(*time));
+            now = Some(*time);
+        }
+    }
+    // the last burst holds until the log ends
+    if let Some(now) = now {
+        let tail = header.detector.valid_time + header.detector.blanking_time;
+        poll_until(&detector, now, now + tail.as_micros() as Micros);
+    }
+
+    Ok(measurements)
+}
+
+/// Whether a replayed measurement matches the recorded one, up to float rounding between platforms.
+pub fn same(recorded: &Measurement, replayed: &Measurement) -> bool {
+    let close = |a: f32, b: f32| (a - b).abs() <= 1e-4 * a.abs().max(b.abs()).max(1.0);
+    recorded.offset == replayed.offset
+        && close(recorded.path_difference, replayed.path_difference)
+        && close(recorded.bearing, replayed.bearing)
+        && close(recorded.confidence, replayed.confidence)
+}
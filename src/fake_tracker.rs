@@ -0,0 +1,183 @@
+// Host emulator of the tracker: serves scripted path differences on the control data port,
+// so the car can be tested without microphones (see `src/offset_script.rs` for the script format).
+// Run with `cargo run --bin fake_tracker --target x86_64-unknown-linux-gnu -- <script> [options]`.
+
+mod geometry;
+mod offset_script;
+mod protocol;
+mod rng;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use offset_script::Script;
+use protocol::ControlData;
+use rng::Rng;
+
+const USAGE: &str =
+    "Usage: fake_tracker <script> [--address <host:port>] [--period-ms <ms>] [--seed <n>] [--repeat]";
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+// how often the tracker has a new measurement, one per beep of the car
+const DEFAULT_PERIOD: Duration = Duration::from_millis(400);
+
+// the two receivers of the smallest tracker
+const GEOMETRY: geometry::ArrayGeometry = geometry::ArrayGeometry {
+    microphones: &[
+        geometry::Point::new(-0.25, 0.0),
+        geometry::Point::new(0.25, 0.0),
+    ],
+    temperature_c: 20.0,
+    speed_of_sound: None,
+};
+
+fn control_data(path_difference: f32, rate: f32, confidence: f32, sequence: u32) -> ControlData {
+    let offset = (path_difference / GEOMETRY.speed_of_sound() * 1e9) as i128;
+    ControlData {
+        offset,
+        path_difference,
+        filtered: path_difference,
+        rate,
+        bearing: GEOMETRY.bearing(0, 1, path_difference),
+        confidence,
+        sequence,
+        ..ControlData::empty()
+    }
+}
+
+// the car connects once and reads forever; while the link is down, connections are closed right away
+fn accept(listener: TcpListener, clients: Arc<Mutex<Vec<TcpStream>>>, link_down: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if link_down.load(Ordering::SeqCst) {
+                    eprintln!("Link down, dropping {:?}", stream.peer_addr());
+                    continue;
+                }
+                eprintln!("Car connected from {:?}", stream.peer_addr());
+                clients.lock().unwrap().push(stream);
+            }
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let mut script_path = None;
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut period = DEFAULT_PERIOD;
+    let mut seed = 1;
+    let mut repeat = false;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" => address = args.next().unwrap_or_default(),
+            "--period-ms" => {
+                period = Duration::from_millis(args.next().unwrap_or_default().parse()?)
+            }
+            "--seed" => seed = args.next().unwrap_or_default().parse()?,
+            "--repeat" => repeat = true,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if script_path.is_none() && !arg.starts_with("--") => script_path = Some(arg),
+            _ => bail!(USAGE),
+        }
+    }
+    let script_path = match script_path {
+        Some(path) => path,
+        None => bail!(USAGE),
+    };
+    let script = Script::parse(&fs::read_to_string(&script_path)?)?;
+
+    let listener = TcpListener::bind(&address)?;
+    let clients = Arc::new(Mutex::new(Vec::new()));
+    let link_down = Arc::new(AtomicBool::new(false));
+    {
+        let clients = clients.clone();
+        let link_down = link_down.clone();
+        thread::spawn(move || accept(listener, clients, link_down));
+    }
+    eprintln!(
+        "Serving {} ({:?}) on {}, waiting for the car",
+        script_path,
+        script.duration(),
+        address
+    );
+
+    // the script starts when the car connects, so that runs are repeatable
+    while clients.lock().unwrap().is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    println!("time_s,path_difference_m,confidence,clients");
+    let mut rng = Rng::new(seed);
+    let mut start = Instant::now();
+    let mut previous: Option<(Duration, f32)> = None;
+    let mut sequence = 0;
+    loop {
+        let mut time = start.elapsed();
+        if time >= script.duration() {
+            if !repeat {
+                break;
+            }
+            start += script.duration();
+            time = start.elapsed();
+        }
+
+        match script.sample(time, &mut rng) {
+            Some(sample) => {
+                link_down.store(false, Ordering::SeqCst);
+                let rate = match previous {
+                    Some((at, value)) if time > at => {
+                        (sample.path_difference - value) / (time - at).as_secs_f32()
+                    }
+                    _ => 0.0,
+                };
+                previous = Some((time, sample.path_difference));
+                sequence += 1;
+                // heard just now, on the time base of the script
+                let data = ControlData {
+                    time: time.as_micros() as u64,
+                    ..control_data(sample.path_difference, rate, sample.confidence, sequence)
+                };
+                let mut clients = clients.lock().unwrap();
+                clients.retain(|stream| {
+                    let mut stream: &TcpStream = stream;
+                    stream.write_all(data.to_slice()).is_ok()
+                });
+                println!(
+                    "{:.3},{:.4},{:.2},{}",
+                    time.as_secs_f32(),
+                    sample.path_difference,
+                    sample.confidence,
+                    clients.len()
+                );
+            }
+            None => {
+                if !link_down.swap(true, Ordering::SeqCst) {
+                    // like the tracker going out of range: the car sees the connection drop
+                    clients.lock().unwrap().clear();
+                    previous = None;
+                    println!("{:.3},,,link down", time.as_secs_f32());
+                }
+            }
+        }
+
+        thread::sleep(period);
+    }
+
+    eprintln!("Script done");
+    Ok(())
+}
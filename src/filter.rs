@@ -0,0 +1,194 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+#[derive(Debug, Copy, Clone)]
+pub struct FilterConfig {
+    // measurements the outlier rejection looks back at
+    pub window: usize,
+    // a measurement further than this many (scaled) median absolute deviations from the median is an outlier
+    pub threshold: f32,
+    // deviations below this are never outliers, in the unit of the measurements
+    pub min_deviation: f32,
+    // alpha-beta gains of the value and of its rate of change
+    pub alpha: f32,
+    pub beta: f32,
+    // the estimate restarts from scratch after a gap this long, in seconds
+    pub max_gap: f64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            window: 7,
+            threshold: 3.0,
+            min_deviation: 0.02,
+            alpha: 0.5,
+            beta: 0.1,
+            max_gap: 1.0,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Estimate {
+    pub value: f32,
+    // per second
+    pub rate: f32,
+    // the measurement was rejected and the estimate only predicted
+    pub outlier: bool,
+}
+
+/// Hampel outlier rejection: compares each measurement with the median of the last ones.
+pub struct Hampel {
+    window: VecDeque<f32>,
+    size: usize,
+    threshold: f32,
+    min_deviation: f32,
+}
+
+// scales the median absolute deviation to the standard deviation of normally distributed data
+const MAD_SCALE: f32 = 1.4826;
+
+fn median(values: &mut [f32]) -> f32 {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let middle = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+impl Hampel {
+    pub fn new(config: &FilterConfig) -> Self {
+        Self {
+            window: VecDeque::with_capacity(config.window),
+            size: config.window,
+            threshold: config.threshold,
+            min_deviation: config.min_deviation,
+        }
+    }
+
+    /// Returns false if `value` is an outlier; outliers are still remembered,
+    /// so that a real jump is accepted once it persists for half of the window.
+    pub fn accept(&mut self, value: f32) -> bool {
+        let accepted = if self.window.len() < self.size {
+            true
+        } else {
+            let mut values: Vec<f32> = self.window.iter().copied().collect();
+            let center = median(&mut values);
+            let mut deviations: Vec<f32> = values.iter().map(|x| (x - center).abs()).collect();
+            let deviation = (MAD_SCALE * median(&mut deviations)).max(self.min_deviation);
+            (value - center).abs() <= self.threshold * deviation
+        };
+        if self.window.len() == self.size {
+            self.window.pop_front();
+        }
+        self.window.push_back(value);
+        accepted
+    }
+
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// Alpha-beta tracker of a value and its rate of change.
+pub struct AlphaBeta {
+    alpha: f32,
+    beta: f32,
+    // (time, value, rate)
+    state: Option<(f64, f32, f32)>,
+}
+
+impl AlphaBeta {
+    pub fn new(config: &FilterConfig) -> Self {
+        Self {
+            alpha: config.alpha,
+            beta: config.beta,
+            state: None,
+        }
+    }
+
+    pub fn predict(&self, time: f64) -> Option<(f32, f32)> {
+        self.state
+            .map(|(last, value, rate)| (value + rate * (time - last) as f32, rate))
+    }
+
+    pub fn update(&mut self, time: f64, measurement: f32) -> (f32, f32) {
+        let (value, rate) = match self.state {
+            Some((last, value, rate)) if time > last => {
+                let dt = (time - last) as f32;
+                let predicted = value + rate * dt;
+                let residual = measurement - predicted;
+                (
+                    predicted + self.alpha * residual,
+                    rate + self.beta / dt * residual,
+                )
+            }
+            _ => (measurement, 0.0),
+        };
+        self.state = Some((time, value, rate));
+        (value, rate)
+    }
+
+    /// Moves the estimate forward to `time` without a measurement.
+    pub fn coast(&mut self, time: f64) -> Option<(f32, f32)> {
+        let predicted = self.predict(time)?;
+        self.state = Some((time, predicted.0, predicted.1));
+        Some(predicted)
+    }
+
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Outlier rejection followed by an alpha-beta tracker.
+pub struct MeasurementFilter {
+    config: FilterConfig,
+    hampel: Hampel,
+    tracker: AlphaBeta,
+    last: Option<(f64, Estimate)>,
+}
+
+impl MeasurementFilter {
+    pub fn new(config: FilterConfig) -> Self {
+        Self {
+            hampel: Hampel::new(&config),
+            tracker: AlphaBeta::new(&config),
+            config,
+            last: None,
+        }
+    }
+
+    /// `time` in seconds identifies the measurement; the same measurement is only filtered once.
+    pub fn update(&mut self, time: f64, measurement: f32) -> Estimate {
+        match self.last {
+            Some((last, estimate)) if last == time => return estimate,
+            Some((last, _)) if time - last > self.config.max_gap => {
+                self.hampel.reset();
+                self.tracker.reset();
+            }
+            _ => {}
+        }
+        let estimate = if self.hampel.accept(measurement) {
+            let (value, rate) = self.tracker.update(time, measurement);
+            Estimate {
+                value,
+                rate,
+                outlier: false,
+            }
+        } else {
+            let (value, rate) = self.tracker.coast(time).unwrap_or((measurement, 0.0));
+            Estimate {
+                value,
+                rate,
+                outlier: true,
+            }
+        };
+        self.last = Some((time, estimate));
+        estimate
+    }
+}
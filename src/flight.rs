@@ -0,0 +1,28 @@
+// Decodes a flight recorder dump of the car into CSV.
+// Run with `cargo run --bin flight --target x86_64-unknown-linux-gnu -- <dump>`.
+
+mod flight_recorder;
+mod timebase;
+
+use std::env;
+use std::fs;
+
+use anyhow::{bail, Result};
+
+use flight_recorder::Tick;
+
+fn main() -> Result<()> {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => bail!("Usage: flight <dump>"),
+    };
+    let ticks = flight_recorder::decode(&fs::read(&path)?)?;
+
+    println!("{}", Tick::CSV_HEADER);
+    for tick in &ticks {
+        println!("{}", tick.to_csv());
+    }
+    eprintln!("{} ticks", ticks.len());
+
+    Ok(())
+}
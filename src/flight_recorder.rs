@@ -0,0 +1,206 @@
+#![allow(dead_code)]
+
+// What the car did on each control tick, kept in a fixed size ring buffer and dumped after a run.
+//
+// The binary dump is the magic "FLT", the version (u8), the tick count (u32) and the ticks, all little endian.
+
+use std::convert::TryInto;
+
+use anyhow::{bail, Result};
+
+use crate::timebase::Micros;
+
+const MAGIC: &[u8; 3] = b"FLT";
+pub const VERSION: u8 = 6;
+
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Tick {
+    // since the car started
+    pub time: Micros,
+    // as received from the tracker, in nanoseconds
+    pub offset: i64,
+    // the filtered path difference the PID works on, in metres
+    pub filtered: f32,
+    // what the PID worked on, predicted from `filtered` for the time of the tick; NaN when stopped
+    pub predicted: f32,
+    // how long ago the control data was received, in milliseconds
+    pub age_ms: u32,
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+    // fraction of the max duty
+    pub output: f32,
+    // taken from the left engine and given to the right one
+    pub turn: f32,
+    // in radians, counterclockwise; NaN without a heading sensor
+    pub heading: f32,
+    // to the nearest obstacle ahead, in metres; NaN when nothing is in range or without a range sensor
+    pub obstacle: f32,
+    // filtered, in volts; NaN without battery monitoring
+    pub battery: f32,
+    pub duty1: i32,
+    pub duty2: i32,
+    // how many goals of the mission are reached
+    pub goal: u32,
+}
+
+impl Tick {
+    pub const SIZE: usize = 72;
+
+    pub const CSV_HEADER: &'static str =
+        "time_us,offset_ns,filtered_m,predicted_m,age_ms,p,i,d,output,turn,heading_rad,obstacle_m,battery_v,duty1,duty2,goal";
+
+    pub fn to_csv(self) -> String {
+        format!(
+            "{},{},{:.4},{:.4},{},{:.4},{:.4},{:.4},{:.4},{:.4},{:.4},{:.3},{:.2},{},{},{}",
+            self.time,
+            self.offset,
+            self.filtered,
+            self.predicted,
+            self.age_ms,
+            self.p,
+            self.i,
+            self.d,
+            self.output,
+            self.turn,
+            self.heading,
+            self.obstacle,
+            self.battery,
+            self.duty1,
+            self.duty2,
+            self.goal
+        )
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.time.to_le_bytes());
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.filtered.to_le_bytes());
+        bytes.extend_from_slice(&self.predicted.to_le_bytes());
+        bytes.extend_from_slice(&self.age_ms.to_le_bytes());
+        for value in &[
+            self.p,
+            self.i,
+            self.d,
+            self.output,
+            self.turn,
+            self.heading,
+            self.obstacle,
+            self.battery,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.duty1.to_le_bytes());
+        bytes.extend_from_slice(&self.duty2.to_le_bytes());
+        bytes.extend_from_slice(&self.goal.to_le_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let f32_at = |at: usize| f32::from_bits(u32_at(at));
+        Self {
+            time: u64_at(0),
+            offset: u64_at(8) as i64,
+            filtered: f32_at(16),
+            predicted: f32_at(20),
+            age_ms: u32_at(24),
+            p: f32_at(28),
+            i: f32_at(32),
+            d: f32_at(36),
+            output: f32_at(40),
+            turn: f32_at(44),
+            heading: f32_at(48),
+            obstacle: f32_at(52),
+            battery: f32_at(56),
+            duty1: u32_at(60) as i32,
+            duty2: u32_at(64) as i32,
+            goal: u32_at(68),
+        }
+    }
+}
+
+/// Keeps the latest `capacity` ticks, the oldest are overwritten.
+pub struct FlightRecorder {
+    ticks: Vec<Tick>,
+    capacity: usize,
+    next: usize,
+}
+
+impl FlightRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ticks: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, tick: Tick) {
+        if self.ticks.len() < self.capacity {
+            self.ticks.push(tick);
+        } else {
+            self.ticks[self.next] = tick;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ticks.is_empty()
+    }
+
+    /// Oldest first.
+    pub fn ticks(&self) -> Vec<Tick> {
+        if self.ticks.len() < self.capacity {
+            return self.ticks.clone();
+        }
+        let (newer, older) = self.ticks.split_at(self.next);
+        older.iter().chain(newer.iter()).copied().collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let ticks = self.ticks();
+        let mut bytes = Vec::with_capacity(8 + ticks.len() * Tick::SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&(ticks.len() as u32).to_le_bytes());
+        for tick in &ticks {
+            tick.write(&mut bytes);
+        }
+        bytes
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(Tick::CSV_HEADER);
+        csv.push('\n');
+        for tick in self.ticks() {
+            csv.push_str(&tick.to_csv());
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Decodes a binary dump; ticks cut off at the end (the connection dropped) are left out.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Tick>> {
+    if bytes.len() < 8 || &bytes[..3] != MAGIC {
+        bail!("Not a flight recorder dump");
+    }
+    if bytes[3] != VERSION {
+        bail!(
+            "Flight recorder version {} is not supported, expected {}",
+            bytes[3],
+            VERSION
+        );
+    }
+    let count = u32::from_le_bytes(bytes[4..8].try_into()?) as usize;
+    Ok(bytes[8..]
+        .chunks_exact(Tick::SIZE)
+        .take(count)
+        .map(Tick::read)
+        .collect())
+}
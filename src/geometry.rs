@@ -0,0 +1,120 @@
+#![allow(dead_code)]
+
+/// Speed of sound in dry air at 0 °C, in m/s.
+pub const SPEED_OF_SOUND_0C: f32 = 331.3;
+
+/// Speed of sound in dry air at the given temperature, in m/s.
+pub fn speed_of_sound(temperature_c: f32) -> f32 {
+    SPEED_OF_SOUND_0C * (1.0 + temperature_c / 273.15).sqrt()
+}
+
+/// A position in the tracker frame, in metres.
+/// x points from microphone a towards microphone b, y points away from the tracker.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Point {
+    pub const fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+
+    pub fn distance(&self, other: &Point) -> f32 {
+        ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+    }
+}
+
+/// Where the microphones are and how fast sound travels between them.
+#[derive(Debug, Copy, Clone)]
+pub struct ArrayGeometry {
+    // a and b first, their bisector is the line the car drives to
+    pub microphones: &'static [Point],
+    pub temperature_c: f32,
+    // overrides the temperature compensated value when set
+    pub speed_of_sound: Option<f32>,
+}
+
+impl ArrayGeometry {
+    pub fn speed_of_sound(&self) -> f32 {
+        self.speed_of_sound
+            .unwrap_or_else(|| speed_of_sound(self.temperature_c))
+    }
+
+    pub fn baseline(&self, i: usize, j: usize) -> f32 {
+        self.microphones[i].distance(&self.microphones[j])
+    }
+
+    /// Largest arrival time difference a real sound can produce between two microphones, in nanoseconds.
+    pub fn max_offset(&self, i: usize, j: usize) -> i128 {
+        (self.baseline(i, j) / self.speed_of_sound() * 1e9) as i128
+    }
+
+    /// Whether no pair of arrivals is further apart than sound needs to cross their baseline
+    /// (plus `margin_ns` for the jitter of the receivers).
+    pub fn is_physical(&self, arrivals_ns: &[i64], margin_ns: i64) -> bool {
+        (0..arrivals_ns.len()).all(|i| {
+            (i + 1..arrivals_ns.len()).all(|j| {
+                ((arrivals_ns[i] - arrivals_ns[j]).abs() as i128)
+                    <= self.max_offset(i, j) + margin_ns as i128
+            })
+        })
+    }
+
+    /// How much further the sound travelled to reach microphone i than microphone j, in metres.
+    pub fn path_difference(&self, offset_ns: i128) -> f32 {
+        offset_ns as f32 * 1e-9 * self.speed_of_sound()
+    }
+
+    /// Direction of a far away source, in radians from the bisector of microphones i and j,
+    /// positive towards microphone j.
+    pub fn bearing(&self, i: usize, j: usize, path_difference: f32) -> f32 {
+        let ratio = path_difference / self.baseline(i, j);
+        ratio.clamp(-1.0, 1.0).asin()
+    }
+
+    /// Path difference to microphones i and j of a sound emitted at `source`, in metres.
+    pub fn expected_path_difference(&self, i: usize, j: usize, source: &Point) -> f32 {
+        source.distance(&self.microphones[i]) - source.distance(&self.microphones[j])
+    }
+
+    /// Least squares direction of a far away source from the arrival times at every microphone,
+    /// in radians from the y axis, positive towards x; `None` if the microphones are collinear.
+    ///
+    /// A plane wave from direction `u` reaches microphone i at `t - (p_i . u) / c`,
+    /// so the centered positions and path lengths give an over-determined linear system for `u`.
+    pub fn direction(&self, arrivals_ns: &[i64]) -> Option<f32> {
+        let n = self.microphones.len().min(arrivals_ns.len());
+        if n < 3 {
+            return None;
+        }
+        let paths: Vec<f32> = arrivals_ns[..n]
+            .iter()
+            .map(|arrival| *arrival as f32 * 1e-9 * self.speed_of_sound())
+            .collect();
+        let mean_x = self.microphones[..n].iter().map(|p| p.x).sum::<f32>() / n as f32;
+        let mean_y = self.microphones[..n].iter().map(|p| p.y).sum::<f32>() / n as f32;
+        let mean_path = paths.iter().sum::<f32>() / n as f32;
+
+        // normal equations of (p_i - p) . u = -(d_i - d)
+        let (mut xx, mut xy, mut yy, mut xd, mut yd) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (microphone, path) in self.microphones[..n].iter().zip(paths.iter()) {
+            let x = microphone.x - mean_x;
+            let y = microphone.y - mean_y;
+            let d = -(path - mean_path);
+            xx += x * x;
+            xy += x * y;
+            yy += y * y;
+            xd += x * d;
+            yd += y * d;
+        }
+        let determinant = xx * yy - xy * xy;
+        if determinant.abs() < 1e-9 {
+            return None;
+        }
+        let ux = (yy * xd - xy * yd) / determinant;
+        let uy = (xx * yd - xy * xd) / determinant;
+        Some(ux.atan2(uy))
+    }
+}
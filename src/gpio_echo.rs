@@ -0,0 +1,107 @@
+// Times the echo pulse of a range sensor with an interrupt on both edges of its GPIO (ESP32 only)
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use esp_idf_sys::*;
+
+use crate::obstacle::EchoTimer;
+
+// how often the edges are looked at while waiting; a tick of FreeRTOS, shorter sleeps would spin
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+// no edge seen since the start
+const NONE: u32 = u32::MAX;
+
+// written by the interrupt, in microseconds since boot, wrapping around every 71 minutes
+// (the ESP32 has no 64 bit atomics)
+struct Edges {
+    pin: i32,
+    rise: AtomicU32,
+    fall: AtomicU32,
+}
+
+fn micros() -> u32 {
+    unsafe { esp_timer_get_time() as u32 }
+}
+
+unsafe extern "C" fn on_edge(arg: *mut c_types::c_void) {
+    let edges = &*(arg as *const Edges);
+    let now = micros();
+    if gpio_get_level(edges.pin) != 0 {
+        edges.rise.store(now, Ordering::SeqCst);
+    } else {
+        edges.fall.store(now, Ordering::SeqCst);
+    }
+}
+
+pub struct GpioEcho {
+    // boxed, so the interrupt keeps its address
+    edges: Box<Edges>,
+    start: u32,
+}
+
+impl GpioEcho {
+    pub fn new(pin: i32) -> Result<Self> {
+        let edges = Box::new(Edges {
+            pin,
+            rise: AtomicU32::new(NONE),
+            fall: AtomicU32::new(NONE),
+        });
+        let config = gpio_config_t {
+            pin_bit_mask: 1 << pin,
+            mode: gpio_mode_t_GPIO_MODE_INPUT,
+            pull_up_en: gpio_pullup_t_GPIO_PULLUP_DISABLE,
+            pull_down_en: gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+            intr_type: gpio_int_type_t_GPIO_INTR_ANYEDGE,
+        };
+        esp!(unsafe { gpio_config(&config) })?;
+        // shared by all GPIO interrupts, another driver may have installed it already
+        let installed = unsafe { gpio_install_isr_service(0) };
+        if installed != ESP_ERR_INVALID_STATE as esp_err_t {
+            esp!(installed)?;
+        }
+        esp!(unsafe {
+            gpio_isr_handler_add(pin, Some(on_edge), &*edges as *const Edges as *mut _)
+        })?;
+        Ok(Self { edges, start: 0 })
+    }
+
+    fn since_start(&self, time: u32) -> Option<Duration> {
+        if time == NONE {
+            None
+        } else {
+            Some(Duration::from_micros(time.wrapping_sub(self.start) as u64))
+        }
+    }
+}
+
+impl EchoTimer for GpioEcho {
+    fn start(&mut self) -> Result<()> {
+        self.edges.rise.store(NONE, Ordering::SeqCst);
+        self.edges.fall.store(NONE, Ordering::SeqCst);
+        self.start = micros();
+        Ok(())
+    }
+
+    fn edges(&mut self, timeout: Duration) -> Result<(Option<Duration>, Option<Duration>)> {
+        loop {
+            let rise = self.since_start(self.edges.rise.load(Ordering::SeqCst));
+            let fall = self.since_start(self.edges.fall.load(Ordering::SeqCst));
+            let waited = Duration::from_micros(micros().wrapping_sub(self.start) as u64);
+            if matches!((rise, fall), (Some(rise), Some(fall)) if fall >= rise) || waited >= timeout
+            {
+                return Ok((rise, fall));
+            }
+            thread::sleep(POLL_PERIOD);
+        }
+    }
+}
+
+impl Drop for GpioEcho {
+    fn drop(&mut self) {
+        unsafe { gpio_isr_handler_remove(self.edges.pin) };
+    }
+}
@@ -0,0 +1,320 @@
+#![allow(dead_code)]
+
+// Inertial sensors on I2C and the yaw of the car from them, built on the embedded-hal I2C traits
+
+use std::fmt::Debug;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+use crate::odometry::wrap_angle;
+
+const GRAVITY: f32 = 9.80665;
+
+/// One reading in the frame of the sensor, z up when the car stands on the floor.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct ImuSample {
+    // in radians per second, counterclockwise
+    pub gyro: [f32; 3],
+    // in metres per second squared
+    pub accel: [f32; 3],
+    // in microtesla, None without a magnetometer
+    pub magnetometer: Option<[f32; 3]>,
+}
+
+pub trait Imu {
+    fn read(&mut self) -> Result<ImuSample>;
+}
+
+impl<I: Imu + ?Sized> Imu for Box<I> {
+    fn read(&mut self) -> Result<ImuSample> {
+        (**self).read()
+    }
+}
+
+fn bus<E: Debug>(result: Result<(), E>) -> Result<()> {
+    result.map_err(|e| anyhow!("IMU I2C transfer failed: {:?}", e))
+}
+
+fn write_register<I2C, E>(i2c: &mut I2C, address: u8, register: u8, value: u8) -> Result<()>
+where
+    I2C: Write<Error = E>,
+    E: Debug,
+{
+    bus(i2c.write(address, &[register, value]))
+}
+
+fn read_registers<I2C, E>(i2c: &mut I2C, address: u8, register: u8, buffer: &mut [u8]) -> Result<()>
+where
+    I2C: WriteRead<Error = E>,
+    E: Debug,
+{
+    bus(i2c.write_read(address, &[register], buffer))
+}
+
+fn big_endian(bytes: &[u8], scale: f32) -> [f32; 3] {
+    let at = |i: usize| i16::from_be_bytes([bytes[2 * i], bytes[2 * i + 1]]) as f32 * scale;
+    [at(0), at(1), at(2)]
+}
+
+// both sensors run their gyro at +-500 degrees per second and their accelerometer at +-2 g
+const GYRO_SCALE: f32 = std::f32::consts::PI / 180.0 / 65.5;
+const ACCEL_SCALE: f32 = GRAVITY / 16384.0;
+
+pub const MPU6050_ADDRESS: u8 = 0x68;
+
+mod mpu6050 {
+    pub const CONFIG: u8 = 0x1a;
+    pub const GYRO_CONFIG: u8 = 0x1b;
+    pub const ACCEL_CONFIG: u8 = 0x1c;
+    pub const ACCEL_XOUT_H: u8 = 0x3b;
+    pub const PWR_MGMT_1: u8 = 0x6b;
+    pub const WHO_AM_I: u8 = 0x75;
+}
+
+/// InvenSense MPU6050, gyro and accelerometer.
+pub struct Mpu6050<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> Mpu6050<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(mut i2c: I2C, address: u8) -> Result<Self> {
+        let mut id = [0];
+        read_registers(&mut i2c, address, mpu6050::WHO_AM_I, &mut id)?;
+        if id[0] != 0x68 {
+            bail!("No MPU6050 at {:#x}, WHO_AM_I is {:#x}", address, id[0]);
+        }
+        // awake, clocked by the X gyro
+        write_register(&mut i2c, address, mpu6050::PWR_MGMT_1, 0x01)?;
+        thread::sleep(Duration::from_millis(10));
+        // low pass at 44 Hz
+        write_register(&mut i2c, address, mpu6050::CONFIG, 0x03)?;
+        write_register(&mut i2c, address, mpu6050::GYRO_CONFIG, 0x08)?;
+        write_register(&mut i2c, address, mpu6050::ACCEL_CONFIG, 0x00)?;
+        Ok(Self { i2c, address })
+    }
+}
+
+impl<I2C, E> Imu for Mpu6050<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn read(&mut self) -> Result<ImuSample> {
+        // accelerometer, temperature, gyro
+        let mut bytes = [0; 14];
+        read_registers(
+            &mut self.i2c,
+            self.address,
+            mpu6050::ACCEL_XOUT_H,
+            &mut bytes,
+        )?;
+        Ok(ImuSample {
+            gyro: big_endian(&bytes[8..], GYRO_SCALE),
+            accel: big_endian(&bytes[..6], ACCEL_SCALE),
+            magnetometer: None,
+        })
+    }
+}
+
+pub const ICM20948_ADDRESS: u8 = 0x69;
+
+mod icm20948 {
+    // bank 0
+    pub const WHO_AM_I: u8 = 0x00;
+    pub const PWR_MGMT_1: u8 = 0x06;
+    pub const PWR_MGMT_2: u8 = 0x07;
+    pub const INT_PIN_CFG: u8 = 0x0f;
+    pub const ACCEL_XOUT_H: u8 = 0x2d;
+    pub const REG_BANK_SEL: u8 = 0x7f;
+    // bank 2
+    pub const GYRO_CONFIG_1: u8 = 0x01;
+    pub const ACCEL_CONFIG: u8 = 0x14;
+    // the AK09916 magnetometer, reachable in bypass mode
+    pub const MAGNETOMETER_ADDRESS: u8 = 0x0c;
+    pub const WIA2: u8 = 0x01;
+    pub const ST1: u8 = 0x10;
+    pub const CNTL2: u8 = 0x31;
+}
+
+const MAGNETOMETER_SCALE: f32 = 0.15;
+
+/// InvenSense ICM-20948, gyro, accelerometer and magnetometer.
+pub struct Icm20948<I2C> {
+    i2c: I2C,
+    address: u8,
+    // the latest reading, kept while the magnetometer has no new one
+    magnetometer: Option<[f32; 3]>,
+}
+
+impl<I2C, E> Icm20948<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(mut i2c: I2C, address: u8) -> Result<Self> {
+        let mut id = [0];
+        write_register(&mut i2c, address, icm20948::REG_BANK_SEL, 0x00)?;
+        read_registers(&mut i2c, address, icm20948::WHO_AM_I, &mut id)?;
+        if id[0] != 0xea {
+            bail!("No ICM-20948 at {:#x}, WHO_AM_I is {:#x}", address, id[0]);
+        }
+        // awake with the best clock, all axes on
+        write_register(&mut i2c, address, icm20948::PWR_MGMT_1, 0x01)?;
+        thread::sleep(Duration::from_millis(10));
+        write_register(&mut i2c, address, icm20948::PWR_MGMT_2, 0x00)?;
+        // the magnetometer on the same bus
+        write_register(&mut i2c, address, icm20948::INT_PIN_CFG, 0x02)?;
+
+        write_register(&mut i2c, address, icm20948::REG_BANK_SEL, 0x20)?;
+        // +-500 degrees per second and +-2 g, low pass filters on
+        write_register(&mut i2c, address, icm20948::GYRO_CONFIG_1, 0x03)?;
+        write_register(&mut i2c, address, icm20948::ACCEL_CONFIG, 0x01)?;
+        write_register(&mut i2c, address, icm20948::REG_BANK_SEL, 0x00)?;
+
+        let magnetometer = icm20948::MAGNETOMETER_ADDRESS;
+        read_registers(&mut i2c, magnetometer, icm20948::WIA2, &mut id)?;
+        if id[0] != 0x09 {
+            bail!(
+                "No AK09916 magnetometer in the ICM-20948, WIA2 is {:#x}",
+                id[0]
+            );
+        }
+        // continuous at 100 Hz
+        write_register(&mut i2c, magnetometer, icm20948::CNTL2, 0x08)?;
+        Ok(Self {
+            i2c,
+            address,
+            magnetometer: None,
+        })
+    }
+}
+
+impl<I2C, E> Imu for Icm20948<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    E: Debug,
+{
+    fn read(&mut self) -> Result<ImuSample> {
+        let mut bytes = [0; 12];
+        read_registers(
+            &mut self.i2c,
+            self.address,
+            icm20948::ACCEL_XOUT_H,
+            &mut bytes,
+        )?;
+
+        // status, the field little endian, a spare register and the status that ends the reading
+        let mut field = [0; 9];
+        read_registers(
+            &mut self.i2c,
+            icm20948::MAGNETOMETER_ADDRESS,
+            icm20948::ST1,
+            &mut field,
+        )?;
+        // data ready and no overflow
+        if field[0] & 0x01 != 0 && field[8] & 0x08 == 0 {
+            let at = |i: usize| {
+                i16::from_le_bytes([field[1 + 2 * i], field[2 + 2 * i]]) as f32 * MAGNETOMETER_SCALE
+            };
+            // its y and z axes are the other way round than the ones of the gyro
+            self.magnetometer = Some([at(0), -at(1), -at(2)]);
+        }
+
+        Ok(ImuSample {
+            gyro: big_endian(&bytes[6..], GYRO_SCALE),
+            accel: big_endian(&bytes[..6], ACCEL_SCALE),
+            magnetometer: self.magnetometer,
+        })
+    }
+}
+
+/// The heading of the car from the field of a level magnetometer, counterclockwise from magnetic north.
+pub fn magnetic_heading(field: [f32; 3]) -> f64 {
+    // north is at -heading in the frame of the car
+    -(field[1] as f64).atan2(field[0] as f64)
+}
+
+/// The gyro bias from readings taken while the car stands still; fails if it moved.
+pub fn gyro_bias(samples: &[[f32; 3]], max_deviation: f32) -> Result<[f32; 3]> {
+    if samples.len() < 2 {
+        bail!("Not enough samples to calibrate the gyro");
+    }
+    let count = samples.len() as f32;
+    let mut bias = [0.0; 3];
+    for axis in 0..3 {
+        let mean = samples.iter().map(|sample| sample[axis]).sum::<f32>() / count;
+        let variance = samples
+            .iter()
+            .map(|sample| (sample[axis] - mean).powi(2))
+            .sum::<f32>()
+            / (count - 1.0);
+        if variance.sqrt() > max_deviation {
+            bail!(
+                "The car moved while calibrating the gyro: deviation {:.4} rad/s on axis {}",
+                variance.sqrt(),
+                axis
+            );
+        }
+        bias[axis] = mean;
+    }
+    Ok(bias)
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct YawConfig {
+    // how much of each step the yaw keeps from the gyro rather than the reference, from 0 to 1
+    pub gyro_weight: f64,
+}
+
+/// Complementary filter: the yaw rate of the gyro, pulled slowly towards a reference heading
+/// (magnetometer, odometry) when there is one.
+pub struct YawFilter {
+    config: YawConfig,
+    // of the z axis, in radians per second
+    bias: f64,
+    // in the frame of the reference, or from where the car started without one
+    yaw: Option<f64>,
+}
+
+impl YawFilter {
+    pub fn new(config: YawConfig, bias: f64) -> Self {
+        Self {
+            config,
+            bias,
+            yaw: None,
+        }
+    }
+
+    /// None before the first update.
+    pub fn yaw(&self) -> Option<f64> {
+        self.yaw
+    }
+
+    pub fn update(&mut self, gyro_z: f64, elapsed: Duration, reference: Option<f64>) -> f64 {
+        let yaw = match (self.yaw, reference) {
+            (None, Some(reference)) => reference,
+            (None, None) => 0.0,
+            (Some(yaw), reference) => {
+                let yaw = yaw + (gyro_z - self.bias) * elapsed.as_secs_f64();
+                match reference {
+                    Some(reference) => {
+                        yaw + (1.0 - self.config.gyro_weight) * wrap_angle(reference - yaw)
+                    }
+                    None => yaw,
+                }
+            }
+        };
+        let yaw = wrap_angle(yaw);
+        self.yaw = Some(yaw);
+        yaw
+    }
+}
@@ -0,0 +1,195 @@
+#![allow(dead_code)]
+
+// The goals the car works through in a run, one after the other.
+//
+// A mission is uploaded and stored as text, one goal per line; `#` starts a comment.
+//
+//     offset   0.0           # drive until the path difference is 0 m
+//     bearing  90            # turn on the spot to 90 degrees counterclockwise from where the car started
+//     position 1.0 0.5       # drive to x, y in metres from where the car started, x straight ahead
+//     hold     2.5           # stand still for 2.5 s
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+
+// more goals than this will not fit where the car stores its mission
+pub const MAX_GOALS: usize = 64;
+// a longer hold is a typo, the battery does not last that long anyway
+pub const MAX_HOLD: Duration = Duration::from_secs(3600);
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Goal {
+    // path difference, in metres
+    Offset(f64),
+    // heading, in radians counterclockwise from where the car faced at the start
+    Bearing(f64),
+    // in metres from where the car started, x straight ahead
+    Position { x: f64, y: f64 },
+    Hold(Duration),
+}
+
+impl Goal {
+    /// Whether the goal needs the path difference from the tracker.
+    pub fn needs_fix(self) -> bool {
+        matches!(self, Goal::Offset(_))
+    }
+
+    /// Whether the car drives towards the goal, rather than turning or standing.
+    pub fn drives(self) -> bool {
+        matches!(self, Goal::Offset(_) | Goal::Position { .. })
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct MissionConfig {
+    // the car is at an offset goal within this, in metres
+    pub offset_tolerance: f64,
+    // at a bearing goal within this, in radians
+    pub bearing_tolerance: f64,
+    // at a position goal within this, in metres
+    pub position_tolerance: f64,
+    // how long the car stands at an offset, bearing or position goal before the next one
+    pub settle: Duration,
+    // output per metre to a position goal
+    pub kp_position: f64,
+}
+
+impl Default for MissionConfig {
+    fn default() -> Self {
+        Self {
+            offset_tolerance: 0.02,
+            bearing_tolerance: 0.05,
+            position_tolerance: 0.05,
+            settle: Duration::from_secs(3),
+            kp_position: 2.0,
+        }
+    }
+}
+
+impl MissionConfig {
+    /// How long the car stands at `goal`.
+    pub fn hold(&self, goal: Goal) -> Duration {
+        match goal {
+            Goal::Hold(duration) => duration,
+            _ => self.settle,
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Mission {
+    goals: Vec<Goal>,
+    // of the current goal, the length of `goals` once all are reached
+    current: usize,
+}
+
+impl Default for Mission {
+    /// Drive to the line.
+    fn default() -> Self {
+        Self::new(vec![Goal::Offset(0.0)])
+    }
+}
+
+impl Mission {
+    pub fn new(goals: Vec<Goal>) -> Self {
+        Self { goals, current: 0 }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut goals = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let goal = Self::parse_line(line).map_err(|e| anyhow!("Line {}: {}", number + 1, e))?;
+            goals.push(goal);
+        }
+        if goals.is_empty() {
+            bail!("The mission has no goals");
+        }
+        if goals.len() > MAX_GOALS {
+            bail!(
+                "The mission has {} goals, at most {}",
+                goals.len(),
+                MAX_GOALS
+            );
+        }
+        Ok(Self::new(goals))
+    }
+
+    fn parse_line(line: &str) -> Result<Goal> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let kind = words[0];
+        let values = words[1..]
+            .iter()
+            .map(|word| word.parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if values.iter().any(|value| !value.is_finite()) {
+            bail!("{} takes finite values", kind);
+        }
+        let expect = |count: usize| -> Result<()> {
+            if values.len() != count {
+                bail!("{} takes {} values, got {}", kind, count, values.len());
+            }
+            Ok(())
+        };
+        Ok(match kind {
+            "offset" => {
+                expect(1)?;
+                Goal::Offset(values[0])
+            }
+            "bearing" => {
+                expect(1)?;
+                Goal::Bearing(values[0].to_radians())
+            }
+            "position" => {
+                expect(2)?;
+                Goal::Position {
+                    x: values[0],
+                    y: values[1],
+                }
+            }
+            "hold" => {
+                expect(1)?;
+                if values[0] < 0.0 || values[0] > MAX_HOLD.as_secs_f64() {
+                    bail!(
+                        "Hold of {} s is not between 0 and {} s",
+                        values[0],
+                        MAX_HOLD.as_secs()
+                    );
+                }
+                Goal::Hold(Duration::from_secs_f64(values[0]))
+            }
+            _ => bail!("Unknown goal {}", kind),
+        })
+    }
+
+    pub fn goals(&self) -> &[Goal] {
+        &self.goals
+    }
+
+    /// None once all goals are reached.
+    pub fn current(&self) -> Option<Goal> {
+        self.goals.get(self.current).copied()
+    }
+
+    /// How many goals are reached.
+    pub fn reached(&self) -> usize {
+        self.current
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.current + 1 >= self.goals.len()
+    }
+
+    pub fn next_goal(&mut self) {
+        self.current = (self.current + 1).min(self.goals.len());
+    }
+
+    /// Starts over from the first goal.
+    pub fn restart(&mut self) {
+        self.current = 0;
+    }
+}
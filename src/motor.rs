@@ -0,0 +1,342 @@
+#![allow(dead_code)]
+
+// Motor drivers behind one interface, built on the embedded-hal PWM and output pin traits
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::PwmPin;
+
+pub type DutySigned = i32;
+
+/// A DC motor: positive duties drive forward, negative ones backward.
+pub trait MotorDriver {
+    fn max_duty(&self) -> u32;
+
+    fn set_duty(&mut self, duty: DutySigned) -> Result<()>;
+
+    /// Shorts the motor, so it stops quickly.
+    fn brake(&mut self) -> Result<()>;
+
+    /// Lets the motor spin freely.
+    fn coast(&mut self) -> Result<()>;
+
+    /// `output` is a fraction of the max duty, from -1 to 1; returns the duty set.
+    fn set_output(&mut self, output: f64) -> Result<DutySigned> {
+        let duty = (output.clamp(-1.0, 1.0) * self.max_duty() as f64) as DutySigned;
+        self.set_duty(duty)?;
+        Ok(duty)
+    }
+}
+
+impl<M: MotorDriver + ?Sized> MotorDriver for Box<M> {
+    fn max_duty(&self) -> u32 {
+        (**self).max_duty()
+    }
+
+    fn set_duty(&mut self, duty: DutySigned) -> Result<()> {
+        (**self).set_duty(duty)
+    }
+
+    fn brake(&mut self) -> Result<()> {
+        (**self).brake()
+    }
+
+    fn coast(&mut self) -> Result<()> {
+        (**self).coast()
+    }
+}
+
+fn pin<E: Debug>(result: Result<(), E>) -> Result<()> {
+    result.map_err(|e| anyhow!("Failed to set a motor pin: {:?}", e))
+}
+
+fn clamp<P: PwmPin<Duty = u32>>(pwm: &P, duty: DutySigned) -> u32 {
+    duty.unsigned_abs().min(pwm.get_max_duty())
+}
+
+/// H-bridge with a PWM input per side (DRV8833, TB6612 in PWM mode, ...), as the car is wired.
+pub struct HBridge<A, B> {
+    positive: A,
+    negative: B,
+}
+
+impl<A: PwmPin<Duty = u32>, B: PwmPin<Duty = u32>> HBridge<A, B> {
+    pub fn new(positive: A, negative: B) -> Self {
+        assert_eq!(positive.get_max_duty(), negative.get_max_duty());
+        Self { positive, negative }
+    }
+}
+
+impl<A: PwmPin<Duty = u32>, B: PwmPin<Duty = u32>> MotorDriver for HBridge<A, B> {
+    fn max_duty(&self) -> u32 {
+        self.positive.get_max_duty()
+    }
+
+    fn set_duty(&mut self, duty: DutySigned) -> Result<()> {
+        let value = clamp(&self.positive, duty);
+        if duty > 0 {
+            self.positive.set_duty(value);
+            self.negative.set_duty(0);
+        } else {
+            self.positive.set_duty(0);
+            self.negative.set_duty(value);
+        }
+        Ok(())
+    }
+
+    fn brake(&mut self) -> Result<()> {
+        // both sides high
+        self.positive.set_duty(self.positive.get_max_duty());
+        self.negative.set_duty(self.negative.get_max_duty());
+        Ok(())
+    }
+
+    fn coast(&mut self) -> Result<()> {
+        self.positive.set_duty(0);
+        self.negative.set_duty(0);
+        Ok(())
+    }
+}
+
+/// Driver with a PWM and a direction input (Cytron MD10C, ...); a zero duty brakes.
+pub struct PwmDir<P, D> {
+    pwm: P,
+    direction: D,
+}
+
+impl<P: PwmPin<Duty = u32>, D: OutputPin> PwmDir<P, D> {
+    pub fn new(pwm: P, direction: D) -> Self {
+        Self { pwm, direction }
+    }
+}
+
+impl<P: PwmPin<Duty = u32>, D: OutputPin> MotorDriver for PwmDir<P, D>
+where
+    D::Error: Debug,
+{
+    fn max_duty(&self) -> u32 {
+        self.pwm.get_max_duty()
+    }
+
+    fn set_duty(&mut self, duty: DutySigned) -> Result<()> {
+        if duty >= 0 {
+            pin(self.direction.set_high())?;
+        } else {
+            pin(self.direction.set_low())?;
+        }
+        let value = clamp(&self.pwm, duty);
+        self.pwm.set_duty(value);
+        Ok(())
+    }
+
+    fn brake(&mut self) -> Result<()> {
+        self.pwm.set_duty(0);
+        Ok(())
+    }
+
+    fn coast(&mut self) -> Result<()> {
+        // these drivers have no free running state
+        self.brake()
+    }
+}
+
+/// Driver with a PWM enable and two direction inputs (L298N, ...).
+pub struct PwmInputs<P, I1, I2> {
+    enable: P,
+    in1: I1,
+    in2: I2,
+}
+
+impl<P: PwmPin<Duty = u32>, I1: OutputPin, I2: OutputPin> PwmInputs<P, I1, I2> {
+    pub fn new(enable: P, in1: I1, in2: I2) -> Self {
+        Self { enable, in1, in2 }
+    }
+}
+
+impl<P: PwmPin<Duty = u32>, I1: OutputPin, I2: OutputPin> MotorDriver for PwmInputs<P, I1, I2>
+where
+    I1::Error: Debug,
+    I2::Error: Debug,
+{
+    fn max_duty(&self) -> u32 {
+        self.enable.get_max_duty()
+    }
+
+    fn set_duty(&mut self, duty: DutySigned) -> Result<()> {
+        if duty >= 0 {
+            pin(self.in2.set_low())?;
+            pin(self.in1.set_high())?;
+        } else {
+            pin(self.in1.set_low())?;
+            pin(self.in2.set_high())?;
+        }
+        let value = clamp(&self.enable, duty);
+        self.enable.set_duty(value);
+        Ok(())
+    }
+
+    fn brake(&mut self) -> Result<()> {
+        // both inputs at the same level, fully enabled
+        pin(self.in1.set_low())?;
+        pin(self.in2.set_low())?;
+        self.enable.set_duty(self.enable.get_max_duty());
+        Ok(())
+    }
+
+    fn coast(&mut self) -> Result<()> {
+        self.enable.set_duty(0);
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Stop {
+    Brake,
+    Coast,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct OutputConfig {
+    // how fast the output may change, in fractions of the max duty per second
+    pub max_slew: f64,
+    // the smallest fraction of the max duty that turns the motor, smaller outputs are raised to it
+    pub min_duty: f64,
+    // gain on the output, to match the motors so the car drives straight
+    pub trim: f64,
+    // what the motor does when the output is zero
+    pub stop: Stop,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            // a full reversal takes 0.4 s
+            max_slew: 5.0,
+            min_duty: 0.0,
+            trim: 1.0,
+            stop: Stop::Coast,
+        }
+    }
+}
+
+/// Shapes the control output before it reaches a motor.
+pub struct OutputStage<M> {
+    motor: M,
+    config: OutputConfig,
+    // the output after slew limiting, before trim and deadband
+    output: f64,
+    // gain on the duty for the voltage of the supply
+    supply: f64,
+}
+
+impl<M: MotorDriver> OutputStage<M> {
+    pub fn new(motor: M, config: OutputConfig) -> Self {
+        Self {
+            motor,
+            config,
+            output: 0.0,
+            supply: 1.0,
+        }
+    }
+
+    /// Scales the duties by `gain`, so the motor is as strong on a sagging supply.
+    pub fn set_supply_compensation(&mut self, gain: f64) {
+        self.supply = gain;
+    }
+
+    pub fn motor(&self) -> &M {
+        &self.motor
+    }
+
+    /// The output the motor is moving at, from -1 to 1.
+    pub fn output(&self) -> f64 {
+        self.output
+    }
+
+    /// Moves towards `target` (from -1 to 1) as far as allowed in `elapsed`; returns the duty set.
+    pub fn drive(&mut self, target: f64, elapsed: Duration) -> Result<DutySigned> {
+        let target = target.clamp(-1.0, 1.0);
+        let step = self.config.max_slew * elapsed.as_secs_f64();
+        self.output = if (target - self.output).abs() <= step {
+            target
+        } else {
+            self.output + step.copysign(target - self.output)
+        };
+        if self.output == 0.0 {
+            if target == 0.0 {
+                match self.config.stop {
+                    Stop::Brake => self.motor.brake()?,
+                    Stop::Coast => self.motor.coast()?,
+                }
+            } else {
+                // reversing
+                self.motor.set_duty(0)?;
+            }
+            return Ok(0);
+        }
+        let magnitude = (self.output.abs() * self.config.trim).min(1.0);
+        let min_duty = self.config.min_duty;
+        let magnitude = ((min_duty + (1.0 - min_duty) * magnitude) * self.supply).min(1.0);
+        let duty = (magnitude * self.motor.max_duty() as f64) as DutySigned;
+        let duty = if self.output < 0.0 { -duty } else { duty };
+        self.motor.set_duty(duty)?;
+        Ok(duty)
+    }
+
+    /// Stops at once, without slewing down.
+    pub fn brake(&mut self) -> Result<()> {
+        self.output = 0.0;
+        self.motor.brake()
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum MotorCommand {
+    Duty(DutySigned),
+    Brake,
+    Coast,
+}
+
+/// Remembers what it was told to do, for host tests.
+pub struct MockMotor {
+    max_duty: u32,
+    pub commands: Vec<MotorCommand>,
+}
+
+impl MockMotor {
+    pub fn new(max_duty: u32) -> Self {
+        Self {
+            max_duty,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn last(&self) -> Option<MotorCommand> {
+        self.commands.last().copied()
+    }
+}
+
+impl MotorDriver for MockMotor {
+    fn max_duty(&self) -> u32 {
+        self.max_duty
+    }
+
+    fn set_duty(&mut self, duty: DutySigned) -> Result<()> {
+        self.commands.push(MotorCommand::Duty(duty));
+        Ok(())
+    }
+
+    fn brake(&mut self) -> Result<()> {
+        self.commands.push(MotorCommand::Brake);
+        Ok(())
+    }
+
+    fn coast(&mut self) -> Result<()> {
+        self.commands.push(MotorCommand::Coast);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+#![allow(dead_code)]
+
+// What is in front of the car, and when it must not drive on
+
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+
+use embedded_hal::digital::v2::OutputPin;
+
+/// Measures the distance to the nearest obstacle ahead.
+pub trait RangeSensor {
+    /// In metres, None when nothing is in range; an error when the sensor did not answer.
+    fn distance(&mut self) -> Result<Option<f32>>;
+}
+
+/// Times the echo pulse of a range sensor, without blocking the CPU while waiting for it.
+pub trait EchoTimer {
+    /// Forgets the edges seen so far, the times of the next ones count from here.
+    fn start(&mut self) -> Result<()>;
+
+    /// When the echo went high and low again, waiting at most `timeout` from the start;
+    /// None for an edge that did not come.
+    fn edges(&mut self, timeout: Duration) -> Result<(Option<Duration>, Option<Duration>)>;
+}
+
+impl<R: RangeSensor + ?Sized> RangeSensor for Box<R> {
+    fn distance(&mut self) -> Result<Option<f32>> {
+        (**self).distance()
+    }
+}
+
+const SPEED_OF_SOUND: f32 = 343.0;
+const TRIGGER_PULSE: Duration = Duration::from_micros(10);
+// the echo starts about half a millisecond after the trigger
+const ECHO_START_TIMEOUT: Duration = Duration::from_millis(5);
+// about 4 m there and back
+const ECHO_TIMEOUT: Duration = Duration::from_millis(25);
+
+fn pin<T, E: Debug>(result: Result<T, E>) -> Result<T> {
+    result.map_err(|e| anyhow!("HC-SR04 pin failed: {:?}", e))
+}
+
+fn spin(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {}
+}
+
+/// Ultrasonic ranging with a HC-SR04. Blocks the calling thread for up to 30 ms.
+pub struct HcSr04<T, E> {
+    trigger: T,
+    echo: E,
+}
+
+impl<T: OutputPin, E: EchoTimer> HcSr04<T, E>
+where
+    T::Error: Debug,
+{
+    pub fn new(mut trigger: T, echo: E) -> Result<Self> {
+        pin(trigger.set_low())?;
+        Ok(Self { trigger, echo })
+    }
+}
+
+impl<T: OutputPin, E: EchoTimer> RangeSensor for HcSr04<T, E>
+where
+    T::Error: Debug,
+{
+    fn distance(&mut self) -> Result<Option<f32>> {
+        self.echo.start()?;
+        pin(self.trigger.set_high())?;
+        spin(TRIGGER_PULSE);
+        pin(self.trigger.set_low())?;
+
+        let (rise, fall) = self.echo.edges(ECHO_START_TIMEOUT + ECHO_TIMEOUT)?;
+        // a sensor that is unplugged or broken must not look like a clear way
+        let rise = match rise {
+            Some(rise) if rise <= ECHO_START_TIMEOUT => rise,
+            _ => bail!("HC-SR04 did not answer the trigger"),
+        };
+        // the echo pulse of a sensor that hears nothing goes on beyond the timeout
+        Ok(fall
+            .and_then(|fall| fall.checked_sub(rise))
+            .filter(|pulse| *pulse <= ECHO_TIMEOUT)
+            .map(|pulse| pulse.as_secs_f32() * SPEED_OF_SOUND / 2.0))
+    }
+}
+
+/// A range sensor the host tests place obstacles for.
+#[derive(Default)]
+pub struct MockRange {
+    pub distances: Vec<Option<f32>>,
+}
+
+impl RangeSensor for MockRange {
+    fn distance(&mut self) -> Result<Option<f32>> {
+        Ok(if self.distances.is_empty() {
+            None
+        } else {
+            self.distances.remove(0)
+        })
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct SafetyConfig {
+    // the car must not drive forward with an obstacle closer than this, in metres
+    pub stop_distance: f32,
+    // and drives on once it is further than this
+    pub clear_distance: f32,
+    // without a measurement for this long, the way ahead counts as blocked
+    pub max_age: Duration,
+}
+
+/// Decides whether the way ahead is blocked, from the measurements of a range sensor.
+pub struct Supervisor {
+    config: SafetyConfig,
+    blocked: bool,
+    distance: Option<f32>,
+    measured: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(config: SafetyConfig) -> Self {
+        Self {
+            config,
+            blocked: false,
+            distance: None,
+            measured: None,
+        }
+    }
+
+    /// The latest measurement, None when nothing was in range.
+    pub fn distance(&self) -> Option<f32> {
+        self.distance
+    }
+
+    /// The sensor failed to measure at `now`, so the way ahead counts as blocked.
+    pub fn fail(&mut self, now: Instant) {
+        self.distance = None;
+        self.measured = Some(now);
+        self.blocked = true;
+    }
+
+    pub fn measure(&mut self, distance: Option<f32>, now: Instant) {
+        self.distance = distance;
+        self.measured = Some(now);
+        self.blocked = match distance {
+            Some(distance) if distance < self.config.stop_distance => true,
+            Some(distance) if distance < self.config.clear_distance => self.blocked,
+            _ => false,
+        };
+    }
+
+    pub fn blocked(&self, now: Instant) -> bool {
+        match self.measured {
+            Some(measured) => {
+                self.blocked || now.saturating_duration_since(measured) > self.config.max_age
+            }
+            // the sensor never answered
+            None => true,
+        }
+    }
+}
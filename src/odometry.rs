@@ -0,0 +1,168 @@
+#![allow(dead_code)]
+
+// Where the car is and how fast its wheels turn, from the counts of its wheel encoders
+
+use std::cell::Cell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use pid::Pid;
+
+/// A quadrature wheel encoder; counts grow when the wheel drives the car forward.
+pub trait Encoder {
+    /// Counts since the encoder started, every edge of both signals.
+    fn count(&mut self) -> Result<i64>;
+}
+
+impl<E: Encoder + ?Sized> Encoder for Box<E> {
+    fn count(&mut self) -> Result<i64> {
+        (**self).count()
+    }
+}
+
+/// An encoder the host tests turn by hand.
+#[derive(Clone, Default)]
+pub struct MockEncoder(pub Rc<Cell<i64>>);
+
+impl MockEncoder {
+    pub fn turn(&self, counts: i64) {
+        self.0.set(self.0.get() + counts);
+    }
+}
+
+impl Encoder for MockEncoder {
+    fn count(&mut self) -> Result<i64> {
+        Ok(self.0.get())
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct OdometryConfig {
+    // counts of the encoder per turn of the wheel
+    pub counts_per_revolution: f64,
+    // in metres
+    pub wheel_diameter: f64,
+    // between the middles of the wheels, in metres
+    pub track_width: f64,
+}
+
+impl OdometryConfig {
+    pub fn metres_per_count(&self) -> f64 {
+        PI * self.wheel_diameter / self.counts_per_revolution
+    }
+}
+
+/// Relative to where the car started, heading along x; in metres and radians, counterclockwise.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Pose {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+}
+
+/// Differential drive dead reckoning.
+pub struct Odometry {
+    config: OdometryConfig,
+    pose: Pose,
+    // driven along the path, backwards counts negative
+    distance: f64,
+    // of the left and right wheel, in metres per second
+    speeds: (f64, f64),
+    counts: Option<(i64, i64)>,
+}
+
+impl Odometry {
+    pub fn new(config: OdometryConfig) -> Self {
+        Self {
+            config,
+            pose: Pose::default(),
+            distance: 0.0,
+            speeds: (0.0, 0.0),
+            counts: None,
+        }
+    }
+
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    pub fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    pub fn speeds(&self) -> (f64, f64) {
+        self.speeds
+    }
+
+    /// Takes the counts of the left and right encoder, read `elapsed` after the previous ones.
+    pub fn update(&mut self, left: i64, right: i64, elapsed: Duration) {
+        let (previous_left, previous_right) = self.counts.unwrap_or((left, right));
+        self.counts = Some((left, right));
+        let metres = self.config.metres_per_count();
+        let left = (left - previous_left) as f64 * metres;
+        let right = (right - previous_right) as f64 * metres;
+        let seconds = elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.speeds = (left / seconds, right / seconds);
+        }
+
+        let forward = (left + right) / 2.0;
+        let turn = (right - left) / self.config.track_width;
+        // along the mean heading of the step
+        let heading = self.pose.heading + turn / 2.0;
+        self.pose.x += forward * heading.cos();
+        self.pose.y += forward * heading.sin();
+        self.pose.heading = wrap_angle(self.pose.heading + turn);
+        self.distance += forward;
+    }
+}
+
+/// The same angle, from -PI to PI.
+pub fn wrap_angle(angle: f64) -> f64 {
+    let wrapped = (angle + PI).rem_euclid(2.0 * PI) - PI;
+    if wrapped == -PI {
+        PI
+    } else {
+        wrapped
+    }
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct SpeedConfig {
+    // wheel speed at full duty on the floor, in metres per second
+    pub max_speed: f64,
+    // gains on the speed error in metres per second, with the output a fraction of the max duty
+    pub kp: f64,
+    pub ki: f64,
+}
+
+/// Keeps a wheel at a speed: feed forward from `max_speed`, corrected by a PI on the measured speed.
+/// Runs once per control tick.
+pub struct SpeedControl {
+    config: SpeedConfig,
+    pid: Pid<f64>,
+}
+
+impl SpeedControl {
+    pub fn new(config: SpeedConfig) -> Self {
+        Self {
+            config,
+            pid: Pid::new(config.kp, config.ki, 0.0, 1.0, 0.5, 1.0, 1.0, 0.0),
+        }
+    }
+
+    /// The output driving the wheel at `target` metres per second, from -1 to 1.
+    pub fn next(&mut self, target: f64, measured: f64) -> f64 {
+        if target == 0.0 {
+            // stopping is left to the output stage, nothing to wind up
+            self.pid.reset_integral_term();
+            return 0.0;
+        }
+        self.pid.setpoint = target;
+        let correction = self.pid.next_control_output(measured).output;
+        (target / self.config.max_speed + correction).clamp(-1.0, 1.0)
+    }
+}
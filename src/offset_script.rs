@@ -0,0 +1,184 @@
+#![allow(dead_code)]
+
+// Scripted path difference sequences for the fake tracker.
+//
+// One segment per line: its duration in seconds, its shape and the parameters of the shape,
+// optionally followed by `confidence=<0..1>` (1 by default). `#` starts a comment.
+//
+//     2    constant 0.1
+//     2    step     0.1 -0.1          # first half, second half
+//     3    ramp     0.2 -0.2          # from, to
+//     5    sine     0.0 0.15 2.0      # center, amplitude, period in seconds
+//     4    noise    0.0 0.05          # mean, standard deviation
+//     1.5  drop                       # the link is down
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::rng::Rng;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Shape {
+    Constant(f32),
+    Step(f32, f32),
+    Ramp(f32, f32),
+    Sine {
+        center: f32,
+        amplitude: f32,
+        period: f32,
+    },
+    Noise {
+        mean: f32,
+        deviation: f32,
+    },
+    Drop,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Segment {
+    pub duration: Duration,
+    pub shape: Shape,
+    pub confidence: f32,
+}
+
+/// What the fake tracker reports at some moment.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Sample {
+    // in metres
+    pub path_difference: f32,
+    pub confidence: f32,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Script {
+    pub segments: Vec<Segment>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let segment =
+                Self::parse_line(line).map_err(|e| anyhow!("Line {}: {}", number + 1, e))?;
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            bail!("The script has no segments");
+        }
+        Ok(Self { segments })
+    }
+
+    fn parse_line(line: &str) -> Result<Segment> {
+        let mut confidence = 1.0;
+        let mut words = Vec::new();
+        for word in line.split_whitespace() {
+            match word.strip_prefix("confidence=") {
+                Some(value) => confidence = value.parse()?,
+                None => words.push(word),
+            }
+        }
+        let seconds: f32 = words
+            .first()
+            .ok_or_else(|| anyhow!("No duration"))?
+            .parse()?;
+        if seconds.is_nan() || seconds <= 0.0 {
+            bail!("Duration {} is not positive", seconds);
+        }
+        let kind = words.get(1).ok_or_else(|| anyhow!("No shape"))?;
+        let values = words[2..]
+            .iter()
+            .map(|word| word.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let expect = |count: usize| -> Result<()> {
+            if values.len() != count {
+                bail!("{} takes {} values, got {}", kind, count, values.len());
+            }
+            Ok(())
+        };
+        let shape = match *kind {
+            "constant" => {
+                expect(1)?;
+                Shape::Constant(values[0])
+            }
+            "step" => {
+                expect(2)?;
+                Shape::Step(values[0], values[1])
+            }
+            "ramp" => {
+                expect(2)?;
+                Shape::Ramp(values[0], values[1])
+            }
+            "sine" => {
+                expect(3)?;
+                Shape::Sine {
+                    center: values[0],
+                    amplitude: values[1],
+                    period: values[2],
+                }
+            }
+            "noise" => {
+                expect(2)?;
+                Shape::Noise {
+                    mean: values[0],
+                    deviation: values[1],
+                }
+            }
+            "drop" => {
+                expect(0)?;
+                Shape::Drop
+            }
+            _ => bail!("Unknown shape {}", kind),
+        };
+        Ok(Segment {
+            duration: Duration::from_secs_f32(seconds),
+            shape,
+            confidence,
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.segments.iter().map(|segment| segment.duration).sum()
+    }
+
+    /// The report `time` after the script started; None while the link is down or after the end.
+    pub fn sample(&self, time: Duration, rng: &mut Rng) -> Option<Sample> {
+        let mut start = Duration::ZERO;
+        for segment in &self.segments {
+            if time < start + segment.duration {
+                let t = (time - start).as_secs_f32();
+                let share = t / segment.duration.as_secs_f32();
+                let path_difference = match segment.shape {
+                    Shape::Constant(value) => value,
+                    Shape::Step(before, after) => {
+                        if share < 0.5 {
+                            before
+                        } else {
+                            after
+                        }
+                    }
+                    Shape::Ramp(from, to) => from + (to - from) * share,
+                    Shape::Sine {
+                        center,
+                        amplitude,
+                        period,
+                    } => center + amplitude * (2.0 * std::f32::consts::PI * t / period).sin(),
+                    Shape::Noise { mean, deviation } => {
+                        mean + rng.gaussian(deviation as f64) as f32
+                    }
+                    Shape::Drop => return None,
+                };
+                return Some(Sample {
+                    path_difference,
+                    confidence: segment.confidence,
+                });
+            }
+            start += segment.duration;
+        }
+        None
+    }
+}
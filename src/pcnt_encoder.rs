@@ -0,0 +1,70 @@
+// Quadrature wheel encoders counted by the PCNT peripheral (ESP32 only)
+
+use anyhow::Result;
+
+use esp_idf_sys::*;
+
+use crate::odometry::Encoder;
+
+// the counter is reset to zero when it reaches either limit
+const LIMIT: i16 = 30000;
+// pulses shorter than this many APB clock cycles (80 MHz) are ignored
+const GLITCH_FILTER: u16 = 100;
+
+pub struct PcntEncoder {
+    unit: pcnt_unit_t,
+    // as read last time
+    counter: i16,
+    count: i64,
+}
+
+impl PcntEncoder {
+    /// Counts every edge of both signals of the encoder on `unit`; swap `a` and `b` to reverse it.
+    pub fn new(unit: pcnt_unit_t, a: i32, b: i32) -> Result<Self> {
+        // each channel counts the edges of one signal, in the direction the other gives
+        for (channel, pulse, control) in [(pcnt_channel_t_PCNT_CHANNEL_0, a, b), (pcnt_channel_t_PCNT_CHANNEL_1, b, a)] {
+            let reverse = channel == pcnt_channel_t_PCNT_CHANNEL_1;
+            let config = pcnt_config_t {
+                pulse_gpio_num: pulse,
+                ctrl_gpio_num: control,
+                lctrl_mode: if reverse { pcnt_ctrl_mode_t_PCNT_MODE_KEEP } else { pcnt_ctrl_mode_t_PCNT_MODE_REVERSE },
+                hctrl_mode: if reverse { pcnt_ctrl_mode_t_PCNT_MODE_REVERSE } else { pcnt_ctrl_mode_t_PCNT_MODE_KEEP },
+                pos_mode: pcnt_count_mode_t_PCNT_COUNT_INC,
+                neg_mode: pcnt_count_mode_t_PCNT_COUNT_DEC,
+                counter_h_lim: LIMIT,
+                counter_l_lim: -LIMIT,
+                unit,
+                channel,
+            };
+            esp!(unsafe { pcnt_unit_config(&config) })?;
+        }
+        esp!(unsafe { pcnt_set_filter_value(unit, GLITCH_FILTER) })?;
+        esp!(unsafe { pcnt_filter_enable(unit) })?;
+        esp!(unsafe { pcnt_counter_pause(unit) })?;
+        esp!(unsafe { pcnt_counter_clear(unit) })?;
+        esp!(unsafe { pcnt_counter_resume(unit) })?;
+        Ok(Self {
+            unit,
+            counter: 0,
+            count: 0,
+        })
+    }
+}
+
+impl Encoder for PcntEncoder {
+    // must be read before the counter moves by half its range
+    fn count(&mut self) -> Result<i64> {
+        let mut counter: i16 = 0;
+        esp!(unsafe { pcnt_get_counter_value(self.unit, &mut counter) })?;
+        let mut change = counter as i64 - self.counter as i64;
+        // the counter went back to zero at a limit
+        if change > LIMIT as i64 / 2 {
+            change -= LIMIT as i64;
+        } else if change < -(LIMIT as i64) / 2 {
+            change += LIMIT as i64;
+        }
+        self.counter = counter;
+        self.count += change;
+        Ok(self.count)
+    }
+}
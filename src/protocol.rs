@@ -0,0 +1,129 @@
+#![allow(dead_code)]
+
+// What the tracker and the car send each other, in the memory layout of the ESP32
+
+use std::{mem, slice};
+
+// the tracker supports 2 to 8 receivers
+pub const MAX_RECEIVERS: usize = 8;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct ControlData {
+    // arrival time at microphone a minus arrival time at microphone b, in nanoseconds
+    pub offset: i128,
+    // `offset` converted with the array geometry: how much further the sound travelled to a than to b, in metres
+    pub path_difference: f32,
+    // `path_difference` after outlier rejection and smoothing, in metres
+    pub filtered: f32,
+    // how fast `filtered` changes, in metres per second
+    pub rate: f32,
+    // direction of the car in radians from the bisector of microphones a and b, positive towards b;
+    // all around the tracker when it has more than two receivers
+    pub bearing: f32,
+    // how much the measurement can be trusted, from 0 (noise) to 1 (clean hit on every channel)
+    pub confidence: f32,
+    // distance to each microphone in metres, NaN unless time-of-flight ranging is available
+    pub distance: [f32; MAX_RECEIVERS],
+    // counts the measurements of the tracker from 1, so the car takes each only once
+    pub sequence: u32,
+    // when the tracker heard the beep, in microseconds of its time base
+    pub time: u64,
+    // how long before sending the control data the tracker heard the beep, in microseconds
+    pub age: u32,
+}
+
+// https://stackoverflow.com/questions/25917260/getting-raw-bytes-from-packed-struct/25918452#25918452
+pub fn raw_byte_repr<T>(ptr: &T) -> &[u8] {
+    let p: *const T = ptr; // the same operator is used as with references
+    let p: *const u8 = p as *const u8; // convert between pointer types
+    unsafe { slice::from_raw_parts(p, mem::size_of::<T>()) }
+}
+
+pub fn struct_repr<T>(ptr: &[u8]) -> &T {
+    if ptr.len() != std::mem::size_of::<T>() {
+        panic!("Unexpected len");
+    }
+    let p: *const u8 = ptr.as_ptr();
+    let p: *const T = p as *const T;
+    unsafe { &*p }
+}
+
+impl ControlData {
+    pub fn empty() -> Self {
+        Self {
+            offset: 0,
+            path_difference: 0.0,
+            filtered: 0.0,
+            rate: 0.0,
+            bearing: 0.0,
+            confidence: 0.0,
+            distance: [f32::NAN; MAX_RECEIVERS],
+            sequence: 0,
+            time: 0,
+            age: 0,
+        }
+    }
+    pub fn size() -> usize {
+        mem::size_of::<ControlData>()
+    }
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_slice(&self) -> &[u8] {
+        raw_byte_repr(self)
+    }
+    pub fn from_slice(x: &[u8]) -> &Self {
+        struct_repr(x)
+    }
+}
+
+/// Numbers the measurements of the tracker for `ControlData::sequence`. The detection reports each
+/// burst again until it has the next one, a burst seen before keeps its number.
+pub struct Sequence {
+    // `ControlData::time` of the last burst and its number
+    last: Option<(u64, u32)>,
+}
+
+impl Sequence {
+    pub fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// The number of the burst heard at `time`.
+    pub fn number(&mut self, time: u64) -> u32 {
+        let number = match self.last {
+            Some((last, number)) if last == time => return number,
+            Some((_, number)) => number + 1,
+            None => 1,
+        };
+        self.last = Some((time, number));
+        number
+    }
+}
+
+pub const TIME_SYNC_REQUEST: u8 = 0;
+pub const TIME_SYNC_RESPONSE: u8 = 1;
+pub const TIME_BEEP_REPORT: u8 = 2;
+
+// exchanged over UDP port 8081 of the tracker to share its time base with the car
+#[derive(PartialEq, Debug, Copy, Clone)]
+#[repr(C, packed)]
+pub struct TimePacket {
+    pub kind: u8,
+    // sync request/response: car time when the request was sent
+    pub sent: u64,
+    // sync response: tracker time when answering; beep report: tracker time of the rising edge
+    pub tracker: u64,
+}
+
+impl TimePacket {
+    pub fn size() -> usize {
+        mem::size_of::<TimePacket>()
+    }
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_slice(&self) -> &[u8] {
+        raw_byte_repr(self)
+    }
+    pub fn from_slice(x: &[u8]) -> &Self {
+        struct_repr(x)
+    }
+}
@@ -0,0 +1,51 @@
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use crate::geometry::ArrayGeometry;
+
+/// How much a measurement can be trusted, from 0 (noise) to 1 (clean hit on every channel).
+///
+/// The product of three scores:
+/// - spread: the arrivals should be much closer together than the `range_time` detection window
+/// - geometry: no pair of arrivals may be further apart than sound needs to cross their baseline
+/// - pulses: every channel should have heard pulses of the same width
+pub fn confidence(
+    geometry: &ArrayGeometry,
+    arrivals_ns: &[i64],
+    pulse_widths: &[Duration],
+    range_time: Duration,
+) -> f32 {
+    spread_score(arrivals_ns, range_time)
+        * geometry_score(geometry, arrivals_ns)
+        * pulse_score(pulse_widths)
+}
+
+pub fn spread_score(arrivals_ns: &[i64], range_time: Duration) -> f32 {
+    let first = arrivals_ns.iter().min().copied().unwrap_or(0);
+    let last = arrivals_ns.iter().max().copied().unwrap_or(0);
+    (1.0 - (last - first) as f32 / range_time.as_nanos() as f32).clamp(0.0, 1.0)
+}
+
+pub fn geometry_score(geometry: &ArrayGeometry, arrivals_ns: &[i64]) -> f32 {
+    let mut score = 1.0;
+    for i in 0..arrivals_ns.len() {
+        for j in i + 1..arrivals_ns.len() {
+            let limit = geometry.max_offset(i, j) as f32;
+            let excess = ((arrivals_ns[i] - arrivals_ns[j]).abs() as f32 - limit).max(0.0);
+            // an excess of a whole baseline is certainly not the same sound
+            score *= (1.0 - excess / limit).clamp(0.0, 1.0);
+        }
+    }
+    score
+}
+
+pub fn pulse_score(pulse_widths: &[Duration]) -> f32 {
+    let shortest = pulse_widths.iter().min().copied().unwrap_or_default();
+    let longest = pulse_widths.iter().max().copied().unwrap_or_default();
+    if longest.as_nanos() == 0 {
+        // nothing heard before, no evidence either way
+        return 0.5;
+    }
+    shortest.as_secs_f32() / longest.as_secs_f32()
+}
@@ -0,0 +1,101 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+use crate::geometry;
+use crate::timebase::Micros;
+
+#[derive(Debug, Copy, Clone)]
+pub struct RangingConfig {
+    // in m/s, see `geometry::ArrayGeometry::speed_of_sound`
+    pub speed_of_sound: f32,
+    // anything further away is not considered the echo of a beep
+    pub max_range_m: f32,
+}
+
+impl Default for RangingConfig {
+    fn default() -> Self {
+        Self {
+            speed_of_sound: geometry::speed_of_sound(20.0),
+            max_range_m: 10.0,
+        }
+    }
+}
+
+impl RangingConfig {
+    pub fn max_flight_time(&self) -> Micros {
+        (self.max_range_m / self.speed_of_sound * 1e6) as Micros
+    }
+
+    /// Distance travelled by a sound emitted at `emitted` and heard at `arrived`, in metres.
+    pub fn distance(&self, emitted: Micros, arrived: Micros) -> Option<f32> {
+        if arrived < emitted || arrived - emitted > self.max_flight_time() {
+            return None;
+        }
+        Some((arrived - emitted) as f32 * 1e-6 * self.speed_of_sound)
+    }
+}
+
+const BEEP_HISTORY: usize = 8;
+
+/// Pairs the beep emission times reported by the car with the arrival times seen by the tracker.
+/// Either side may come in first, the ranges are (re)computed whenever a new piece arrives.
+pub struct RangeMatcher {
+    config: RangingConfig,
+    beeps: VecDeque<Micros>,
+    arrivals: Vec<Micros>,
+    ranges: Option<Vec<f32>>,
+}
+
+impl RangeMatcher {
+    pub fn new(config: RangingConfig) -> Self {
+        Self {
+            config,
+            beeps: VecDeque::with_capacity(BEEP_HISTORY),
+            arrivals: Vec::new(),
+            ranges: None,
+        }
+    }
+
+    /// True if it completes the ranges of the latest arrivals, heard before the beep was reported.
+    pub fn push_beep(&mut self, emitted: Micros) -> bool {
+        if self.beeps.len() == BEEP_HISTORY {
+            self.beeps.pop_front();
+        }
+        self.beeps.push_back(emitted);
+        let known = self.ranges.is_some();
+        self.update();
+        !known && self.ranges.is_some()
+    }
+
+    pub fn push_arrivals(&mut self, arrivals: &[Micros]) {
+        if self.arrivals == arrivals {
+            return;
+        }
+        self.arrivals = arrivals.to_vec();
+        self.ranges = None;
+        self.update();
+    }
+
+    /// Distance from the car to each microphone for the latest arrivals, if their beep is known.
+    pub fn ranges(&self) -> Option<&[f32]> {
+        self.ranges.as_deref()
+    }
+
+    fn update(&mut self) {
+        let first = match self.arrivals.iter().min() {
+            Some(first) => *first,
+            None => return,
+        };
+        // the latest beep which may still have been heard
+        let emitted = match self.beeps.iter().filter(|beep| **beep <= first).max() {
+            Some(emitted) => *emitted,
+            None => return,
+        };
+        self.ranges = self
+            .arrivals
+            .iter()
+            .map(|arrived| self.config.distance(emitted, *arrived))
+            .collect();
+    }
+}
@@ -0,0 +1,78 @@
+// Replays a log recorded by the tracker (feature `record`) through its detection and `calculate`.
+// Run with `cargo run --bin replay --target x86_64-unknown-linux-gnu -- <log>`; prints the replayed
+// measurements as CSV and exits non-zero if any of them differs from the recorded one.
+
+mod calibration;
+mod detection;
+mod event_log;
+mod geometry;
+mod quality;
+mod timebase;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+// how often the tracker polls its receivers, roughly
+const POLL: Duration = Duration::from_micros(100);
+
+fn main() -> Result<()> {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => bail!("Usage: replay <log>"),
+    };
+    let records = event_log::parse(&fs::read(&path)?)?;
+    let recorded = event_log::recorded(&records);
+    let replayed = event_log::replay(&records, POLL)?;
+
+    println!("time_us,offset_ns,path_difference_m,bearing_deg,confidence,recorded");
+    let mut differing = 0;
+    for (time, measurement) in &replayed {
+        let status = match recorded
+            .iter()
+            .find(|(recorded_time, _)| recorded_time == time)
+        {
+            Some((_, recorded)) if event_log::same(recorded, measurement) => "same",
+            Some(_) => {
+                differing += 1;
+                "DIFFERENT"
+            }
+            None => "missing",
+        };
+        println!(
+            "{},{},{:.4},{:.1},{:.2},{}",
+            time,
+            measurement.offset,
+            measurement.path_difference,
+            measurement.bearing.to_degrees(),
+            measurement.confidence,
+            status
+        );
+    }
+    let unmatched = recorded
+        .iter()
+        .filter(|(time, _)| {
+            !replayed
+                .iter()
+                .any(|(replayed_time, _)| replayed_time == time)
+        })
+        .count();
+
+    eprintln!(
+        "{} records, {} measurements recorded, {} replayed, {} different, {} not replayed",
+        records.len(),
+        recorded.len(),
+        replayed.len(),
+        differing,
+        unmatched
+    );
+
+    if differing > 0 || unmatched > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+#![allow(dead_code)]
+
+// How the car moves while the tracker hears nothing of it, so it gets heard
+
+use std::time::Duration;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum SearchPattern {
+    // stand still and beep
+    Stand,
+    // turn on the spot
+    Rotate,
+    // drive in circles that widen with time
+    Spiral,
+    // drive forward and backward around where the search started
+    BackAndForth,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct SearchConfig {
+    pub pattern: SearchPattern,
+    // fraction of the max duty driving forward or backward
+    pub speed: f64,
+    // taken from the left engine and given to the right one when rotating, at the start of a spiral
+    pub turn: f64,
+    // the turn of a spiral halves after this, so its radius doubles
+    pub spiral_period: Duration,
+    // of a leg back or forth
+    pub leg: Duration,
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            pattern: SearchPattern::Rotate,
+            speed: 0.3,
+            turn: 0.3,
+            spiral_period: Duration::from_secs(5),
+            leg: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The output and the turn of the car `elapsed` into the search.
+pub fn motion(config: &SearchConfig, elapsed: Duration) -> (f64, f64) {
+    match config.pattern {
+        SearchPattern::Stand => (0.0, 0.0),
+        SearchPattern::Rotate => (0.0, config.turn),
+        SearchPattern::Spiral => {
+            let period = config.spiral_period.as_secs_f64();
+            (
+                config.speed,
+                config.turn * period / (period + elapsed.as_secs_f64()),
+            )
+        }
+        SearchPattern::BackAndForth => {
+            // the first leg is half as long, so the car swings around where it started
+            let legs = elapsed.as_secs_f64() / config.leg.as_secs_f64() + 0.5;
+            if (legs as u64) % 2 == 0 {
+                (config.speed, 0.0)
+            } else {
+                (-config.speed, 0.0)
+            }
+        }
+    }
+}
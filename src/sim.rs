@@ -0,0 +1,2508 @@
+// Host simulator: runs the signal processing of the tracker against synthetic data.
+// Run with `cargo run --bin sim --target x86_64-unknown-linux-gnu`; exits non-zero if a scenario fails.
+
+mod battery;
+mod beep_pattern;
+mod calibration;
+mod car_control;
+mod detection;
+mod dsp;
+mod estimator;
+mod event_log;
+mod filter;
+mod flight_recorder;
+mod geometry;
+mod imu;
+mod mission;
+mod motor;
+mod obstacle;
+mod odometry;
+mod offset_script;
+mod protocol;
+mod quality;
+mod ranging;
+mod rng;
+mod search;
+mod state_machine;
+mod timebase;
+mod tone;
+
+use std::process;
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+
+use rng::Rng;
+
+const GEOMETRY: geometry::ArrayGeometry = geometry::ArrayGeometry {
+    microphones: &[
+        geometry::Point::new(-0.25, 0.0),
+        geometry::Point::new(0.25, 0.0),
+        geometry::Point::new(0.0, 0.25),
+    ],
+    temperature_c: 20.0,
+    speed_of_sound: None,
+};
+
+// arrival times in ns of a sound emitted at `source`, as seen through channels with the given latencies
+fn arrivals(source: &geometry::Point, delays: &[i64], jitter_ns: f64, noise: &mut Rng) -> Vec<i64> {
+    arrivals_at(&GEOMETRY, source, delays, jitter_ns, noise)
+}
+
+fn arrivals_at(
+    geometry: &geometry::ArrayGeometry,
+    source: &geometry::Point,
+    delays: &[i64],
+    jitter_ns: f64,
+    noise: &mut Rng,
+) -> Vec<i64> {
+    geometry
+        .microphones
+        .iter()
+        .zip(delays.iter())
+        .map(|(microphone, delay)| {
+            let flight = source.distance(microphone) / geometry.speed_of_sound() * 1e9;
+            1_000_000_000 + flight as i64 + delay + noise.gaussian(jitter_ns) as i64
+        })
+        .collect()
+}
+
+const DELAYS: [i64; 3] = [0, 35_000, -12_000];
+
+fn calibration_bisector() -> Result<()> {
+    let mut noise = Rng::new(1);
+    let mut collector = calibration::Collector::new(&[calibration::Source::Bisector], 20);
+    while !collector.done() {
+        let source = geometry::Point::new(0.0, 1.0 + noise.uniform() as f32 * 2.0);
+        collector.push(&arrivals(&source, &DELAYS, 2_000.0, &mut noise));
+    }
+    let solution = calibration::solve(&GEOMETRY, collector.events())?;
+    let error = solution.calibration.delays[1] - DELAYS[1];
+    ensure!(error.abs() < 2_000, "delay of b off by {} ns", error);
+    ensure!(
+        solution.samples[2] == 0,
+        "c calibrated without known positions"
+    );
+    Ok(())
+}
+
+fn calibration_known_positions() -> Result<()> {
+    let sources = [
+        calibration::Source::At(geometry::Point::new(-1.0, 1.5)),
+        calibration::Source::At(geometry::Point::new(0.0, 2.0)),
+        calibration::Source::At(geometry::Point::new(1.5, 1.0)),
+    ];
+    let mut noise = Rng::new(2);
+    let mut collector = calibration::Collector::new(&sources, 10);
+    while let Some(calibration::Source::At(source)) = collector.current_source() {
+        collector.push(&arrivals(&source, &DELAYS, 2_000.0, &mut noise));
+    }
+    let solution = calibration::solve(&GEOMETRY, collector.events())?;
+    for (i, (delay, expected)) in solution
+        .calibration
+        .delays
+        .iter()
+        .zip(DELAYS.iter())
+        .enumerate()
+    {
+        let error = delay - expected;
+        ensure!(
+            error.abs() < 2_000,
+            "delay of channel {} off by {} ns",
+            i,
+            error
+        );
+    }
+    let restored = calibration::Calibration::from_bytes(&solution.calibration.to_bytes())?;
+    ensure!(
+        restored == solution.calibration,
+        "calibration does not survive storage"
+    );
+    Ok(())
+}
+
+// a car moving at a constant rate, with jitter and the occasional echo
+fn filter_ramp_with_echoes() -> Result<()> {
+    let mut noise = Rng::new(3);
+    let mut filter = filter::MeasurementFilter::new(Default::default());
+    let rate = 0.1;
+    for k in 0..100 {
+        let time = k as f64 * 0.4;
+        let truth = -0.5 + rate * time as f32;
+        let echo = k % 9 == 8;
+        let measured = truth + noise.gaussian(0.005) as f32 + if echo { 0.5 } else { 0.0 };
+        let estimate = filter.update(time, measured);
+        if k < 20 {
+            continue;
+        }
+        ensure!(
+            estimate.outlier == echo,
+            "echo {} at {} s flagged {}",
+            echo,
+            time,
+            estimate.outlier
+        );
+        ensure!(
+            (estimate.value - truth).abs() < 0.03,
+            "estimate {} for {} at {} s",
+            estimate.value,
+            truth,
+            time
+        );
+        ensure!(
+            (estimate.rate - rate).abs() < 0.05,
+            "rate {} at {} s",
+            estimate.rate,
+            time
+        );
+    }
+    Ok(())
+}
+
+fn confidence_clean_and_marginal() -> Result<()> {
+    let mut noise = Rng::new(4);
+    let range_time = Duration::from_millis(50);
+    let pulses = [Duration::from_millis(200); 3];
+    let clean = arrivals(
+        &geometry::Point::new(0.7, 1.8),
+        &[0; 3],
+        2_000.0,
+        &mut noise,
+    );
+    let confidence = quality::confidence(&GEOMETRY, &clean, &pulses, range_time);
+    ensure!(confidence > 0.9, "clean hit scored {}", confidence);
+
+    // c heard something else 5 ms later than a real sound could arrive
+    let mut late = clean.clone();
+    late[2] += 5_000_000;
+    let confidence = quality::confidence(&GEOMETRY, &late, &pulses, range_time);
+    ensure!(
+        confidence < 0.1,
+        "impossible arrivals scored {}",
+        confidence
+    );
+
+    let chattering = [
+        Duration::from_millis(200),
+        Duration::from_millis(20),
+        Duration::from_millis(190),
+    ];
+    let confidence = quality::confidence(&GEOMETRY, &clean, &chattering, range_time);
+    ensure!(confidence < 0.2, "chattering channel scored {}", confidence);
+    Ok(())
+}
+
+const RING: &[geometry::Point] = &[
+    geometry::Point::new(-0.25, 0.0),
+    geometry::Point::new(0.25, 0.0),
+    geometry::Point::new(0.0, 0.25),
+    geometry::Point::new(0.0, -0.25),
+];
+
+// four receivers on a ring hear the car all around the tracker
+fn direction_all_around() -> Result<()> {
+    let ring = geometry::ArrayGeometry {
+        microphones: RING,
+        ..GEOMETRY
+    };
+    let mut noise = Rng::new(5);
+    for step in 0..24 {
+        let bearing = -std::f32::consts::PI + step as f32 * std::f32::consts::PI / 12.0;
+        let source = geometry::Point::new(5.0 * bearing.sin(), 5.0 * bearing.cos());
+        let arrivals = arrivals_at(&ring, &source, &[0; 4], 2_000.0, &mut noise);
+        let estimate = ring.direction(&arrivals).unwrap();
+        let error = (estimate - bearing + std::f32::consts::PI)
+            .rem_euclid(2.0 * std::f32::consts::PI)
+            - std::f32::consts::PI;
+        ensure!(
+            error.abs() < 5f32.to_radians(),
+            "bearing {} estimated as {}",
+            bearing.to_degrees(),
+            estimate.to_degrees()
+        );
+    }
+    Ok(())
+}
+
+// a band limited burst of sound, evaluated at any (fractional) time so that it can be delayed exactly
+struct Burst {
+    // (frequency in Hz, phase)
+    tones: Vec<(f32, f32)>,
+    start: f32,
+    length: f32,
+}
+
+impl Burst {
+    fn new(noise: &mut Rng, start: f32, length: f32) -> Self {
+        let tones = (0..40)
+            .map(|_| {
+                (
+                    500.0 + noise.uniform() as f32 * 7_500.0,
+                    noise.uniform() as f32 * 2.0 * std::f32::consts::PI,
+                )
+            })
+            .collect();
+        Self {
+            tones,
+            start,
+            length,
+        }
+    }
+
+    fn at(&self, time: f32) -> f32 {
+        let t = time - self.start;
+        if t < 0.0 || t > self.length {
+            return 0.0;
+        }
+        let envelope = (std::f32::consts::PI * t / self.length).sin();
+        envelope
+            * self
+                .tones
+                .iter()
+                .map(|(frequency, phase)| {
+                    (2.0 * std::f32::consts::PI * frequency * t + phase).sin()
+                })
+                .sum::<f32>()
+            / 6.0
+    }
+}
+
+// samples of the burst delayed by `delay` seconds, with an echo and background noise
+fn capture(burst: &Burst, delay: f32, rate: f32, samples: usize, noise: &mut Rng) -> Vec<f32> {
+    (0..samples)
+        .map(|k| {
+            let time = k as f32 / rate - delay;
+            burst.at(time) + 0.3 * burst.at(time - 0.0031) + noise.gaussian(0.05) as f32
+        })
+        .collect()
+}
+
+fn gcc_phat_fractional_delays() -> Result<()> {
+    let rate = 32_000.0;
+    let mut noise = Rng::new(6);
+    for delay_samples in &[-13.6f32, -4.25, 0.0, 0.4, 7.5, 20.8] {
+        let burst = Burst::new(&mut noise, 0.004, 0.02);
+        let b = capture(&burst, 0.0, rate, 1024, &mut noise);
+        let a = capture(&burst, delay_samples / rate, rate, 1024, &mut noise);
+        let estimate = dsp::gcc_phat(&a, &b, 48)
+            .ok_or_else(|| anyhow::anyhow!("no correlation peak for {}", delay_samples))?;
+        ensure!(
+            (estimate - delay_samples).abs() < 0.25,
+            "delay of {} samples estimated as {}",
+            delay_samples,
+            estimate
+        );
+    }
+    Ok(())
+}
+
+fn ring_buffer_latest() -> Result<()> {
+    let mut ring = dsp::RingBuffer::new(8);
+    for k in 0..5 {
+        ring.push(k as f32);
+    }
+    ensure!(
+        ring.latest(3) == vec![2.0, 3.0, 4.0],
+        "{:?}",
+        ring.latest(3)
+    );
+    for k in 5..13 {
+        ring.push(k as f32);
+    }
+    ensure!(ring.len() == 8, "len {}", ring.len());
+    ensure!(
+        ring.latest(4) == vec![9.0, 10.0, 11.0, 12.0],
+        "{:?}",
+        ring.latest(4)
+    );
+    Ok(())
+}
+
+fn goertzel_buzzer_against_noise() -> Result<()> {
+    let rate = 32_000.0;
+    let config = tone::ToneConfig::default();
+    let mut noise = Rng::new(7);
+    // the buzzer 80 Hz off its nominal frequency, in some background noise
+    let buzzer: Vec<f32> = (0..512)
+        .map(|k| {
+            0.4 * (2.0 * std::f32::consts::PI * 2780.0 * k as f32 / rate).sin()
+                + noise.gaussian(0.05) as f32
+        })
+        .collect();
+    let ratio = tone::tone_ratio(&buzzer, &config, rate);
+    ensure!(ratio > 0.6, "buzzer scored {}", ratio);
+
+    let clap: Vec<f32> = (0..512).map(|_| noise.gaussian(0.4) as f32).collect();
+    let ratio = tone::tone_ratio(&clap, &config, rate);
+    ensure!(ratio < config.min_tone_ratio, "clap scored {}", ratio);
+
+    let voice: Vec<f32> = (0..512)
+        .map(|k| 0.4 * (2.0 * std::f32::consts::PI * 440.0 * k as f32 / rate).sin())
+        .collect();
+    let ratio = tone::tone_ratio(&voice, &config, rate);
+    ensure!(ratio < config.min_tone_ratio, "440 Hz scored {}", ratio);
+    Ok(())
+}
+
+fn cadence_beeps_and_claps() -> Result<()> {
+    let config = tone::ToneConfig::default();
+    let mut matcher = tone::CadenceMatcher::new(config);
+    let beep = [config.beep_on; 3];
+    let clap = [Duration::from_millis(30); 3];
+
+    ensure!(!matcher.accept(0.0, &beep), "first beep has no cadence yet");
+    ensure!(matcher.accept(0.41, &beep), "second beep rejected");
+    // the same detection is reported again
+    ensure!(matcher.accept(0.41, &beep), "repeated detection rejected");
+    ensure!(
+        !matcher.accept(0.57, &beep),
+        "beep off the cadence accepted"
+    );
+    // one beep missed
+    ensure!(
+        matcher.accept(1.2, &beep),
+        "beep after a missed one rejected"
+    );
+    ensure!(!matcher.accept(1.6, &clap), "short pulses accepted");
+    ensure!(
+        !matcher.accept(5.0, &beep),
+        "beep after a long silence accepted"
+    );
+    ensure!(matcher.accept(5.4, &[]), "unknown pulse widths rejected");
+    Ok(())
+}
+
+fn beep_patterns() -> Result<()> {
+    use beep_pattern::{Code, BARKER_13};
+
+    let square = Code::Square {
+        on: Duration::from_millis(200),
+    }
+    .pattern(2700);
+    ensure!(
+        beep_pattern::duration(&square) == Duration::from_millis(200),
+        "square lasts {:?}",
+        beep_pattern::duration(&square)
+    );
+
+    let chirp = Code::Chirp {
+        from_hz: 2000,
+        to_hz: 4000,
+        duration: Duration::from_millis(100),
+        steps: 10,
+    }
+    .pattern(0);
+    ensure!(chirp.len() == 10, "{} chirp steps", chirp.len());
+    ensure!(
+        chirp.first().unwrap().frequency_hz == 2100 && chirp.last().unwrap().frequency_hz == 3900,
+        "chirp from {} to {} Hz",
+        chirp.first().unwrap().frequency_hz,
+        chirp.last().unwrap().frequency_hz
+    );
+
+    // the sidelobes of the autocorrelation of a Barker code are at most 1
+    for lag in 1..BARKER_13.len() {
+        let sidelobe: i32 = (0..BARKER_13.len() - lag)
+            .map(|k| (BARKER_13[k] * BARKER_13[k + lag]) as i32)
+            .sum();
+        ensure!(sidelobe.abs() <= 1, "sidelobe {} at lag {}", sidelobe, lag);
+    }
+
+    let id = Code::CarId {
+        id: 0b1010_0001,
+        low_hz: 2400,
+        high_hz: 3000,
+        chip: Duration::from_millis(5),
+    }
+    .pattern(0);
+    ensure!(id.len() == 21, "{} id chips", id.len());
+    let bits: Vec<u32> = id[13..].iter().map(|chip| chip.frequency_hz).collect();
+    ensure!(
+        bits == vec![3000, 2400, 3000, 2400, 2400, 2400, 2400, 3000],
+        "id bits {:?}",
+        bits
+    );
+
+    // what the tracker hears of the plain beep is in the band of the buzzer
+    let rate = 32_000.0;
+    let samples = beep_pattern::render(&square, rate);
+    ensure!(samples.len() == 6400, "{} samples", samples.len());
+    let config = tone::ToneConfig::default();
+    let ratio = tone::tone_ratio(&samples[..512], &config, rate);
+    ensure!(ratio > 0.6, "rendered beep scored {}", ratio);
+    Ok(())
+}
+
+// the pattern as heard `delay` samples late (in 1/16 sample steps), with an echo and background noise
+fn hear(
+    pattern: &[beep_pattern::Segment],
+    rate: f32,
+    delay: f32,
+    echo: (f32, f32),
+    samples: usize,
+    noise: &mut Rng,
+) -> Vec<f32> {
+    const OVERSAMPLING: usize = 16;
+    let fine = beep_pattern::render(pattern, rate * OVERSAMPLING as f32);
+    let at = |position: f32| -> f32 {
+        let index = (position * OVERSAMPLING as f32).round();
+        if index < 0.0 {
+            return 0.0;
+        }
+        fine.get(index as usize).copied().unwrap_or(0.0)
+    };
+    let (echo_delay, echo_gain) = echo;
+    (0..samples)
+        .map(|k| {
+            let position = k as f32 - delay;
+            0.2 * at(position)
+                + 0.2 * echo_gain * at(position - echo_delay)
+                + noise.gaussian(0.05) as f32
+        })
+        .collect()
+}
+
+fn matched_filter_chirp_with_echoes() -> Result<()> {
+    let rate = 32_000.0;
+    let mut noise = Rng::new(8);
+    let pattern = beep_pattern::CODED.pattern(0);
+    let template = beep_pattern::render(&pattern, rate);
+    for (delay, echo_gain) in &[(100.0f32, 0.5f32), (137.3, 0.8), (211.6, 1.3), (90.8, 0.0)] {
+        // the echo of a wall about a metre further away
+        let heard = hear(&pattern, rate, *delay, (96.0, *echo_gain), 1024, &mut noise);
+        let estimate = dsp::detect(&heard, &template, 0.5, 0.5)
+            .ok_or_else(|| anyhow::anyhow!("chirp at {} not detected", delay))?;
+        ensure!(
+            (estimate - delay).abs() < 0.25,
+            "chirp at {} samples with an echo of {} estimated at {}",
+            delay,
+            echo_gain,
+            estimate
+        );
+    }
+    Ok(())
+}
+
+fn matched_filter_rejects_other_sounds() -> Result<()> {
+    let rate = 32_000.0;
+    let mut noise = Rng::new(9);
+    let template = beep_pattern::render(&beep_pattern::CODED.pattern(0), rate);
+
+    let steady = hear(
+        &beep_pattern::Code::Square {
+            on: Duration::from_millis(30),
+        }
+        .pattern(2700),
+        rate,
+        0.0,
+        (0.0, 0.0),
+        1024,
+        &mut noise,
+    );
+    ensure!(
+        dsp::detect(&steady, &template, 0.5, 0.5).is_none(),
+        "plain beep detected as the chirp"
+    );
+
+    let mut burst_noise = Rng::new(10);
+    let burst = Burst::new(&mut burst_noise, 0.005, 0.01);
+    let clap = capture(&burst, 0.0, rate, 1024, &mut noise);
+    ensure!(
+        dsp::detect(&clap, &template, 0.5, 0.5).is_none(),
+        "clap detected as the chirp"
+    );
+    Ok(())
+}
+
+fn physical_delays() -> Result<()> {
+    let mut noise = Rng::new(11);
+    let limit = GEOMETRY.max_offset(0, 1) as i64;
+    // a source far off to the left, along the baseline of a and b
+    let left = arrivals(
+        &geometry::Point::new(-20.0, 0.0),
+        &[0, 0, 0],
+        0.0,
+        &mut noise,
+    );
+    ensure!(
+        GEOMETRY.is_physical(&left, 0),
+        "arrivals from the left {:?} rejected",
+        left
+    );
+    ensure!(
+        !GEOMETRY.is_physical(&[0, limit + 100_000, limit / 2], 0),
+        "echo on b accepted"
+    );
+    ensure!(
+        GEOMETRY.is_physical(&[0, limit + 100_000, limit / 2], 200_000),
+        "receiver jitter within the margin rejected"
+    );
+    Ok(())
+}
+
+// the edges of a beep starting at `start` µs, heard from `source`; receiver b also hears an echo
+fn beep_edges(start: u64, source: &geometry::Point, noise: &mut Rng) -> Vec<event_log::Record> {
+    let mut edges = Vec::new();
+    for (channel, arrival) in arrivals(source, &[0, 0, 0], 0.0, noise).iter().enumerate() {
+        let time = start + (*arrival / 1_000) as u64;
+        let mut edge = |time: u64, level: bool| {
+            edges.push(event_log::Record::Edge {
+                time,
+                channel: channel as u8,
+                level,
+            })
+        };
+        edge(time, true);
+        if channel == 1 {
+            edge(time + 2_000, false);
+            edge(time + 3_000, true);
+        }
+        edge(time + 200_000, false);
+    }
+    edges.sort_by_key(|edge| match edge {
+        event_log::Record::Edge { time, .. } => *time,
+        _ => 0,
+    });
+    edges
+}
+
+fn record_and_replay() -> Result<()> {
+    let mut noise = Rng::new(12);
+    let header = event_log::Header {
+        microphones: GEOMETRY.microphones.to_vec(),
+        speed_of_sound: GEOMETRY.speed_of_sound(),
+        delays: vec![0; 3],
+        detector: detection::DetectorConfig {
+            valid_time: Duration::from_millis(100),
+            sound_range_time: Duration::from_millis(50),
+            blanking_time: Duration::from_millis(300),
+            check_physical_delays: true,
+            physical_delay_margin: Duration::from_micros(200),
+        },
+    };
+    let source = geometry::Point::new(1.0, 2.0);
+    let mut records = vec![event_log::Record::Header(header)];
+    records.extend(beep_edges(1_000_000, &source, &mut noise));
+    records.extend(beep_edges(1_400_000, &source, &mut noise));
+    // a clap right next to receiver c, heard 5 ms later than by the others
+    records.extend(beep_edges(
+        1_800_000,
+        &geometry::Point::new(0.0, 1.0),
+        &mut noise,
+    ));
+    if let Some(event_log::Record::Edge { time, .. }) = records.iter_mut().rev().find(|record| {
+        matches!(
+            record,
+            event_log::Record::Edge {
+                channel: 2,
+                level: true,
+                ..
+            }
+        )
+    }) {
+        *time += 5_000;
+    }
+
+    let replayed = event_log::replay(&records, Duration::from_micros(100))?;
+    ensure!(
+        replayed.len() == 2,
+        "{} measurements replayed: {:?}",
+        replayed.len(),
+        replayed
+    );
+    let expected =
+        source.distance(&GEOMETRY.microphones[0]) - source.distance(&GEOMETRY.microphones[1]);
+    for (time, measurement) in &replayed {
+        ensure!(
+            (measurement.path_difference - expected).abs() < 0.005,
+            "path difference {} at {} µs, expected {} despite the echo",
+            measurement.path_difference,
+            time,
+            expected
+        );
+    }
+
+    // what the tracker would have logged survives the round trip through the bytes
+    records.extend(
+        replayed
+            .iter()
+            .map(|(time, measurement)| event_log::Record::Measurement {
+                time: *time,
+                measurement: *measurement,
+            }),
+    );
+    let bytes: Vec<u8> = records
+        .iter()
+        .flat_map(|record| record.to_bytes())
+        .collect();
+    let parsed = event_log::parse(&bytes)?;
+    ensure!(parsed == records, "records do not survive the round trip");
+    let again = event_log::replay(&parsed, Duration::from_micros(100))?;
+    let recorded = event_log::recorded(&parsed);
+    ensure!(
+        again.len() == recorded.len()
+            && again
+                .iter()
+                .zip(recorded.iter())
+                .all(|(a, b)| a.0 == b.0 && event_log::same(&b.1, &a.1)),
+        "replay differs from the recording"
+    );
+    // a log cut off in the middle of a record
+    ensure!(
+        event_log::parse(&bytes[..bytes.len() - 5])?.len() == records.len() - 1,
+        "truncated record not dropped"
+    );
+    Ok(())
+}
+
+fn flight_recorder_ring() -> Result<()> {
+    let mut recorder = flight_recorder::FlightRecorder::new(5);
+    for k in 0..8u64 {
+        recorder.push(flight_recorder::Tick {
+            time: k * 100_000,
+            offset: -(k as i64) * 1_000,
+            filtered: 0.01 * k as f32,
+            predicted: 0.01 * k as f32 - 0.002,
+            age_ms: 20,
+            p: 0.04 * k as f32,
+            i: 0.0,
+            d: -0.5,
+            output: 0.04 * k as f32 - 0.5,
+            turn: 0.01,
+            heading: -0.1 * k as f32,
+            obstacle: 1.5,
+            battery: 7.4 - 0.01 * k as f32,
+            duty1: 10 * k as i32 - 127,
+            duty2: 127 - 10 * k as i32,
+            goal: k as u32 / 3,
+        });
+    }
+    let ticks = recorder.ticks();
+    let times: Vec<u64> = ticks.iter().map(|tick| tick.time).collect();
+    ensure!(
+        times == vec![300_000, 400_000, 500_000, 600_000, 700_000],
+        "ring keeps {:?}",
+        times
+    );
+    let bytes = recorder.to_bytes();
+    ensure!(
+        flight_recorder::decode(&bytes)? == ticks,
+        "ticks do not survive the binary dump"
+    );
+    ensure!(
+        flight_recorder::decode(&bytes[..bytes.len() - 1])?.len() == 4,
+        "cut off tick not dropped"
+    );
+    ensure!(
+        recorder.to_csv().lines().count() == 6,
+        "CSV of {} lines",
+        recorder.to_csv().lines().count()
+    );
+    Ok(())
+}
+
+fn car_follows_confident_data() -> Result<()> {
+    let config = car_control::CarConfig::default();
+    let mut control = car_control::CarControl::new(config);
+    let now = std::time::Instant::now();
+    let data = |filtered: f32, confidence: f32, sequence: u32| protocol::ControlData {
+        filtered,
+        confidence,
+        sequence,
+        ..protocol::ControlData::empty()
+    };
+
+    control.arm();
+    control.set_link(true);
+    let command = control.tick(now);
+    ensure!(
+        command.output == 0.0 && command.predicted.is_none(),
+        "car drives before hearing from the tracker: {:?}",
+        command
+    );
+    ensure!(
+        !control.receive(data(0.1, config.min_confidence / 2.0, 1), now),
+        "weak measurement followed"
+    );
+    control.tick(now);
+    ensure!(
+        control.state() == car_control::State::Searching,
+        "weak measurement started the run"
+    );
+    ensure!(
+        control.receive(data(0.1, 1.0, 2), now),
+        "clean measurement ignored"
+    );
+    // half confident: halfway from 0.1 to 0.0
+    control.receive(data(0.0, 0.5, 3), now);
+    let filtered = control.data().filtered;
+    ensure!(
+        (filtered - 0.05).abs() < 1e-6,
+        "blended filtered {}",
+        filtered
+    );
+    // the same measurement sent again does not move the car further
+    ensure!(
+        !control.receive(data(0.0, 0.5, 3), now) && control.data().filtered == filtered,
+        "repeated measurement blended in"
+    );
+    let command = control.tick(now);
+    ensure!(
+        control.state() == car_control::State::Approaching,
+        "run not started"
+    );
+    ensure!(
+        command.output < 0.0
+            && (command.output - command.p).abs() < 1e-9
+            && command.predicted == Some(filtered as f64),
+        "PID output {:?} for a path difference of {}",
+        command,
+        filtered
+    );
+    control.finish();
+    ensure!(
+        control.tick(now) == car_control::Command::default(),
+        "car moves when done"
+    );
+    Ok(())
+}
+
+fn scripted_offsets() -> Result<()> {
+    let script = offset_script::Script::parse(
+        "# warm up
+        1   constant 0.1
+        2   step 0.1 -0.1   confidence=0.5
+        1   drop
+        2   sine 0.0 0.2 2.0
+        ",
+    )?;
+    ensure!(
+        script.duration() == Duration::from_secs(6),
+        "script lasts {:?}",
+        script.duration()
+    );
+    let mut rng = Rng::new(1);
+    let at = |seconds: f32, rng: &mut Rng| script.sample(Duration::from_secs_f32(seconds), rng);
+    ensure!(
+        at(0.5, &mut rng).map(|sample| sample.path_difference) == Some(0.1),
+        "constant"
+    );
+    let step = at(2.5, &mut rng);
+    ensure!(
+        step == Some(offset_script::Sample {
+            path_difference: -0.1,
+            confidence: 0.5
+        }),
+        "second half of the step {:?}",
+        step
+    );
+    ensure!(at(3.5, &mut rng).is_none(), "link not down");
+    let peak = at(4.5, &mut rng).map(|sample| sample.path_difference);
+    ensure!(
+        matches!(peak, Some(value) if (value - 0.2).abs() < 1e-5),
+        "sine peak {:?}",
+        peak
+    );
+    ensure!(at(6.0, &mut rng).is_none(), "sample after the end");
+
+    for bad in &["1 constant", "-1 constant 0.1", "1 wobble 0.1", "# nothing"] {
+        ensure!(
+            offset_script::Script::parse(bad).is_err(),
+            "{:?} accepted",
+            bad
+        );
+    }
+
+    // the car logic against the script, one tick per 0.1 s
+    let mut config = car_control::CarConfig::default();
+    config.estimator.horizon = std::time::Duration::from_millis(500);
+    let mut control = car_control::CarControl::new(config);
+    control.arm();
+    control.set_link(true);
+    let start = std::time::Instant::now();
+    let mut outputs = Vec::new();
+    for tick in 0..60 {
+        let now = start + std::time::Duration::from_millis(100 * tick);
+        if let Some(sample) = at(tick as f32 * 0.1, &mut rng) {
+            control.receive(
+                protocol::ControlData {
+                    filtered: sample.path_difference,
+                    confidence: sample.confidence,
+                    sequence: tick as u32 + 1,
+                    ..protocol::ControlData::empty()
+                },
+                now,
+            );
+        }
+        outputs.push(control.tick(now).output);
+    }
+    ensure!(outputs[5] < 0.0, "car does not steer back from 0.1 m");
+    ensure!(outputs[25] > 0.0, "car does not steer back from -0.1 m");
+    // early in the drop the car goes on with its prediction, then stops
+    ensure!(
+        outputs[31] != 0.0,
+        "car stopped as soon as the link went down"
+    );
+    ensure!(
+        outputs[35] == 0.0,
+        "car still moving 0.6 s after the last data"
+    );
+    Ok(())
+}
+
+// what the drivers did to their pins
+#[derive(Clone, Default)]
+struct Pins(std::rc::Rc<std::cell::RefCell<Vec<i64>>>);
+
+struct FakePwm(Pins, usize);
+
+impl embedded_hal::PwmPin for FakePwm {
+    type Duty = u32;
+
+    fn disable(&mut self) {}
+
+    fn enable(&mut self) {}
+
+    fn get_duty(&self) -> u32 {
+        (self.0).0.borrow()[self.1] as u32
+    }
+
+    fn get_max_duty(&self) -> u32 {
+        1000
+    }
+
+    fn set_duty(&mut self, duty: u32) {
+        (self.0).0.borrow_mut()[self.1] = duty as i64;
+    }
+}
+
+struct FakeOutput(Pins, usize);
+
+impl embedded_hal::digital::v2::OutputPin for FakeOutput {
+    type Error = std::convert::Infallible;
+
+    fn set_low(&mut self) -> std::result::Result<(), Self::Error> {
+        (self.0).0.borrow_mut()[self.1] = 0;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> std::result::Result<(), Self::Error> {
+        (self.0).0.borrow_mut()[self.1] = 1;
+        Ok(())
+    }
+}
+
+fn motor_drivers() -> Result<()> {
+    use motor::MotorDriver;
+
+    let pins = Pins::default();
+    pins.0.borrow_mut().resize(3, -1);
+    let check = |what: &str, expected: &[i64]| -> Result<()> {
+        let actual = pins.0.borrow().clone();
+        ensure!(
+            actual == expected,
+            "{}: pins {:?}, expected {:?}",
+            what,
+            actual,
+            expected
+        );
+        Ok(())
+    };
+
+    let mut bridge = motor::HBridge::new(FakePwm(pins.clone(), 0), FakePwm(pins.clone(), 1));
+    ensure!(bridge.set_output(0.5)? == 500, "half output");
+    check("h-bridge forward", &[500, 0, -1])?;
+    ensure!(bridge.set_output(-2.0)? == -1000, "output not clamped");
+    check("h-bridge backward", &[0, 1000, -1])?;
+    bridge.brake()?;
+    check("h-bridge brake", &[1000, 1000, -1])?;
+    bridge.coast()?;
+    check("h-bridge coast", &[0, 0, -1])?;
+
+    let mut dir = motor::PwmDir::new(FakePwm(pins.clone(), 0), FakeOutput(pins.clone(), 1));
+    dir.set_duty(300)?;
+    check("pwm+dir forward", &[300, 1, -1])?;
+    dir.set_duty(-5000)?;
+    check("pwm+dir backward", &[1000, 0, -1])?;
+    dir.brake()?;
+    check("pwm+dir brake", &[0, 0, -1])?;
+
+    let mut l298n = motor::PwmInputs::new(
+        FakePwm(pins.clone(), 0),
+        FakeOutput(pins.clone(), 1),
+        FakeOutput(pins.clone(), 2),
+    );
+    l298n.set_duty(-250)?;
+    check("l298n backward", &[250, 0, 1])?;
+    l298n.set_duty(250)?;
+    check("l298n forward", &[250, 1, 0])?;
+    l298n.brake()?;
+    check("l298n brake", &[1000, 0, 0])?;
+    l298n.coast()?;
+    check("l298n coast", &[0, 0, 0])?;
+
+    let mut mock = motor::MockMotor::new(100);
+    mock.set_output(0.25)?;
+    mock.brake()?;
+    ensure!(
+        mock.commands == [motor::MotorCommand::Duty(25), motor::MotorCommand::Brake],
+        "mock commands {:?}",
+        mock.commands
+    );
+    Ok(())
+}
+
+fn motor_output_stage() -> Result<()> {
+    use motor::{MotorCommand, OutputConfig, OutputStage, Stop};
+
+    let tick = std::time::Duration::from_millis(100);
+    let config = OutputConfig {
+        max_slew: 5.0,
+        min_duty: 0.2,
+        trim: 0.9,
+        stop: Stop::Brake,
+    };
+    let mut stage = OutputStage::new(motor::MockMotor::new(1000), config);
+
+    // 0.5 per tick towards full forward
+    let duties = (0..3)
+        .map(|_| stage.drive(1.0, tick))
+        .collect::<Result<Vec<_>>>()?;
+    // 0.2 + 0.8 * 0.9 * output
+    ensure!(
+        duties == [560, 920, 920],
+        "accelerating duties {:?}",
+        duties
+    );
+
+    // a full reversal passes through zero without braking
+    let duties = (0..4)
+        .map(|_| stage.drive(-1.0, tick))
+        .collect::<Result<Vec<_>>>()?;
+    ensure!(
+        duties == [560, 0, -560, -920],
+        "reversing duties {:?}",
+        duties
+    );
+    ensure!(
+        !stage.motor().commands.contains(&MotorCommand::Brake),
+        "braked while reversing"
+    );
+
+    // tiny outputs still turn the motor
+    let mut stage = OutputStage::new(motor::MockMotor::new(1000), config);
+    let duty = stage.drive(0.01, tick)?;
+    ensure!((200..=210).contains(&duty), "deadband duty {}", duty);
+
+    stage.drive(0.0, tick)?;
+    ensure!(
+        stage.motor().last() == Some(MotorCommand::Brake),
+        "stopped with {:?}",
+        stage.motor().last()
+    );
+    let mut stage = OutputStage::new(
+        motor::MockMotor::new(1000),
+        OutputConfig {
+            stop: Stop::Coast,
+            ..config
+        },
+    );
+    stage.drive(0.0, tick)?;
+    ensure!(
+        stage.motor().last() == Some(MotorCommand::Coast),
+        "stopped with {:?}",
+        stage.motor().last()
+    );
+    Ok(())
+}
+
+fn wheel_odometry() -> Result<()> {
+    use odometry::{Encoder, MockEncoder, Odometry, OdometryConfig};
+
+    let config = OdometryConfig {
+        counts_per_revolution: 1000.0,
+        wheel_diameter: 0.1,
+        track_width: 0.2,
+    };
+    let counts = |metres: f64| (metres / config.metres_per_count()).round() as i64;
+    let tick = std::time::Duration::from_millis(100);
+    let (mut left, mut right) = (MockEncoder::default(), MockEncoder::default());
+    let mut odometry = Odometry::new(config);
+    odometry.update(left.count()?, right.count()?, tick);
+
+    // 1 m straight ahead at 0.5 m/s
+    for _ in 0..20 {
+        left.turn(counts(0.05));
+        right.turn(counts(0.05));
+        odometry.update(left.count()?, right.count()?, tick);
+    }
+    let pose = odometry.pose();
+    ensure!(
+        (pose.x - 1.0).abs() < 1e-3 && pose.y.abs() < 1e-9 && pose.heading == 0.0,
+        "straight ahead to {:?}",
+        pose
+    );
+    let (left_speed, right_speed) = odometry.speeds();
+    ensure!(
+        (left_speed - 0.5).abs() < 1e-2 && (right_speed - 0.5).abs() < 1e-2,
+        "wheel speeds {:?}",
+        odometry.speeds()
+    );
+
+    // a quarter turn to the left on the spot
+    let arc = std::f64::consts::FRAC_PI_2 * config.track_width / 2.0;
+    for _ in 0..10 {
+        left.turn(-counts(arc / 10.0));
+        right.turn(counts(arc / 10.0));
+        odometry.update(left.count()?, right.count()?, tick);
+    }
+    let pose = odometry.pose();
+    ensure!(
+        (pose.x - 1.0).abs() < 1e-3 && (pose.heading - std::f64::consts::FRAC_PI_2).abs() < 1e-2,
+        "turned to {:?}",
+        pose
+    );
+
+    // half a circle of 0.5 m radius to the right ends 1 m further to the right, heading the other way
+    let radius = 0.5;
+    let steps = 200;
+    let angle = std::f64::consts::PI / steps as f64;
+    for _ in 0..steps {
+        left.turn(counts((radius + config.track_width / 2.0) * angle));
+        right.turn(counts((radius - config.track_width / 2.0) * angle));
+        odometry.update(left.count()?, right.count()?, tick);
+    }
+    let pose = odometry.pose();
+    ensure!(
+        (pose.x - 2.0).abs() < 0.02
+            && pose.y.abs() < 0.02
+            && (pose.heading + std::f64::consts::FRAC_PI_2).abs() < 0.02,
+        "half circle to {:?}",
+        pose
+    );
+    Ok(())
+}
+
+fn wheel_speed_control() -> Result<()> {
+    use odometry::{SpeedConfig, SpeedControl};
+
+    let config = SpeedConfig {
+        max_speed: 1.0,
+        kp: 0.5,
+        ki: 0.1,
+    };
+    // a wheel slower than expected, following its duty in a few ticks
+    let wheel = |speed: f64, output: f64| speed + 0.5 * (0.6 * output - speed);
+
+    let target = 0.4;
+    let mut open_loop = 0.0;
+    let mut closed_loop = 0.0;
+    let mut control = SpeedControl::new(config);
+    for _ in 0..100 {
+        open_loop = wheel(open_loop, target / config.max_speed);
+        closed_loop = wheel(closed_loop, control.next(target, closed_loop));
+    }
+    ensure!(
+        (open_loop - 0.24).abs() < 1e-3,
+        "open loop wheel at {}",
+        open_loop
+    );
+    ensure!(
+        (closed_loop - target).abs() < 0.01,
+        "closed loop wheel at {} instead of {}",
+        closed_loop,
+        target
+    );
+    ensure!(control.next(0.0, closed_loop) == 0.0, "moves when stopped");
+    Ok(())
+}
+
+fn estimator_between_updates() -> Result<()> {
+    use estimator::{Estimator, EstimatorConfig};
+    use std::time::Duration;
+
+    let config = EstimatorConfig::default();
+    let mut estimator = Estimator::new(config);
+    let start = std::time::Instant::now();
+    let tick = Duration::from_millis(100);
+    // path difference change per second at full output, unknown to the estimator
+    let sensitivity = -0.3;
+    // the car weaves, one measurement every 0.4 s
+    let output = |tick: u32| if (tick / 10) % 2 == 0 { 0.8 } else { -0.4 };
+
+    let mut truth = 0.2;
+    // the path difference at the time of each tick
+    let mut history = vec![truth];
+    let mut worst_held: f64 = 0.0;
+    let mut worst_predicted: f64 = 0.0;
+    for i in 0..100 {
+        let now = start + tick * i;
+        if i % 4 == 0 && i > 0 {
+            // measured at the beep, 40 ms before the tracker sent it and the latency earlier
+            let beep = history[i as usize] - 0.5 * (history[i as usize] - history[i as usize - 1]);
+            estimator.measure(i / 4, beep, 0.0, Duration::from_millis(40), now);
+        }
+        if i >= 60 {
+            let predicted = estimator.predict(now).unwrap();
+            let held = history[i as usize - (i as usize % 4)];
+            worst_predicted = worst_predicted.max((predicted - truth).abs());
+            worst_held = worst_held.max((held - truth).abs());
+        }
+        estimator.command(now, output(i));
+        truth += sensitivity * output(i) * tick.as_secs_f64();
+        history.push(truth);
+    }
+    let learnt = estimator.sensitivity().unwrap_or(f64::NAN);
+    ensure!(
+        (learnt - sensitivity).abs() < 0.03,
+        "learnt sensitivity {}",
+        learnt
+    );
+    ensure!(
+        worst_predicted < 0.2 * worst_held,
+        "prediction off by {} m, holding the last measurement by {} m",
+        worst_predicted,
+        worst_held
+    );
+
+    // no prediction far beyond the last measurement, even when it is sent again
+    let later = start + tick * 99 + config.horizon + tick;
+    estimator.measure(24, 0.0, 0.0, Duration::from_millis(40), later - tick);
+    ensure!(
+        estimator.predict(later).is_none(),
+        "prediction beyond the horizon"
+    );
+    Ok(())
+}
+
+// an I2C bus with register files behind its addresses
+#[derive(Default)]
+struct FakeI2c {
+    registers: std::collections::HashMap<(u8, u8), u8>,
+}
+
+impl FakeI2c {
+    fn set(&mut self, address: u8, register: u8, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.registers.insert((address, register + i as u8), *byte);
+        }
+    }
+}
+
+impl embedded_hal::blocking::i2c::Write for FakeI2c {
+    type Error = String;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> std::result::Result<(), String> {
+        self.set(address, bytes[0], &bytes[1..]);
+        Ok(())
+    }
+}
+
+impl embedded_hal::blocking::i2c::WriteRead for FakeI2c {
+    type Error = String;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> std::result::Result<(), String> {
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = *self
+                .registers
+                .get(&(address, bytes[0] + i as u8))
+                .ok_or_else(|| format!("nothing at {:#x}", address))?;
+        }
+        Ok(())
+    }
+}
+
+fn imu_drivers() -> Result<()> {
+    use imu::Imu;
+
+    let close = |a: [f32; 3], b: [f32; 3], tolerance: f32| {
+        a.iter()
+            .zip(b.iter())
+            .all(|(a, b)| (a - b).abs() < tolerance)
+    };
+
+    // 1 g down z, 65.5 per degree per second
+    let mut bus = FakeI2c::default();
+    bus.set(imu::MPU6050_ADDRESS, 0x75, &[0x68]);
+    bus.set(
+        imu::MPU6050_ADDRESS,
+        0x3b,
+        &[0, 0, 0, 0, 0x40, 0, 0, 0, 0, 131, 0, 0, 0xfe, 0xfd],
+    );
+    let sample = imu::Mpu6050::new(bus, imu::MPU6050_ADDRESS)?.read()?;
+    let degrees = std::f32::consts::PI / 180.0;
+    ensure!(
+        close(sample.accel, [0.0, 0.0, 9.80665], 1e-3),
+        "MPU6050 accelerometer {:?}",
+        sample.accel
+    );
+    ensure!(
+        close(sample.gyro, [2.0 * degrees, 0.0, -4.0 * degrees], 1e-3),
+        "MPU6050 gyro {:?}",
+        sample.gyro
+    );
+    ensure!(sample.magnetometer.is_none(), "MPU6050 magnetometer");
+
+    let mut bus = FakeI2c::default();
+    bus.set(imu::MPU6050_ADDRESS, 0x75, &[0x70]);
+    ensure!(
+        imu::Mpu6050::new(bus, imu::MPU6050_ADDRESS).is_err(),
+        "wrong WHO_AM_I accepted"
+    );
+
+    let mut bus = FakeI2c::default();
+    bus.set(imu::ICM20948_ADDRESS, 0x00, &[0xea]);
+    bus.set(0x0c, 0x01, &[0x09]);
+    bus.set(
+        imu::ICM20948_ADDRESS,
+        0x2d,
+        &[0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 131],
+    );
+    // 30 uT along x, 15 uT along its y, 45 uT along its z; ready, no overflow
+    bus.set(0x0c, 0x10, &[0x01, 200, 0, 100, 0, 44, 1, 0, 0]);
+    let mut icm = imu::Icm20948::new(bus, imu::ICM20948_ADDRESS)?;
+    let sample = icm.read()?;
+    ensure!(
+        close(sample.gyro, [0.0, 0.0, 2.0 * degrees], 1e-3),
+        "ICM-20948 gyro {:?}",
+        sample.gyro
+    );
+    ensure!(
+        matches!(sample.magnetometer, Some(field) if close(field, [30.0, -15.0, -45.0], 1e-3)),
+        "ICM-20948 magnetometer {:?}",
+        sample.magnetometer
+    );
+    Ok(())
+}
+
+fn gyro_calibration_and_yaw() -> Result<()> {
+    use std::f64::consts::PI;
+
+    let mut noise = Rng::new(45);
+    let bias = [0.01, -0.02, 0.03];
+    let still: Vec<[f32; 3]> = (0..200)
+        .map(|_| {
+            let mut sample = [0.0; 3];
+            for (value, bias) in sample.iter_mut().zip(bias.iter()) {
+                *value = (bias + noise.gaussian(0.005)) as f32;
+            }
+            sample
+        })
+        .collect();
+    let estimate = imu::gyro_bias(&still, 0.02)?;
+    ensure!(
+        estimate
+            .iter()
+            .zip(bias.iter())
+            .all(|(estimate, bias)| (*estimate as f64 - bias).abs() < 0.002),
+        "gyro bias {:?} instead of {:?}",
+        estimate,
+        bias
+    );
+    let mut moved = still.clone();
+    for (i, sample) in moved.iter_mut().enumerate().skip(100) {
+        sample[2] += 0.5 * ((i - 100) as f32 / 100.0);
+    }
+    ensure!(
+        imu::gyro_bias(&moved, 0.02).is_err(),
+        "calibrated while turning"
+    );
+
+    // the car turns at 0.5 rad/s for 10 s, then stands for 10 s; the gyro is biased by more than calibrated
+    let tick = std::time::Duration::from_millis(10);
+    let rate = |i: usize| if i < 1000 { 0.5 } else { 0.0 };
+    let run = |with_reference: bool, noise: &mut Rng| {
+        let mut filter = imu::YawFilter::new(imu::YawConfig { gyro_weight: 0.98 }, 0.03);
+        let mut truth: f64 = 0.3;
+        let mut worst: f64 = 0.0;
+        for i in 0..2000 {
+            let gyro = rate(i) + 0.035 + noise.gaussian(0.01);
+            // magnetometer headings are noisy
+            let reference = if with_reference {
+                Some(odometry::wrap_angle(truth + noise.gaussian(0.05)))
+            } else {
+                None
+            };
+            let yaw = filter.update(gyro, tick, reference);
+            truth += rate(i) * tick.as_secs_f64();
+            if i > 100 {
+                worst = worst.max(odometry::wrap_angle(yaw - truth).abs());
+            }
+        }
+        (worst, filter.yaw().unwrap())
+    };
+    let (worst, _) = run(true, &mut noise);
+    ensure!(
+        worst < 0.05,
+        "yaw off by {} rad with the magnetometer",
+        worst
+    );
+    // without reference it starts at zero and drifts by the uncalibrated bias
+    let (_, yaw) = run(false, &mut noise);
+    let expected = odometry::wrap_angle(5.0 + 0.005 * 20.0);
+    ensure!(
+        (yaw - expected).abs() < 0.05 && yaw.abs() <= PI,
+        "gyro only yaw {} instead of {}",
+        yaw,
+        expected
+    );
+
+    let heading = imu::magnetic_heading([20.0, -20.0, -40.0]);
+    ensure!(
+        (heading - PI / 4.0).abs() < 1e-9,
+        "magnetic heading {}",
+        heading
+    );
+    Ok(())
+}
+
+fn car_keeps_heading() -> Result<()> {
+    let mut control = car_control::CarControl::new(car_control::CarConfig::default());
+    let now = std::time::Instant::now();
+    let data = protocol::ControlData {
+        filtered: 0.1,
+        confidence: 1.0,
+        ..protocol::ControlData::empty()
+    };
+    control.arm();
+    control.set_link(true);
+    control.set_heading(Some(3.0));
+    control.receive(data, now);
+    ensure!(control.tick(now).turn == 0.0, "turns on its heading");
+    // drifted clockwise across the wrap
+    control.set_heading(Some(-3.0));
+    let turn = control.tick(now).turn;
+    ensure!(
+        turn < 0.0 && (turn + 0.5 * (2.0 * std::f64::consts::PI - 6.0)).abs() < 1e-9,
+        "turn {} back to the heading",
+        turn
+    );
+    control.set_heading(None);
+    ensure!(control.tick(now).turn == 0.0, "turns without a heading");
+    Ok(())
+}
+
+// a HC-SR04 in front of a wall: the echo pin goes high half a millisecond after the trigger,
+// for the time the sound takes there and back
+struct FakeTrigger;
+
+impl embedded_hal::digital::v2::OutputPin for FakeTrigger {
+    type Error = std::convert::Infallible;
+
+    fn set_low(&mut self) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+// None for a sensor that does not answer
+struct FakeEcho(Option<f64>);
+
+impl obstacle::EchoTimer for FakeEcho {
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn edges(&mut self, timeout: Duration) -> Result<(Option<Duration>, Option<Duration>)> {
+        let wall = match self.0 {
+            Some(wall) => wall,
+            None => return Ok((None, None)),
+        };
+        let rise = Duration::from_micros(500);
+        let fall = rise + Duration::from_secs_f64(2.0 * wall / 343.0);
+        Ok((Some(rise), Some(fall).filter(|fall| *fall <= timeout)))
+    }
+}
+
+fn obstacle_stop() -> Result<()> {
+    use obstacle::RangeSensor;
+    use std::time::Duration;
+
+    let hc_sr04 = |wall: Option<f64>| obstacle::HcSr04::new(FakeTrigger, FakeEcho(wall));
+    let mut sensor = hc_sr04(Some(0.5))?;
+    let distance = sensor.distance()?;
+    ensure!(
+        matches!(distance, Some(distance) if (distance - 0.5).abs() < 0.02),
+        "wall at 0.5 m measured at {:?}",
+        distance
+    );
+    ensure!(
+        hc_sr04(None)?.distance().is_err(),
+        "silent sensor taken for a clear way"
+    );
+    ensure!(
+        hc_sr04(Some(10.0))?.distance()?.is_none(),
+        "measured out of range"
+    );
+
+    let config = obstacle::SafetyConfig {
+        stop_distance: 0.25,
+        clear_distance: 0.35,
+        max_age: Duration::from_millis(300),
+    };
+    let mut supervisor = obstacle::Supervisor::new(config);
+    let start = std::time::Instant::now();
+    ensure!(
+        supervisor.blocked(start),
+        "clear before the first measurement"
+    );
+    let mut range = obstacle::MockRange {
+        distances: vec![None, Some(0.5), Some(0.2), Some(0.3), Some(0.4)],
+    };
+    let mut blocked = Vec::new();
+    for i in 0..5 {
+        let now = start + Duration::from_millis(60 * i);
+        supervisor.measure(range.distance()?, now);
+        blocked.push(supervisor.blocked(now));
+    }
+    ensure!(
+        blocked == [false, false, true, true, false],
+        "blocked {:?}",
+        blocked
+    );
+    let late = start + Duration::from_millis(240) + config.max_age + Duration::from_millis(1);
+    ensure!(supervisor.blocked(late), "clear with a silent sensor");
+    // a failed measurement blocks at once
+    let failed = start + Duration::from_millis(300);
+    supervisor.fail(failed);
+    ensure!(supervisor.blocked(failed), "clear with a failed sensor");
+    supervisor.measure(None, failed + Duration::from_millis(60));
+    ensure!(
+        !supervisor.blocked(failed + Duration::from_millis(60)),
+        "blocked after the sensor recovered"
+    );
+
+    // the car stops driving forward, but backs away
+    let mut control = car_control::CarControl::new(car_control::CarConfig::default());
+    let data = |filtered: f32, sequence: u32| protocol::ControlData {
+        filtered,
+        confidence: 1.0,
+        sequence,
+        ..protocol::ControlData::empty()
+    };
+    control.set_blocked(true);
+    control.tick(start);
+    ensure!(
+        control.state() == car_control::State::Idle,
+        "blocked before the run"
+    );
+    control.arm();
+    control.set_link(true);
+    control.receive(data(-0.1, 1), start);
+    ensure!(control.tick(start).output == 0.0, "forward while blocked");
+    ensure!(
+        control.state() == car_control::State::Blocked,
+        "not blocked"
+    );
+    control.receive(data(0.1, 2), start);
+    ensure!(control.tick(start).output < 0.0, "not backing away");
+    control.set_blocked(false);
+    control.tick(start);
+    ensure!(
+        control.state() == car_control::State::Approaching,
+        "still blocked"
+    );
+    Ok(())
+}
+
+fn battery_low_and_compensation() -> Result<()> {
+    use std::time::Duration;
+
+    let config = battery::BatteryConfig {
+        divider: 4.0,
+        nominal: 7.4,
+        low: 6.6,
+        low_for: Duration::from_secs(2),
+        smoothing: Duration::from_millis(500),
+        max_compensation: 1.33,
+    };
+    let mut battery = battery::Battery::new(config);
+    let start = std::time::Instant::now();
+    let tick = Duration::from_millis(100);
+    let mut noise = Rng::new(47);
+    let mut at = start;
+    let mut run = |battery: &mut battery::Battery, volts: f32, ticks: u32| {
+        for _ in 0..ticks {
+            let millivolts = (volts / config.divider * 1000.0) as f64 + noise.gaussian(20.0);
+            battery.measure(millivolts as u16, at);
+            at += tick;
+        }
+    };
+
+    run(&mut battery, 7.4, 30);
+    let voltage = battery.voltage().unwrap_or(f32::NAN);
+    ensure!((voltage - 7.4).abs() < 0.05, "filtered voltage {}", voltage);
+    ensure!(
+        (battery.compensation() - 1.0).abs() < 0.01,
+        "compensation {} at nominal",
+        battery.compensation()
+    );
+
+    // the engines starting pull it down for a second, that is not a low battery
+    run(&mut battery, 6.0, 10);
+    run(&mut battery, 7.0, 30);
+    ensure!(!battery.low(), "low after a dip");
+    let compensation = battery.compensation();
+    ensure!(
+        (compensation - 7.4 / 7.0).abs() < 0.02,
+        "compensation {} at 7 V",
+        compensation
+    );
+
+    run(&mut battery, 6.4, 40);
+    ensure!(battery.low(), "not low at 6.4 V");
+    run(&mut battery, 7.4, 40);
+    ensure!(battery.low(), "low battery recovered");
+    run(&mut battery, 3.0, 10);
+    ensure!(
+        battery.compensation() == config.max_compensation,
+        "compensation {} not capped",
+        battery.compensation()
+    );
+
+    // a sagging supply gets more duty
+    let mut stage = motor::OutputStage::new(
+        motor::MockMotor::new(1000),
+        motor::OutputConfig {
+            max_slew: 100.0,
+            ..motor::OutputConfig::default()
+        },
+    );
+    stage.set_supply_compensation(1.25);
+    let duty = stage.drive(0.4, tick)?;
+    ensure!(duty == 500, "compensated duty {}", duty);
+    ensure!(stage.drive(1.0, tick)? == 1000, "duty beyond the max");
+
+    // the run stops for good, with a sound the tracker does not take for the car
+    let mut control = car_control::CarControl::new(car_control::CarConfig::default());
+    control.receive(
+        protocol::ControlData {
+            filtered: 0.1,
+            confidence: 1.0,
+            ..protocol::ControlData::empty()
+        },
+        start,
+    );
+    control.arm();
+    control.set_link(true);
+    control.low_battery();
+    ensure!(
+        control.tick(start) == car_control::Command::default(),
+        "moves on a low battery"
+    );
+    ensure!(
+        control.state() == car_control::State::LowBattery,
+        "state {:?}",
+        control.state()
+    );
+    control.set_blocked(false);
+    control.tick(start);
+    ensure!(
+        control.state() == car_control::State::LowBattery,
+        "left the low battery state"
+    );
+    let pattern = beep_pattern::low_battery();
+    ensure!(
+        beep_pattern::duration(&pattern) < std::time::Duration::from_millis(400),
+        "low battery pattern longer than the beep period"
+    );
+    ensure!(
+        pattern.iter().all(|segment| segment.frequency_hz < 2000),
+        "low battery pattern near the tones of the tracker"
+    );
+    Ok(())
+}
+
+fn state_machine_transitions() -> Result<()> {
+    use state_machine::{next, on_entry, Action, Inputs, MachineConfig, State, StateMachine};
+    use std::time::Duration;
+
+    let config = MachineConfig::default();
+    let zero = Duration::from_secs(0);
+    // driving to the line, five tolerances off
+    let on = Inputs {
+        armed: true,
+        link: true,
+        fix: true,
+        needs_fix: true,
+        goal_error: Some(5.0),
+        hold: Duration::from_secs(3),
+        last_goal: true,
+        ..Inputs::default()
+    };
+    let to = |state: State, elapsed: Duration, inputs: Inputs| {
+        next(state, elapsed, &inputs, &config).map(|step| step.to)
+    };
+
+    ensure!(
+        to(State::Idle, zero, Inputs::default()).is_none(),
+        "left idle unarmed"
+    );
+    ensure!(
+        to(State::Idle, zero, on) == Some(State::WaitingForLink),
+        "not waiting for the link once armed"
+    );
+    let waiting = Inputs { link: false, ..on };
+    ensure!(
+        to(State::WaitingForLink, zero, waiting).is_none(),
+        "left without a link"
+    );
+    ensure!(
+        to(State::WaitingForLink, config.link_timeout * 2, waiting) == Some(State::Fault),
+        "waited for the link forever"
+    );
+    let silent = Inputs {
+        fix: false,
+        goal_error: None,
+        ..on
+    };
+    ensure!(
+        to(State::WaitingForLink, zero, silent) == Some(State::Searching),
+        "not searching once connected"
+    );
+    ensure!(
+        to(State::Searching, config.search_timeout * 2, silent) == Some(State::Fault),
+        "searched forever"
+    );
+    ensure!(
+        to(State::Searching, zero, on) == Some(State::Approaching),
+        "not approaching on a measurement"
+    );
+    ensure!(
+        to(State::Approaching, zero, silent) == Some(State::Searching),
+        "approaching without measurements"
+    );
+    ensure!(
+        to(
+            State::Approaching,
+            zero,
+            Inputs {
+                blocked: true,
+                ..on
+            }
+        ) == Some(State::Blocked),
+        "drove into an obstacle"
+    );
+    ensure!(
+        to(
+            State::Blocked,
+            config.blocked_timeout * 2,
+            Inputs {
+                blocked: true,
+                ..on
+            }
+        ) == Some(State::Fault),
+        "blocked forever"
+    );
+
+    // at the goal within the tolerance, off it only beyond twice the tolerance
+    let near = |error: f64| Inputs {
+        goal_error: Some(error),
+        ..on
+    };
+    ensure!(
+        to(State::Approaching, zero, near(0.5)) == Some(State::Holding),
+        "not holding on the line"
+    );
+    ensure!(
+        to(State::Holding, zero, near(1.5)).is_none(),
+        "left the line too early"
+    );
+    ensure!(
+        to(State::Holding, zero, near(2.5)) == Some(State::Approaching),
+        "held off the line"
+    );
+    ensure!(
+        to(State::Holding, on.hold, near(0.0)) == Some(State::Done),
+        "held the line forever"
+    );
+    let step = next(
+        State::Holding,
+        on.hold,
+        &Inputs {
+            last_goal: false,
+            ..near(0.0)
+        },
+        &config,
+    );
+    ensure!(
+        matches!(step, Some(step) if step.to == State::Approaching && step.next_goal),
+        "not on to the next goal: {:?}",
+        step
+    );
+    // goals without the tracker do not wait for it
+    let turning = Inputs {
+        link: false,
+        fix: false,
+        needs_fix: false,
+        ..on
+    };
+    ensure!(
+        to(State::WaitingForLink, zero, turning) == Some(State::Searching)
+            && to(State::Searching, zero, turning) == Some(State::Approaching),
+        "waiting for the tracker to turn"
+    );
+
+    // whatever the state, safety first, and the end of a run is final
+    for state in &[State::Searching, State::Approaching, State::Holding] {
+        ensure!(
+            to(
+                *state,
+                zero,
+                Inputs {
+                    emergency_stop: true,
+                    low_battery: true,
+                    ..on
+                }
+            ) == Some(State::EStop),
+            "no emergency stop from {:?}",
+            state
+        );
+        ensure!(
+            to(
+                *state,
+                zero,
+                Inputs {
+                    fault: Some("test"),
+                    low_battery: true,
+                    ..on
+                }
+            ) == Some(State::Fault),
+            "no fault from {:?}",
+            state
+        );
+    }
+    ensure!(
+        to(
+            State::Done,
+            zero,
+            Inputs {
+                low_battery: true,
+                ..on
+            }
+        )
+        .is_none(),
+        "left done"
+    );
+    ensure!(
+        to(
+            State::EStop,
+            zero,
+            Inputs {
+                emergency_stop: true,
+                ..on
+            }
+        )
+        .is_none(),
+        "left the emergency stop without a reset"
+    );
+    ensure!(
+        to(State::EStop, zero, Inputs { reset: true, ..on }) == Some(State::Idle),
+        "not reset"
+    );
+    ensure!(
+        on_entry(State::Fault).contains(&Action::Brake),
+        "no brake on a fault"
+    );
+
+    // the machine keeps the time in the state and the actions of a transition
+    let start = std::time::Instant::now();
+    let mut machine = StateMachine::new(config, start);
+    machine.update(&on, start);
+    machine.update(&on, start);
+    let transition = machine.update(&on, start + Duration::from_secs(1));
+    ensure!(
+        transition.map(|transition| transition.to) == Some(State::Approaching),
+        "machine in {:?}",
+        machine.state()
+    );
+    let transition = machine.update(&near(0.0), start + Duration::from_secs(2));
+    ensure!(
+        matches!(&transition, Some(transition) if transition.actions == [Action::ResetController, Action::Brake]),
+        "approaching to holding {:?}",
+        transition
+    );
+    ensure!(
+        machine
+            .update(&near(0.0), start + Duration::from_secs(4))
+            .is_none(),
+        "hold time from the start of the run"
+    );
+    ensure!(
+        machine
+            .update(&near(0.0), start + Duration::from_secs(5))
+            .is_some(),
+        "not done after the hold time"
+    );
+
+    // the car control settles on a tick, and brakes at once
+    let mut control = car_control::CarControl::new(car_control::CarConfig::default());
+    control.arm();
+    control.set_link(true);
+    control.receive(
+        protocol::ControlData {
+            filtered: 0.1,
+            confidence: 1.0,
+            ..protocol::ControlData::empty()
+        },
+        start,
+    );
+    ensure!(
+        control.tick(start).output < 0.0,
+        "not approaching on the first tick"
+    );
+    let states: Vec<_> = control
+        .take_transitions()
+        .iter()
+        .map(|transition| transition.to)
+        .collect();
+    ensure!(
+        states == [State::WaitingForLink, State::Searching, State::Approaching],
+        "states {:?}",
+        states
+    );
+    control.emergency_stop();
+    ensure!(
+        control.tick(start) == car_control::Command::default(),
+        "moves after an emergency stop"
+    );
+    ensure!(
+        control.take_transitions()[0].actions
+            == [
+                Action::ResetController,
+                Action::Brake,
+                Action::SaveFlightRecord
+            ],
+        "actions of an emergency stop"
+    );
+    control.reset();
+    control.tick(start);
+    ensure!(
+        control.state() == State::Approaching,
+        "state {:?} after a reset",
+        control.state()
+    );
+
+    let mut stage =
+        motor::OutputStage::new(motor::MockMotor::new(1000), motor::OutputConfig::default());
+    stage.drive(1.0, Duration::from_secs(1))?;
+    stage.brake()?;
+    ensure!(
+        stage.output() == 0.0 && stage.motor().last() == Some(motor::MotorCommand::Brake),
+        "brake not at once"
+    );
+    Ok(())
+}
+
+fn search_until_heard() -> Result<()> {
+    use search::{motion, SearchConfig, SearchPattern};
+    use std::time::Duration;
+
+    let config = SearchConfig::default();
+    let at = |pattern: SearchPattern, seconds: f64| {
+        motion(
+            &SearchConfig { pattern, ..config },
+            Duration::from_secs_f64(seconds),
+        )
+    };
+    ensure!(
+        at(SearchPattern::Stand, 3.0) == (0.0, 0.0),
+        "standing car moves"
+    );
+    ensure!(
+        at(SearchPattern::Rotate, 3.0) == (0.0, config.turn),
+        "not rotating in place"
+    );
+    let (output, turn) = at(SearchPattern::Spiral, config.spiral_period.as_secs_f64());
+    ensure!(
+        output == config.speed && (turn - config.turn / 2.0).abs() < 1e-9,
+        "spiral not widening: {} {}",
+        output,
+        turn
+    );
+    // half a leg forward, then whole legs, so it swings around where it started
+    let leg = config.leg.as_secs_f64();
+    let swings: Vec<f64> = [0.0, 0.4 * leg, 0.6 * leg, 1.4 * leg, 1.6 * leg]
+        .iter()
+        .map(|seconds| at(SearchPattern::BackAndForth, *seconds).0)
+        .collect();
+    ensure!(
+        swings
+            == [
+                config.speed,
+                config.speed,
+                -config.speed,
+                -config.speed,
+                config.speed
+            ],
+        "back and forth {:?}",
+        swings
+    );
+
+    // the car searches until it is heard, without running into obstacles
+    let mut car_config = car_control::CarConfig::default();
+    car_config.search.pattern = SearchPattern::BackAndForth;
+    let mut control = car_control::CarControl::new(car_config);
+    control.arm();
+    control.set_link(true);
+    let start = std::time::Instant::now();
+    let command = control.tick(start);
+    ensure!(
+        control.state() == car_control::State::Searching && command.output > 0.0,
+        "not searching: {:?} {:?}",
+        control.state(),
+        command
+    );
+    control.set_blocked(true);
+    ensure!(
+        control.tick(start + Duration::from_millis(100)).output == 0.0,
+        "searching into an obstacle"
+    );
+    control.set_blocked(false);
+    ensure!(
+        control.tick(start + config.leg).output < 0.0,
+        "not backing on the second leg"
+    );
+    // a weak measurement is not enough to stop searching
+    let data = |confidence: f32| protocol::ControlData {
+        filtered: 0.1,
+        confidence,
+        ..protocol::ControlData::empty()
+    };
+    let heard = start + config.leg + Duration::from_millis(100);
+    control.receive(data(0.1), heard);
+    control.tick(heard);
+    ensure!(
+        control.state() == car_control::State::Searching,
+        "left the search on a weak measurement"
+    );
+    control.receive(data(1.0), heard);
+    let command = control.tick(heard);
+    ensure!(
+        control.state() == car_control::State::Approaching && command.predicted.is_some(),
+        "still searching when heard"
+    );
+
+    // and gives up
+    let mut control = car_control::CarControl::new(car_config);
+    control.arm();
+    control.set_link(true);
+    control.tick(start);
+    let late = start + car_config.machine.search_timeout + Duration::from_secs(1);
+    ensure!(
+        control.tick(late) == car_control::Command::default(),
+        "moves after giving up"
+    );
+    ensure!(
+        control.state() == car_control::State::Fault,
+        "searching for ever, {:?}",
+        control.state()
+    );
+    Ok(())
+}
+
+fn mission_in_order() -> Result<()> {
+    use car_control::State;
+    use mission::{Goal, Mission};
+    use std::f64::consts::PI;
+    use std::time::Duration;
+
+    let mission = Mission::parse(
+        "# turn left, wait, drive a metre ahead, then to 10 cm off the line
+        bearing 90
+        hold 0.5   # s
+        position 1 0
+        offset 0.1
+        ",
+    )?;
+    ensure!(
+        mission.goals()
+            == [
+                Goal::Bearing(PI / 2.0),
+                Goal::Hold(Duration::from_millis(500)),
+                Goal::Position { x: 1.0, y: 0.0 },
+                Goal::Offset(0.1),
+            ],
+        "goals {:?}",
+        mission.goals()
+    );
+    for bad in &[
+        "",
+        "# nothing",
+        "bearing",
+        "turn 90",
+        "hold -1",
+        "hold 1e30",
+        "position 1",
+        "offset inf",
+    ] {
+        ensure!(Mission::parse(bad).is_err(), "{:?} accepted", bad);
+    }
+
+    let mut config = car_control::CarConfig::default();
+    config.mission.settle = Duration::from_millis(500);
+    // the path differences as measured, without learning how the car moves them
+    config.estimator.min_excitation = f64::INFINITY;
+    let mut control = car_control::CarControl::new(config);
+    control.set_mission(mission);
+    control.arm();
+    control.set_heading(Some(0.0));
+    control.set_position(Some((0.0, 0.0)));
+    let start = std::time::Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+
+    // turning does not wait for the tracker
+    let command = control.tick(at(0.0));
+    ensure!(
+        control.state() == State::Approaching && command.output == 0.0 && command.turn > 0.0,
+        "not turning left: {:?} {:?}",
+        control.state(),
+        command
+    );
+    control.set_heading(Some(PI / 2.0 - 0.01));
+    control.tick(at(0.1));
+    ensure!(control.state() == State::Holding, "not at the bearing");
+    // settled, then straight into the hold
+    control.tick(at(0.6));
+    ensure!(
+        control.state() == State::Holding && control.mission().reached() == 1,
+        "not holding: {:?}, {} goals reached",
+        control.state(),
+        control.mission().reached()
+    );
+    let command = control.tick(at(1.2));
+    ensure!(
+        control.mission().reached() == 2 && command.output < 0.05 && command.turn < 0.0,
+        "not turning to the position first: {:?}",
+        command
+    );
+    control.set_heading(Some(0.0));
+    ensure!(
+        control.tick(at(1.3)).output > 0.0,
+        "not driving to the position"
+    );
+    control.set_position(Some((0.98, 0.01)));
+    control.tick(at(1.4));
+    ensure!(control.state() == State::Holding, "not at the position");
+
+    // the offset needs the tracker
+    control.tick(at(2.0));
+    ensure!(
+        control.state() == State::WaitingForLink && control.mission().reached() == 3,
+        "{:?} with {} goals reached",
+        control.state(),
+        control.mission().reached()
+    );
+    control.set_link(true);
+    control.receive(
+        protocol::ControlData {
+            filtered: 0.04,
+            confidence: 1.0,
+            sequence: 1,
+            ..protocol::ControlData::empty()
+        },
+        at(2.1),
+    );
+    ensure!(
+        control.tick(at(2.1)).output > 0.0,
+        "not driving to the offset"
+    );
+    control.receive(
+        protocol::ControlData {
+            filtered: 0.1,
+            confidence: 1.0,
+            sequence: 2,
+            ..protocol::ControlData::empty()
+        },
+        at(2.2),
+    );
+    control.tick(at(2.2));
+    control.tick(at(2.8));
+    let transitions = control.take_transitions();
+    ensure!(
+        control.state() == State::Done
+            && transitions.last().map(|transition| transition.reason) == Some("mission complete"),
+        "not done: {:?}",
+        transitions.last()
+    );
+
+    // a bearing without a heading sensor cannot be reached
+    let mut control = car_control::CarControl::new(config);
+    control.set_mission(Mission::new(vec![Goal::Bearing(1.0)]));
+    control.arm();
+    control.tick(start);
+    ensure!(control.state() == State::Fault, "bearing without a heading");
+    Ok(())
+}
+
+// channel i is sampled i / 3 of a sample period after channel 0, as the ADC scans the three in turn
+fn skewed_channels() -> Result<()> {
+    let rate = 32_000.0;
+    let skews: Vec<Duration> = (0..3)
+        .map(|i| Duration::from_nanos(i * 1_000_000_000 / (32_000 * 3)))
+        .collect();
+    // whole samples late once skewed, where the matched filter is most precise
+    let delays = [0.0, 19.0 / 3.0 / rate, -13.0 / 3.0 / rate];
+    let mut noise = Rng::new(21);
+
+    let burst = Burst::new(&mut noise, 0.004, 0.02);
+    let channels: Vec<Vec<f32>> = (0..3)
+        .map(|i| {
+            let late = delays[i] - skews[i].as_secs_f64();
+            capture(&burst, late as f32, rate as f32, 1024, &mut noise)
+        })
+        .collect();
+    for i in 1..3 {
+        let lag = dsp::gcc_phat(&channels[i], &channels[0], 48)
+            .ok_or_else(|| anyhow::anyhow!("no correlation peak on channel {}", i))?;
+        let delay = dsp::deskew(lag, rate, skews[i]);
+        ensure!(
+            (delay - delays[i]).abs() < 0.25 / rate,
+            "delay of channel {} is {} s, estimated as {} s",
+            i,
+            delays[i],
+            delay
+        );
+    }
+
+    let pattern = beep_pattern::CODED.pattern(0);
+    let template = beep_pattern::render(&pattern, rate as f32);
+    let mut starts = Vec::new();
+    for i in 0..3 {
+        let late = 150.0 + (delays[i] - skews[i].as_secs_f64()) * rate;
+        let heard = hear(
+            &pattern,
+            rate as f32,
+            late as f32,
+            (96.0, 0.5),
+            1024,
+            &mut noise,
+        );
+        let start = dsp::detect(&heard, &template, 0.5, 0.5)
+            .ok_or_else(|| anyhow::anyhow!("chirp not detected on channel {}", i))?;
+        starts.push(dsp::deskew(start, rate, skews[i]));
+    }
+    for i in 1..3 {
+        let delay = starts[i] - starts[0];
+        ensure!(
+            (delay - delays[i]).abs() < 0.25 / rate,
+            "chirp on channel {} is {} s late, estimated as {} s",
+            i,
+            delays[i],
+            delay
+        );
+    }
+    Ok(())
+}
+
+// the IMU counts the heading from magnetic north, the goals count from where the car started
+fn mission_from_magnetic_heading() -> Result<()> {
+    use car_control::State;
+    use mission::{Goal, Mission};
+    use std::f64::consts::PI;
+
+    let config = car_control::CarConfig::default();
+    let mut control = car_control::CarControl::new(config);
+    control.set_mission(Mission::new(vec![
+        Goal::Position { x: 1.0, y: 0.0 },
+        Goal::Bearing(PI / 2.0),
+    ]));
+    control.arm();
+    // facing a bit west of north-west
+    let north = 2.0;
+    control.set_heading(Some(north));
+    control.set_position(Some((0.0, 0.0)));
+    let start = std::time::Instant::now();
+    let command = control.tick(start);
+    ensure!(
+        control.state() == State::Approaching && command.output > 0.5 && command.turn == 0.0,
+        "not driving straight ahead to the position: {:?}",
+        command
+    );
+    control.set_position(Some((1.0, 0.0)));
+    control.tick(start + Duration::from_millis(100));
+    ensure!(control.state() == State::Holding, "not at the position");
+
+    let turning = start + config.mission.settle + Duration::from_millis(200);
+    let command = control.tick(turning);
+    ensure!(
+        control.mission().reached() == 1 && command.turn > 0.0,
+        "not turning left to the bearing: {:?}",
+        command
+    );
+    control.set_heading(Some(north + PI / 2.0));
+    control.tick(turning + Duration::from_millis(100));
+    ensure!(
+        control.state() == State::Holding,
+        "not at the bearing: {:?}",
+        control.heading()
+    );
+    Ok(())
+}
+
+fn car_takes_a_burst_once() -> Result<()> {
+    let mut control = car_control::CarControl::new(car_control::CarConfig::default());
+    let mut sequence = protocol::Sequence::new();
+    let now = std::time::Instant::now();
+    // as the tracker publishes the burst heard at `time`
+    let mut data = |filtered: f32, time: u64| protocol::ControlData {
+        filtered,
+        confidence: 0.5,
+        sequence: sequence.number(time),
+        time,
+        ..protocol::ControlData::empty()
+    };
+
+    control.arm();
+    control.set_link(true);
+    ensure!(control.receive(data(0.1, 1000), now), "first burst ignored");
+    // the detection reports a burst until it has the next one
+    ensure!(
+        control.receive(data(0.0, 401_000), now),
+        "second burst ignored"
+    );
+    for _ in 0..5 {
+        ensure!(
+            !control.receive(data(0.0, 401_000), now),
+            "repeated burst taken again"
+        );
+    }
+    // half confident: halfway from 0.1 to 0.0, once
+    let filtered = control.data().filtered;
+    ensure!(
+        (filtered - 0.05).abs() < 1e-6,
+        "blended filtered {}",
+        filtered
+    );
+    ensure!(
+        control.receive(data(0.0, 801_000), now) && control.data().sequence == 3,
+        "next burst numbered {}",
+        { control.data().sequence }
+    );
+    Ok(())
+}
+
+fn car_learns_from_a_burst_once() -> Result<()> {
+    let start = std::time::Instant::now();
+    let tick = Duration::from_millis(100);
+    // one car gets the control data on every tick like from the tracker, the other each burst once
+    let mut repeated = car_control::CarControl::new(car_control::CarConfig::default());
+    let mut once = car_control::CarControl::new(car_control::CarConfig::default());
+    for control in [&mut repeated, &mut once] {
+        control.arm();
+        control.set_link(true);
+    }
+    let mut sequence = protocol::Sequence::new();
+    // path difference change per second at full output
+    let sensitivity = 0.3;
+    let mut truth = 0.2;
+    let mut published = protocol::ControlData::empty();
+    let mut predicted = None;
+    let mut learnt = false;
+    for i in 0..40u32 {
+        let now = start + tick * i;
+        // the tracker started 0.1 s before the car
+        let tracker_now = (tick * i).as_micros() as u64 + 100_000;
+        // sent by the tracker a varying time before it arrives
+        let sent = tracker_now - (5_000 + (i as u64 * 7_000) % 20_000);
+        if i % 4 == 0 {
+            // heard 30 ms ago
+            let time = tracker_now - 30_000;
+            published = protocol::ControlData {
+                filtered: truth,
+                confidence: 0.8,
+                sequence: sequence.number(time),
+                time,
+                ..protocol::ControlData::empty()
+            };
+            published.age = (sent - time) as u32;
+            once.receive(published, now);
+        } else {
+            // the detection reports the burst again, the tracker sends it as old as it is by then
+            published.sequence = sequence.number(published.time);
+            published.age = (sent - published.time) as u32;
+        }
+        repeated.receive(published, now);
+        let command = repeated.tick(now);
+        let expected = once.tick(now);
+        ensure!(
+            command == expected,
+            "tick {}: {:?} with repeated control data, {:?} without",
+            i,
+            command,
+            expected
+        );
+        // without a learnt effect of the output, the prediction holds between the bursts
+        learnt |= i % 4 != 0 && command.predicted != predicted;
+        predicted = command.predicted;
+        truth += sensitivity * command.output as f32 * tick.as_secs_f32();
+    }
+    ensure!(learnt, "no prediction from the commanded outputs");
+    Ok(())
+}
+
+fn ranges_reported_after_the_beep() -> Result<()> {
+    let config = ranging::RangingConfig::default();
+    let mut ranges = ranging::RangeMatcher::new(config);
+    let distances = [2.0, 2.5, 3.0];
+    let heard = |emitted: u64| -> Vec<u64> {
+        distances
+            .iter()
+            .map(|distance| emitted + (distance / config.speed_of_sound * 1e6) as u64)
+            .collect()
+    };
+    let close = |ranges: Option<&[f32]>| match ranges {
+        Some(ranges) => ranges
+            .iter()
+            .zip(distances.iter())
+            .all(|(range, distance)| (range - distance).abs() < 0.01),
+        None => false,
+    };
+
+    ranges.push_beep(0);
+    // the car reports a beep after the tracker heard it, the last report is a beep earlier
+    ranges.push_arrivals(&heard(1_000_000));
+    ensure!(
+        ranges.ranges().is_none(),
+        "ranges {:?} from the beep before",
+        ranges.ranges()
+    );
+    ensure!(
+        ranges.push_beep(1_000_000) && close(ranges.ranges()),
+        "ranges {:?} once the beep is reported",
+        ranges.ranges()
+    );
+    // the detection reports the burst again, the next beep is after it
+    ranges.push_arrivals(&heard(1_000_000));
+    ensure!(
+        !ranges.push_beep(2_000_000) && close(ranges.ranges()),
+        "ranges {:?} after the next beep",
+        ranges.ranges()
+    );
+    // the report first
+    ranges.push_arrivals(&heard(2_000_000));
+    ensure!(
+        close(ranges.ranges()),
+        "ranges {:?} of a beep reported before",
+        ranges.ranges()
+    );
+
+    // the tracker publishes the measurement again with its ranges, the car takes only those
+    let mut control = car_control::CarControl::new(car_control::CarConfig::default());
+    let now = std::time::Instant::now();
+    let data = protocol::ControlData {
+        filtered: 0.1,
+        confidence: 1.0,
+        sequence: 1,
+        ..protocol::ControlData::empty()
+    };
+    control.receive(data, now);
+    let mut distance = [f32::NAN; protocol::MAX_RECEIVERS];
+    distance[..distances.len()].copy_from_slice(&distances);
+    ensure!(
+        !control.receive(
+            protocol::ControlData {
+                distance,
+                filtered: 0.0,
+                ..data
+            },
+            now
+        ),
+        "published again, taken again"
+    );
+    let taken = control.data();
+    let (taken_distance, filtered) = (taken.distance, taken.filtered);
+    ensure!(
+        taken_distance[..distances.len()] == distances && filtered == 0.1,
+        "distance {:?}, filtered {}",
+        taken_distance,
+        filtered
+    );
+    Ok(())
+}
+
+type Scenario = fn() -> Result<()>;
+
+const SCENARIOS: &[(&str, Scenario)] = &[
+    ("calibration_bisector", calibration_bisector),
+    ("calibration_known_positions", calibration_known_positions),
+    ("filter_ramp_with_echoes", filter_ramp_with_echoes),
+    (
+        "confidence_clean_and_marginal",
+        confidence_clean_and_marginal,
+    ),
+    ("direction_all_around", direction_all_around),
+    ("gcc_phat_fractional_delays", gcc_phat_fractional_delays),
+    ("ring_buffer_latest", ring_buffer_latest),
+    (
+        "goertzel_buzzer_against_noise",
+        goertzel_buzzer_against_noise,
+    ),
+    ("cadence_beeps_and_claps", cadence_beeps_and_claps),
+    ("beep_patterns", beep_patterns),
+    (
+        "matched_filter_chirp_with_echoes",
+        matched_filter_chirp_with_echoes,
+    ),
+    (
+        "matched_filter_rejects_other_sounds",
+        matched_filter_rejects_other_sounds,
+    ),
+    ("physical_delays", physical_delays),
+    ("record_and_replay", record_and_replay),
+    ("flight_recorder_ring", flight_recorder_ring),
+    ("car_follows_confident_data", car_follows_confident_data),
+    ("scripted_offsets", scripted_offsets),
+    ("motor_drivers", motor_drivers),
+    ("motor_output_stage", motor_output_stage),
+    ("wheel_odometry", wheel_odometry),
+    ("wheel_speed_control", wheel_speed_control),
+    ("estimator_between_updates", estimator_between_updates),
+    ("imu_drivers", imu_drivers),
+    ("gyro_calibration_and_yaw", gyro_calibration_and_yaw),
+    ("car_keeps_heading", car_keeps_heading),
+    ("obstacle_stop", obstacle_stop),
+    ("battery_low_and_compensation", battery_low_and_compensation),
+    ("state_machine_transitions", state_machine_transitions),
+    ("search_until_heard", search_until_heard),
+    ("mission_in_order", mission_in_order),
+    ("skewed_channels", skewed_channels),
+    (
+        "mission_from_magnetic_heading",
+        mission_from_magnetic_heading,
+    ),
+    ("car_takes_a_burst_once", car_takes_a_burst_once),
+    ("car_learns_from_a_burst_once", car_learns_from_a_burst_once),
+    (
+        "ranges_reported_after_the_beep",
+        ranges_reported_after_the_beep,
+    ),
+];
+
+fn main() {
+    let mut failed = 0;
+
+    for (name, scenario) in SCENARIOS {
+        match scenario() {
+            Ok(()) => println!("{} ... ok", name),
+            Err(e) => {
+                println!("{} ... FAILED: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} scenarios, {} failed", SCENARIOS.len(), failed);
+
+    if failed > 0 {
+        process::exit(1);
+    }
+}
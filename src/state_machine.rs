@@ -0,0 +1,249 @@
+#![allow(dead_code)]
+
+// The states of the car and what moves it from one to the next, without the hardware
+
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum State {
+    // not armed yet
+    Idle,
+    // no connection to the tracker
+    WaitingForLink,
+    // connected, but the tracker has no confident measurement of the car
+    Searching,
+    // working towards the current goal of the mission
+    Approaching,
+    // working towards the goal, but an obstacle is in the way: no forward motion
+    Blocked,
+    // at the goal, standing still
+    Holding,
+    // held the last goal long enough, the run is over
+    Done,
+    // the battery ran low, the run is over
+    LowBattery,
+    // something broke or timed out, the run is over
+    Fault,
+    // stopped by hand, until reset
+    EStop,
+}
+
+impl State {
+    /// Whether the run is over for good.
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            State::Done | State::LowBattery | State::Fault | State::EStop
+        )
+    }
+}
+
+/// What the car knows on a tick.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct Inputs {
+    pub armed: bool,
+    // connected to the tracker
+    pub link: bool,
+    // the path difference can be predicted from a confident measurement recent enough
+    pub fix: bool,
+    // the current goal needs the path difference from the tracker
+    pub needs_fix: bool,
+    // how far the car is off the current goal, in tolerances of the goal: at the goal within 1,
+    // off it beyond 2; None when unknown
+    pub goal_error: Option<f64>,
+    // how long the car stands at the current goal
+    pub hold: Duration,
+    // no goal after the current one
+    pub last_goal: bool,
+    // an obstacle is in the way of the current goal
+    pub blocked: bool,
+    pub low_battery: bool,
+    // why the car cannot go on, None when all is well
+    pub fault: Option<&'static str>,
+    pub emergency_stop: bool,
+    // leaves the emergency stop
+    pub reset: bool,
+    pub finish: bool,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct MachineConfig {
+    // waiting for the tracker longer than this is a fault
+    pub link_timeout: Duration,
+    // searching longer than this, the car gives up: a fault
+    pub search_timeout: Duration,
+    // blocked longer than this is a fault
+    pub blocked_timeout: Duration,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            link_timeout: Duration::from_secs(30),
+            search_timeout: Duration::from_secs(60),
+            blocked_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+/// Where the state machine goes.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Step {
+    pub to: State,
+    pub reason: &'static str,
+    // on to the next goal of the mission
+    pub next_goal: bool,
+}
+
+fn step(to: State, reason: &'static str) -> Option<Step> {
+    Some(Step {
+        to,
+        reason,
+        next_goal: false,
+    })
+}
+
+/// The step from `state`, held for `elapsed`, given `inputs`; None to stay.
+pub fn next(
+    state: State,
+    elapsed: Duration,
+    inputs: &Inputs,
+    config: &MachineConfig,
+) -> Option<Step> {
+    use State::*;
+
+    if state == EStop {
+        return if inputs.reset {
+            step(Idle, "reset")
+        } else {
+            None
+        };
+    }
+    if inputs.emergency_stop {
+        return step(EStop, "emergency stop");
+    }
+    if state.is_final() {
+        return None;
+    }
+    if let Some(reason) = inputs.fault {
+        return step(Fault, reason);
+    }
+    if inputs.low_battery {
+        return step(LowBattery, "low battery");
+    }
+    if inputs.finish {
+        return step(Done, "finished");
+    }
+
+    let error = |above: f64| matches!(inputs.goal_error, Some(error) if error > above);
+    let lost = inputs.needs_fix && !inputs.fix;
+    match state {
+        Idle if inputs.armed => step(WaitingForLink, "armed"),
+        WaitingForLink if inputs.link || !inputs.needs_fix => step(Searching, "connected"),
+        WaitingForLink if elapsed > config.link_timeout => step(Fault, "no tracker"),
+        Searching if !lost => step(Approaching, "measured"),
+        Searching if !inputs.link => step(WaitingForLink, "disconnected"),
+        Searching if elapsed > config.search_timeout => step(Fault, "gave up searching"),
+        Approaching | Blocked | Holding if lost => step(Searching, "lost the measurements"),
+        Approaching if inputs.blocked => step(Blocked, "obstacle"),
+        Approaching if !error(1.0) => step(Holding, "at the goal"),
+        Blocked if !inputs.blocked => step(Approaching, "way clear"),
+        Blocked if elapsed > config.blocked_timeout => step(Fault, "stuck at an obstacle"),
+        Holding if error(2.0) => step(Approaching, "off the goal"),
+        Holding if elapsed >= inputs.hold && inputs.last_goal => step(Done, "mission complete"),
+        Holding if elapsed >= inputs.hold => Some(Step {
+            to: Approaching,
+            reason: "goal reached",
+            next_goal: true,
+        }),
+        _ => None,
+    }
+}
+
+/// Done on entering or leaving a state.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Action {
+    // clear the integral of the PID
+    ResetController,
+    // keep the heading the car has now
+    HoldHeading,
+    // stop the engines at once, without slewing
+    Brake,
+    // leave the flight recorder on the serial console
+    SaveFlightRecord,
+    // on to the next goal of the mission
+    NextGoal,
+}
+
+pub fn on_entry(state: State) -> &'static [Action] {
+    match state {
+        State::Approaching => &[Action::HoldHeading],
+        State::Blocked | State::Holding => &[Action::Brake],
+        State::Done | State::LowBattery | State::Fault | State::EStop => {
+            &[Action::Brake, Action::SaveFlightRecord]
+        }
+        _ => &[],
+    }
+}
+
+pub fn on_exit(state: State) -> &'static [Action] {
+    match state {
+        // nothing winds up while the car is not driving
+        State::Approaching => &[Action::ResetController],
+        _ => &[],
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Transition {
+    pub from: State,
+    pub to: State,
+    pub reason: &'static str,
+    // the exit actions of `from`, the next goal, then the entry actions of `to`
+    pub actions: Vec<Action>,
+}
+
+pub struct StateMachine {
+    config: MachineConfig,
+    state: State,
+    entered: Instant,
+}
+
+impl StateMachine {
+    pub fn new(config: MachineConfig, now: Instant) -> Self {
+        Self {
+            config,
+            state: State::Idle,
+            entered: now,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Since when the machine is in its state.
+    pub fn entered(&self) -> Instant {
+        self.entered
+    }
+
+    /// Takes one transition if the inputs call for it.
+    pub fn update(&mut self, inputs: &Inputs, now: Instant) -> Option<Transition> {
+        let elapsed = now.saturating_duration_since(self.entered);
+        let step = next(self.state, elapsed, inputs, &self.config)?;
+        let mut actions = on_exit(self.state).to_vec();
+        if step.next_goal {
+            actions.push(Action::NextGoal);
+        }
+        actions.extend_from_slice(on_entry(step.to));
+        let transition = Transition {
+            from: self.state,
+            to: step.to,
+            reason: step.reason,
+            actions,
+        };
+        self.state = step.to;
+        self.entered = now;
+        Some(transition)
+    }
+}
@@ -0,0 +1,74 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Microseconds since the tracker booted; the time base shared by the car and the tracker.
+pub type Micros = u64;
+
+/// A monotonic clock counting from the moment it was created.
+pub struct Timebase {
+    epoch: Instant,
+}
+
+impl Timebase {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn now(&self) -> Micros {
+        self.at(Instant::now())
+    }
+
+    pub fn at(&self, instant: Instant) -> Micros {
+        instant.saturating_duration_since(self.epoch).as_micros() as Micros
+    }
+
+    pub fn instant(&self, micros: Micros) -> Instant {
+        self.epoch + Duration::from_micros(micros)
+    }
+}
+
+// keep the exchanges of the last few seconds, the one with the shortest round trip wins
+const SYNC_WINDOW: usize = 8;
+
+/// Estimates the offset of a remote clock from NTP style request/response exchanges.
+pub struct ClockSync {
+    // (round trip, offset) of the last exchanges
+    samples: VecDeque<(u64, i64)>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(SYNC_WINDOW),
+        }
+    }
+
+    /// `sent` and `received` are local times, `remote` is the remote clock when it answered.
+    pub fn update(&mut self, sent: Micros, remote: Micros, received: Micros) {
+        if received < sent {
+            return;
+        }
+        let round_trip = received - sent;
+        let offset = remote as i64 - (sent + round_trip / 2) as i64;
+        if self.samples.len() == SYNC_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((round_trip, offset));
+    }
+
+    pub fn offset(&self) -> Option<i64> {
+        self.samples
+            .iter()
+            .min_by_key(|(round_trip, _)| *round_trip)
+            .map(|(_, offset)| *offset)
+    }
+
+    pub fn to_remote(&self, local: Micros) -> Option<Micros> {
+        self.offset()
+            .map(|offset| (local as i64 + offset).max(0) as Micros)
+    }
+}
@@ -0,0 +1,129 @@
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::Duration;
+
+/// What the beeps of the car sound like.
+#[derive(Debug, Copy, Clone)]
+pub struct ToneConfig {
+    // frequency of the buzzer
+    pub frequency_hz: f32,
+    // the buzzer may be off by this much either way
+    pub bandwidth_hz: f32,
+    // share of the power which has to be at the buzzer frequency, from 0 to 1
+    pub min_tone_ratio: f32,
+    // how long each beep lasts and how often it starts
+    pub beep_on: Duration,
+    pub beep_period: Duration,
+    // how far pulse widths and beep starts may be off the cadence
+    pub cadence_tolerance: Duration,
+    // beeps which may be missed in a row without losing the cadence
+    pub max_missed: u32,
+}
+
+impl Default for ToneConfig {
+    fn default() -> Self {
+        Self {
+            frequency_hz: 2700.0,
+            bandwidth_hz: 300.0,
+            min_tone_ratio: 0.3,
+            beep_on: Duration::from_millis(200),
+            beep_period: Duration::from_millis(400),
+            cadence_tolerance: Duration::from_millis(60),
+            max_missed: 4,
+        }
+    }
+}
+
+/// Squared magnitude of the DFT bin of `samples` at `frequency`, computed with the Goertzel algorithm.
+pub fn goertzel_power(samples: &[f32], frequency: f32, sample_rate: f32) -> f32 {
+    let coefficient = 2.0 * (2.0 * PI * frequency / sample_rate).cos();
+    let (mut previous, mut before) = (0.0, 0.0);
+    for sample in samples {
+        let current = sample + coefficient * previous - before;
+        before = previous;
+        previous = current;
+    }
+    previous * previous + before * before - coefficient * previous * before
+}
+
+/// Share of the power of `samples` (without DC) which is within the band of the buzzer, from 0 to 1.
+pub fn tone_ratio(samples: &[f32], config: &ToneConfig, sample_rate: f32) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let n = samples.len() as f32;
+    let mean = samples.iter().sum::<f32>() / n;
+    let centered: Vec<f32> = samples.iter().map(|x| x - mean).collect();
+    let power = centered.iter().map(|x| x * x).sum::<f32>() / n;
+    if power <= f32::EPSILON {
+        return 0.0;
+    }
+    // probe the band at half the resolution of the DFT, so no tone falls between two probes
+    let resolution = sample_rate / n;
+    let probes = (2.0 * config.bandwidth_hz / resolution).ceil().max(1.0) as usize;
+    (0..=probes)
+        .map(|k| {
+            config.frequency_hz - config.bandwidth_hz / 2.0
+                + config.bandwidth_hz * k as f32 / probes as f32
+        })
+        .map(|frequency| {
+            // a full scale sine has a bin power of (n / 2)^2 and a mean power of 1 / 2
+            2.0 * goertzel_power(&centered, frequency, sample_rate) / (n * n * power)
+        })
+        .fold(0.0, f32::max)
+        .min(1.0)
+}
+
+/// Accepts detections only when they follow the cadence of the beeps of the car:
+/// every pulse lasts about `beep_on`, and beeps start every `beep_period`
+/// (some of them possibly missed).
+pub struct CadenceMatcher {
+    config: ToneConfig,
+    // start times of the recent detections, in seconds
+    candidates: VecDeque<f64>,
+    last: Option<(f64, bool)>,
+}
+
+impl CadenceMatcher {
+    pub fn new(config: ToneConfig) -> Self {
+        Self {
+            config,
+            candidates: VecDeque::new(),
+            last: None,
+        }
+    }
+
+    /// `time` in seconds identifies the detection; zero pulse widths are not known and not checked.
+    pub fn accept(&mut self, time: f64, pulse_widths: &[Duration]) -> bool {
+        match self.last {
+            Some((last, decision)) if last == time => return decision,
+            _ => {}
+        }
+
+        let tolerance = self.config.cadence_tolerance.as_secs_f64();
+        let period = self.config.beep_period.as_secs_f64();
+        let horizon = period * (self.config.max_missed + 1) as f64;
+
+        let pulses = pulse_widths
+            .iter()
+            .filter(|width| width.as_nanos() > 0)
+            .all(|width| {
+                (width.as_secs_f64() - self.config.beep_on.as_secs_f64()).abs() <= tolerance
+            });
+
+        self.candidates
+            .retain(|candidate| time - candidate <= horizon + tolerance);
+        let rhythm = self.candidates.iter().any(|candidate| {
+            let elapsed = time - candidate;
+            let beeps = (elapsed / period).round();
+            beeps >= 1.0 && (elapsed - beeps * period).abs() <= tolerance
+        });
+        self.candidates.push_back(time);
+
+        let decision = pulses && rhythm;
+        self.last = Some((time, decision));
+        decision
+    }
+}
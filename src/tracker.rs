@@ -2,13 +2,32 @@
 #![allow(clippy::single_component_path_imports)]
 //#![feature(backtrace)]
 
+#[cfg(all(feature = "analog", not(esp32)))]
+compile_error!("The `analog` feature can only be built for the `xtensa-esp32-espidf` target.");
+
+#[cfg(feature = "analog")]
+mod adc_capture;
+#[cfg(feature = "coded")]
+mod beep_pattern;
+mod calibration;
 mod common;
+mod detection;
+mod dsp;
+mod event_log;
+mod filter;
+mod geometry;
+mod protocol;
+mod quality;
+mod ranging;
+mod timebase;
+mod tone;
 
 use std::fs;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::{TcpListener, TcpStream, UdpSocket};
 use std::path::PathBuf;
-use std::sync::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::sync::{mpsc, Condvar, Mutex};
 use std::{cell::RefCell, env, sync::atomic::*, sync::Arc, thread, time::*};
 use std::thread::JoinHandle;
 
@@ -34,6 +53,7 @@ use embedded_svc::io;
 use embedded_svc::ipv4;
 use embedded_svc::mqtt::client::{Client, Connection, MessageImpl, Publish, QoS};
 use embedded_svc::ping::Ping;
+use embedded_svc::storage::RawStorage;
 use embedded_svc::sys_time::SystemTime;
 use embedded_svc::timer::TimerService;
 use embedded_svc::timer::*;
@@ -47,6 +67,7 @@ use esp_idf_svc::httpd::ServerRegistry;
 use esp_idf_svc::mqtt::client::*;
 use esp_idf_svc::netif::*;
 use esp_idf_svc::nvs::*;
+use esp_idf_svc::nvs_storage::EspNvsStorage;
 use esp_idf_svc::ping;
 use esp_idf_svc::sntp;
 use esp_idf_svc::sysloop::*;
@@ -83,98 +104,344 @@ use esp_idf_hal::gpio::Pull;
 
 use smol::io::AsyncWriteExt;
 
-// relative time
-pub struct StateData {
-    pub a: Instant,
-    pub b: Instant,
-    pub c: Instant,
-}
+use detection::StateData;
+use event_log::Recorder;
 
 // 0.1 seconds
 const VALID_TIME: Duration = Duration::from_millis(100);
 // 0.05 seconds
 const SOUND_RANGE_TIME: Duration = Duration::from_millis(50);
 
+// first-arrival detection of the bursts heard by the digital receivers
+const DETECTOR: detection::DetectorConfig = detection::DetectorConfig {
+    valid_time: VALID_TIME,
+    sound_range_time: SOUND_RANGE_TIME,
+    blanking_time: Duration::from_millis(300),
+    check_physical_delays: true,
+    physical_delay_margin: Duration::from_micros(200),
+};
+
+// positions of the receivers, a and b first; adjust to the actual build of the tracker
+const GEOMETRY: geometry::ArrayGeometry = geometry::ArrayGeometry {
+    microphones: &[
+        geometry::Point::new(-0.25, 0.0),
+        geometry::Point::new(0.25, 0.0),
+        geometry::Point::new(0.0, 0.25),
+    ],
+    // used to compensate the speed of sound
+    temperature_c: 20.0,
+    speed_of_sound: None,
+};
+
+// the beeps of the car, keep in sync with `BEEP_FREQUENCY_HZ`, `BEEP_CODE` and `BEEP_PERIOD` in src/car.rs
+fn tone_config() -> tone::ToneConfig {
+    tone::ToneConfig {
+        frequency_hz: 2700.0,
+        beep_on: Duration::from_millis(200),
+        beep_period: Duration::from_millis(400),
+        ..Default::default()
+    }
+}
 
-pub struct Workspace<GpioA: gpio::InputPin, GpioB: gpio::InputPin, GpioC: gpio::InputPin> {
-    // 3 input pins
-    pub recv_a: GpioA,
-    pub recv_b: GpioB,
-    pub recv_c: GpioC,
+// where the sound source is placed during a calibration run, one after the other
+#[cfg(feature = "calibrate")]
+const CALIBRATION_SOURCES: &[calibration::Source] = &[calibration::Source::Bisector];
+// events recorded at each calibration source position
+#[cfg(feature = "calibrate")]
+const CALIBRATION_EVENTS: usize = 20;
+// time to move the sound source to its next position
+#[cfg(feature = "calibrate")]
+const CALIBRATION_MOVE_TIME: Duration = Duration::from_secs(10);
+
+const CALIBRATION_KEY: &str = "chan_delays";
+
+// records waiting for the record server, more are dropped
+#[cfg(feature = "record")]
+const RECORD_QUEUE: usize = 256;
+// records kept for a host connecting later, about 16 bytes each
+#[cfg(feature = "record")]
+const RECORD_RING: usize = 4096;
+
+// analog microphones on GPIO32, GPIO33 and GPIO34, in the order of `GEOMETRY.microphones`
+#[cfg(feature = "analog")]
+const ADC_CHANNELS: &[esp_idf_sys::adc1_channel_t] = &[
+    esp_idf_sys::adc1_channel_t_ADC1_CHANNEL_4,
+    esp_idf_sys::adc1_channel_t_ADC1_CHANNEL_5,
+    esp_idf_sys::adc1_channel_t_ADC1_CHANNEL_6,
+];
+// per microphone
+#[cfg(feature = "analog")]
+const ADC_SAMPLE_RATE: u32 = 32_000;
+// samples of each microphone correlated at once, 32 ms
+#[cfg(feature = "analog")]
+const ANALOG_WINDOW: usize = 1024;
+// power (of the 0..1 scaled samples) above which the latest block counts as a sound
+#[cfg(feature = "analog")]
+const ANALOG_ONSET_ENERGY: f32 = 0.0005;
+// how well the samples have to match the chirp of the car, from 0 to 1
+#[cfg(feature = "coded")]
+const MATCHED_MIN_SCORE: f32 = 0.5;
+// echoes may be louder than the direct sound, the first peak this close to the highest one is taken
+#[cfg(feature = "coded")]
+const MATCHED_PEAK_SHARE: f32 = 0.5;
+
+
+pub type Receiver = Box<dyn InputPin<Error = EspError> + Send>;
+
+pub struct Workspace {
+    // one input pin per microphone, in the order of `GEOMETRY.microphones`
+    pub receivers: Vec<Receiver>,
+}
+
+impl Workspace {
+    pub fn new(receivers: Vec<Receiver>) -> Result<Self> {
+        if receivers.len() < 2 || receivers.len() > common::MAX_RECEIVERS {
+            bail!("Unsupported number of receivers: {}, expected 2 to {}", receivers.len(), common::MAX_RECEIVERS);
+        }
+        Ok(Self { receivers })
+    }
 }
 
 const RECV_VALID: bool = true;
-const RECV_INVALID: bool = !RECV_VALID;
-
-fn read_loop<CB: FnMut(StateData) -> Result<()>, GpioA: gpio::InputPin + InputPin, GpioB: gpio::InputPin + InputPin, GpioC: gpio::InputPin + InputPin>(workspace: &Workspace<GpioA, GpioB, GpioC>, mut callback: CB) -> Result<()>
-    where
-        <GpioA as embedded_hal::digital::v2::InputPin>::Error: std::error::Error + Sync + Send + 'static,
-        <GpioB as embedded_hal::digital::v2::InputPin>::Error: std::error::Error + Sync + Send + 'static,
-        <GpioC as embedded_hal::digital::v2::InputPin>::Error: std::error::Error + Sync + Send + 'static,
-{
-    let now = Instant::now();
-    let mut last_a = now;
-    let mut last_b = now;
-    let mut last_c = now;
-    let mut last_a_val = RECV_INVALID;
-    let mut last_b_val = RECV_INVALID;
-    let mut last_c_val = RECV_INVALID;
+
+fn read_loop<CB: FnMut(StateData) -> Result<()>>(workspace: &Workspace, recorder: &Recorder, mut callback: CB) -> Result<()> {
+    let mut detector = detection::Detector::new(DETECTOR, workspace.receivers.len(), Instant::now());
     loop {
-        let a_val = workspace.recv_a.is_high()? == RECV_VALID;
-        let b_val = workspace.recv_b.is_high()? == RECV_VALID;
-        let c_val = workspace.recv_c.is_high()? == RECV_VALID;
         let now = Instant::now();
 
-        if a_val != last_a_val {
-            last_a_val = a_val;
-            last_a = now;
+        for (i, receiver) in workspace.receivers.iter().enumerate() {
+            let val = receiver.is_high()? == RECV_VALID;
+            if detector.sample(i, val, now) {
+                recorder.edge(i, val, now);
+            }
+        }
+
+        if let Some(data) = detector.burst(&GEOMETRY, now) {
+            callback(data)?;
+        }
+    }
+    Ok(())
+}
+
+
+// samples the analog microphones and measures the delays between them with GCC-PHAT,
+// or with a matched filter for the chirp of the car (feature `coded`)
+#[cfg(feature = "analog")]
+fn analog_loop<CB: FnMut(StateData) -> Result<()>>(capture: &mut adc_capture::AdcCapture, mut callback: CB) -> Result<()> {
+    let receivers = GEOMETRY.microphones.len();
+    let rate = capture.sample_rate() as f64;
+    let window = Duration::from_secs_f64(ANALOG_WINDOW as f64 / rate);
+    #[cfg(not(feature = "coded"))]
+    let tone = tone_config();
+    // the most a real sound can be delayed between any receiver and the first one
+    #[cfg(not(feature = "coded"))]
+    let max_lag = (1..receivers)
+        .map(|i| (GEOMETRY.max_offset(i, 0) as f64 * 1e-9 * rate).ceil() as usize + 1)
+        .max()
+        .unwrap_or(1);
+    // the chirp (10 ms) is whole in the window: it started at most one onset block (4 ms)
+    // before `start`, and the window reaches 16 ms before and after `start`
+    #[cfg(feature = "coded")]
+    let template = beep_pattern::render(&beep_pattern::CODED.pattern(0), rate as f32);
+    let mut onset: Option<Instant> = None;
+    let mut holdoff_until = Instant::now();
+    loop {
+        capture.read()?;
+        let now = capture.latest_time();
+        if now < holdoff_until {
+            continue;
+        }
+        match onset {
+            None => {
+                let block = capture.latest(ANALOG_WINDOW / 8);
+                if block.iter().all(|samples| dsp::energy(samples) > ANALOG_ONSET_ENERGY) {
+                    onset = Some(now);
+                }
+            }
+            // correlate once the sound filled the second half of the window
+            Some(start) if now.duration_since(start) >= window / 2 => {
+                onset = None;
+                holdoff_until = now + VALID_TIME;
+                let samples = capture.latest(ANALOG_WINDOW);
+                #[cfg(not(feature = "coded"))]
+                let delays: Option<Vec<f64>> = {
+                    // only the buzzer of the car, not claps, voices or motors
+                    if samples.iter().any(|samples| tone::tone_ratio(samples, &tone, rate as f32) < tone.min_tone_ratio) {
+                        continue;
+                    }
+                    (0..receivers)
+                        .map(|i| {
+                            let lag = dsp::gcc_phat(&samples[i], &samples[0], max_lag)?;
+                            Some(dsp::deskew(lag, rate, capture.skew(i)))
+                        })
+                        .collect()
+                };
+                // where the chirp starts on each channel, anything else does not match it
+                #[cfg(feature = "coded")]
+                let delays: Option<Vec<f64>> = (0..receivers)
+                    .map(|i| {
+                        let start = dsp::detect(&samples[i], &template, MATCHED_MIN_SCORE, MATCHED_PEAK_SHARE)?;
+                        Some(dsp::deskew(start, rate, capture.skew(i)))
+                    })
+                    .collect();
+                let delays = match delays {
+                    Some(delays) => delays,
+                    None => continue,
+                };
+                let first = delays.iter().cloned().fold(f64::INFINITY, f64::min);
+                let data = StateData {
+                    arrivals: delays.iter().map(|delay| start + Duration::from_secs_f64(delay - first)).collect(),
+                    // a whole window of sound on every channel
+                    pulses: vec![window; receivers],
+                };
+                callback(data)?;
+            }
+            Some(_) => {}
         }
-        if b_val != last_b_val {
-            last_b_val = b_val;
-            last_b = now;
+    }
+}
+
+fn calculate(data: &StateData, calibration: &calibration::Calibration) -> detection::Measurement {
+    detection::calculate(&GEOMETRY, data, calibration, SOUND_RANGE_TIME)
+}
+
+fn load_calibration(storage: &EspNvsStorage, receivers: usize) -> Result<calibration::Calibration> {
+    match storage.get_raw(CALIBRATION_KEY)? {
+        Some(bytes) => {
+            let calibration = calibration::Calibration::from_bytes(&bytes)?;
+            if calibration.delays.len() != receivers {
+                warn!("Stored channel delays {:?} ns are for another number of receivers, ignoring them", calibration.delays);
+                return Ok(calibration::Calibration::none(receivers));
+            }
+            info!("Loaded channel delays {:?} ns", calibration.delays);
+            Ok(calibration)
         }
-        if c_val != last_c_val {
-            last_c_val = c_val;
-            last_c = now;
+        None => {
+            warn!("No channel delays stored, build with the `calibrate` feature to measure them");
+            Ok(calibration::Calibration::none(receivers))
         }
+    }
+}
 
-        let last_earlistest = if last_a <= last_b && last_a <= last_c {
-            last_a
-        } else if last_b <= last_a && last_b <= last_c {
-            last_b
-        } else {
-            if !(last_c <= last_a && last_c <= last_b) {
-                panic!("last_c is not earlistest");
+#[cfg(feature = "calibrate")]
+fn store_calibration(storage: &mut EspNvsStorage, calibration: &calibration::Calibration) -> Result<()> {
+    storage.put_raw(CALIBRATION_KEY, calibration.to_bytes())?;
+    info!("Stored channel delays {:?} ns", calibration.delays);
+    Ok(())
+}
+
+// keeps the latest records and streams them to a host connecting to TCP port 8082
+#[cfg(feature = "record")]
+fn record_server(header: event_log::Header, records: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+    info!("About to bind the record service to port 8082");
+
+    let listener = TcpListener::bind("0.0.0.0:8082")?;
+    let header = event_log::Record::Header(header).to_bytes();
+    let ring = Arc::new(Mutex::new(VecDeque::with_capacity(RECORD_RING)));
+    let client: Arc<Mutex<Option<TcpStream>>> = Arc::new(Mutex::new(None));
+
+    {
+        let ring = ring.clone();
+        let client = client.clone();
+        thread::spawn(move || {
+            for record in records {
+                let mut ring = ring.lock().unwrap();
+                if ring.len() == RECORD_RING {
+                    ring.pop_front();
+                }
+                let mut client = client.lock().unwrap();
+                if let Some(stream) = client.as_mut() {
+                    if let Err(e) = stream.write_all(&record) {
+                        warn!("Record client gone: {}", e);
+                        *client = None;
+                    }
+                }
+                ring.push_back(record);
             }
-            last_c
-        };
+        });
+    }
 
-        if a_val &&
-            b_val &&
-            c_val &&
-            now.duration_since(last_a) > VALID_TIME &&
-            now.duration_since(last_b) > VALID_TIME &&
-            now.duration_since(last_c) > VALID_TIME &&
-            last_a.duration_since(last_earlistest) < SOUND_RANGE_TIME &&
-            last_b.duration_since(last_earlistest) < SOUND_RANGE_TIME &&
-            last_c.duration_since(last_earlistest) < SOUND_RANGE_TIME {
-            let data = StateData {
-                a: last_a,
-                b: last_b,
-                c: last_c,
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Error: {}", e);
+                    continue;
+                }
             };
-            callback(data)?;
+            info!("Streaming records to {:?}", stream.peer_addr());
+            // the history first, under the lock so that no live record slips in between
+            let ring = ring.lock().unwrap();
+            let sent = stream.write_all(&header)
+                .and_then(|_| ring.iter().try_for_each(|record| stream.write_all(record)));
+            match sent {
+                Ok(()) => *client.lock().unwrap() = Some(stream),
+                Err(e) => warn!("Record client gone: {}", e),
+            }
         }
-    }
+    });
+
     Ok(())
 }
 
+// answers the clock sync requests of the car and collects its beep reports
+#[cfg(feature = "tof")]
+fn time_server(timebase: Arc<timebase::Timebase>, ranges: Arc<Mutex<ranging::RangeMatcher>>, control: Arc<ArcSwap<common::ControlData>>) -> Result<()> {
+    info!("About to bind the time service to UDP port 8081");
+
+    let socket = UdpSocket::bind("0.0.0.0:8081")?;
+
+    thread::spawn(move || {
+        let mut buffer = vec![0; common::TimePacket::size()];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("Error: {}", e);
+                    continue;
+                }
+            };
+            if len != common::TimePacket::size() {
+                warn!("Unexpected time packet of {} bytes from {}", len, peer);
+                continue;
+            }
+            let packet = *common::TimePacket::from_slice(&buffer);
+            match packet.kind {
+                common::TIME_SYNC_REQUEST => {
+                    let response = common::TimePacket {
+                        kind: common::TIME_SYNC_RESPONSE,
+                        sent: packet.sent,
+                        tracker: timebase.now(),
+                    };
+                    if let Err(e) = socket.send_to(response.to_slice(), peer) {
+                        error!("Error: {}", e);
+                    }
+                }
+                common::TIME_BEEP_REPORT => {
+                    let mut ranges = ranges.lock().unwrap();
+                    // the beep was heard before the car reported it, publish the measurement again with its ranges
+                    if ranges.push_beep(packet.tracker) {
+                        let mut data = **control.load();
+                        set_distance(&mut data, &ranges);
+                        control.store(Arc::new(data));
+                    }
+                }
+                kind => warn!("Unexpected time packet kind {} from {}", kind, peer),
+            }
+        }
+    });
+
+    Ok(())
+}
 
-fn calculate(data: StateData) -> Result<common::ControlData> {
-    if data.a >= data.b {
-        Ok(common::ControlData { offset: data.a.duration_since(data.b).as_nanos() as i128 })
-    } else {
-        Ok(common::ControlData { offset: -(data.b.duration_since(data.a).as_nanos() as i128) })
+// to each microphone, for the latest arrivals once their beep is known
+#[cfg(feature = "tof")]
+fn set_distance(data: &mut common::ControlData, ranges: &ranging::RangeMatcher) {
+    if let Some(measured) = ranges.ranges() {
+        let mut distance = [f32::NAN; common::MAX_RECEIVERS];
+        distance[..measured.len()].copy_from_slice(measured);
+        data.distance = distance;
     }
 }
 
@@ -216,11 +483,13 @@ fn send_server(data: Arc<ArcSwap<common::ControlData>>) -> Result<()> {
 }
 
 
-fn send_server_async(data: Arc<ArcSwap<common::ControlData>>) -> anyhow::Result<()> {
-    async fn tcp_bind(data: Arc<ArcSwap<common::ControlData>>) -> smol::io::Result<()> {
-        /// Echoes messages from the client back to it.
-        async fn echo(data: Arc<ArcSwap<common::ControlData>>, mut stream: smol::Async<TcpStream>) -> smol::io::Result<()> {
-            stream.write_all(data.load().to_slice()).await?;
+fn send_server_async(data: Arc<ArcSwap<common::ControlData>>, timebase: Arc<timebase::Timebase>) -> anyhow::Result<()> {
+    async fn tcp_bind(data: Arc<ArcSwap<common::ControlData>>, timebase: Arc<timebase::Timebase>) -> smol::io::Result<()> {
+        /// Sends the latest control data to the client, with how old it is.
+        async fn echo(data: Arc<ArcSwap<common::ControlData>>, timebase: Arc<timebase::Timebase>, mut stream: smol::Async<TcpStream>) -> smol::io::Result<()> {
+            let mut data = **data.load();
+            data.age = timebase.now().saturating_sub(data.time) as u32;
+            stream.write_all(data.to_slice()).await?;
             Ok(())
         }
 
@@ -233,7 +502,7 @@ fn send_server_async(data: Arc<ArcSwap<common::ControlData>>) -> anyhow::Result<
             info!("Accepted client: {}", peer_addr);
 
             // Spawn a task that echoes messages from the client back to it.
-            smol::spawn(echo(data.clone(), stream)).detach();
+            smol::spawn(echo(data.clone(), timebase.clone(), stream)).detach();
         }
     }
 
@@ -250,7 +519,7 @@ fn send_server_async(data: Arc<ArcSwap<common::ControlData>>) -> anyhow::Result<
     }
 
     thread::Builder::new().stack_size(4096).spawn(move || {
-        smol::block_on(tcp_bind(data)).unwrap();
+        smol::block_on(tcp_bind(data, timebase)).unwrap();
     })?;
 
     Ok(())
@@ -266,19 +535,61 @@ fn main() -> Result<()> {
     //env::set_var("RUST_BACKTRACE", "1");
 
     let peripherals = Peripherals::take().unwrap();
+    #[allow(unused_variables)]
     let pins = peripherals.pins;
 
-    let mut wifi = common::init_wifi_server()?;
+    let default_nvs = Arc::new(EspDefaultNvs::new()?);
+
+    let mut wifi = common::init_wifi_server(default_nvs.clone())?;
+
+    #[allow(unused_mut)]
+    let mut storage = EspNvsStorage::new_default(default_nvs, "tracker", true)?;
+
+    #[allow(unused_mut)]
+    let mut calibration = load_calibration(&storage, GEOMETRY.microphones.len())?;
 
-    let workspace = Workspace {
-        recv_a: pins.gpio4.into_input()?.into_pull_up()?,
-        recv_b: pins.gpio0.into_input()?.into_pull_up()?,
-        recv_c: pins.gpio2.into_input()?.into_pull_up()?,
+    #[cfg(feature = "calibrate")]
+    let mut collector = {
+        let collector = calibration::Collector::new(CALIBRATION_SOURCES, CALIBRATION_EVENTS);
+        info!("Calibrating, place the sound source at {:?}", collector.current_source());
+        collector
+    };
+    #[cfg(feature = "calibrate")]
+    let mut calibration_resume = Instant::now();
+
+    #[cfg(not(feature = "analog"))]
+    let workspace = Workspace::new(vec![
+        Box::new(pins.gpio4.into_input()?.into_pull_up()?) as Receiver,
+        Box::new(pins.gpio0.into_input()?.into_pull_up()?) as Receiver,
+        Box::new(pins.gpio2.into_input()?.into_pull_up()?) as Receiver,
+    ])?;
+    #[cfg(not(feature = "analog"))]
+    if workspace.receivers.len() != GEOMETRY.microphones.len() {
+        bail!("{} receivers wired, but {} microphones in the geometry", workspace.receivers.len(), GEOMETRY.microphones.len());
+    }
+
+    #[cfg(feature = "analog")]
+    let mut capture = {
+        if ADC_CHANNELS.len() != GEOMETRY.microphones.len() {
+            bail!("{} ADC channels wired, but {} microphones in the geometry", ADC_CHANNELS.len(), GEOMETRY.microphones.len());
+        }
+        adc_capture::AdcCapture::new(ADC_CHANNELS, ADC_SAMPLE_RATE, 2 * ANALOG_WINDOW)?
     };
 
     let control = Arc::new(ArcSwap::from(Arc::new(common::ControlData::empty())));
 
-    send_server_async(control.clone())?;
+    let timebase = Arc::new(timebase::Timebase::new());
+
+    send_server_async(control.clone(), timebase.clone())?;
+
+    #[cfg(feature = "tof")]
+    let ranges = Arc::new(Mutex::new(ranging::RangeMatcher::new(ranging::RangingConfig {
+        speed_of_sound: GEOMETRY.speed_of_sound(),
+        ..Default::default()
+    })));
+
+    #[cfg(feature = "tof")]
+    time_server(timebase.clone(), ranges.clone(), control.clone())?;
 
     // wifi holder
     thread::spawn(move ||{
@@ -286,11 +597,82 @@ fn main() -> Result<()> {
         drop(wifi);
     });
 
+    #[cfg(feature = "record")]
+    let recorder = {
+        let (sender, records) = mpsc::sync_channel(RECORD_QUEUE);
+        record_server(event_log::Header {
+            microphones: GEOMETRY.microphones.to_vec(),
+            speed_of_sound: GEOMETRY.speed_of_sound(),
+            delays: calibration.delays.clone(),
+            detector: DETECTOR,
+        }, records)?;
+        Recorder::new(sender)
+    };
+    #[cfg(not(feature = "record"))]
+    let recorder = Recorder::off();
+
     thread::spawn(move ||{
-        read_loop(&workspace, |data| {
-            control.store(Arc::new(calculate(data)?));
+        let start = Instant::now();
+        // outlier rejection and smoothing of the path difference, nothing about it depends on the hardware
+        let mut filter = filter::MeasurementFilter::new(filter::FilterConfig::default());
+        let mut cadence = tone::CadenceMatcher::new(tone_config());
+        let mut sequence = common::Sequence::new();
+        let on_measurement = |data: StateData| -> Result<()> {
+            #[cfg(feature = "calibrate")]
+            if !collector.done() && Instant::now() >= calibration_resume {
+                let arrivals: Vec<i64> = data.arrivals.iter()
+                    .map(|arrival| arrival.saturating_duration_since(start).as_nanos() as i64)
+                    .collect();
+                if collector.push(&arrivals) {
+                    if let Some(source) = collector.current_source() {
+                        info!("Move the sound source to {:?}", source);
+                        calibration_resume = Instant::now() + CALIBRATION_MOVE_TIME;
+                    } else {
+                        let solution = calibration::solve(&GEOMETRY, collector.events())?;
+                        info!("Calibration residuals {:?} ns from {:?} events", solution.residuals, solution.samples);
+                        store_calibration(&mut storage, &solution.calibration)?;
+                        calibration = solution.calibration;
+                    }
+                }
+            }
+            // every burst goes to the log, also the ones the cadence check drops
+            let measurement = calculate(&data, &calibration);
+            recorder.measurement(data.earliest(), &measurement);
+            let time = data.earliest().saturating_duration_since(start).as_secs_f64();
+            // only follow sounds with the cadence of the beeps of the car; the analog loop checked their frequency already
+            let pulses: &[Duration] = if cfg!(feature = "analog") { &[] } else { &data.pulses };
+            if !cadence.accept(time, pulses) {
+                return Ok(());
+            }
+            // the same burst comes again until the next one, it keeps its number
+            let heard = timebase.at(data.earliest());
+            let mut control_data = common::ControlData {
+                offset: measurement.offset,
+                path_difference: measurement.path_difference,
+                bearing: measurement.bearing,
+                confidence: measurement.confidence,
+                sequence: sequence.number(heard),
+                time: heard,
+                ..common::ControlData::empty()
+            };
+            let estimate = filter.update(time, control_data.path_difference);
+            control_data.filtered = estimate.value;
+            control_data.rate = estimate.rate;
+            // held until stored, so that a beep report in between completes these arrivals
+            #[cfg(feature = "tof")]
+            let mut ranges = ranges.lock().unwrap();
+            #[cfg(feature = "tof")]
+            {
+                ranges.push_arrivals(&data.arrivals.iter().map(|arrival| timebase.at(*arrival)).collect::<Vec<_>>());
+                set_distance(&mut control_data, &ranges);
+            }
+            control.store(Arc::new(control_data));
             Ok(())
-        }).unwrap();
+        };
+        #[cfg(not(feature = "analog"))]
+        read_loop(&workspace, &recorder, on_measurement).unwrap();
+        #[cfg(feature = "analog")]
+        analog_loop(&mut capture, on_measurement).unwrap();
     });
 
     Ok(())